@@ -1,204 +1,176 @@
 use crate::db::{self, DBConnection};
 use crate::dropbox;
+use crate::error::{DeepFreezeError, Result};
 use crate::http::{self, HTTPClient, HeaderMap};
-use crate::json;
+use crate::json::{self, JSON};
+use crate::retry;
 use crate::util::{getenv, prompt, setenv};
 
-use inquire::{InquireError, Select};
-use open;
+use inquire::Select;
 
-async fn login(http: &HTTPClient) {
+async fn login(http: &HTTPClient) -> Result<()> {
     println!("🔒 Initiating login...");
-    get_authorization_code().await;
-    get_access_token(http).await.unwrap();
+    get_authorization_code().await?;
+    get_access_token(http).await
 }
 
-async fn get_access_token(http: &HTTPClient) -> Result<(), String> {
+async fn get_access_token(http: &HTTPClient) -> Result<()> {
     println!("🔐 Requesting access token...");
     let mut headers = HeaderMap::new();
-    headers = http::dropbox_content_type_x_www_form_urlencoded_header(&mut headers);
-    let body = http::dropbox_oauth2_token_body().await;
-    match http
-        .post("https://api.dropbox.com/oauth2/token")
-        .headers(headers)
-        .body(body)
-        .send()
-        .await
-        .unwrap()
-        .text()
-        .await
-    {
-        Ok(res) => match res.contains("error") {
-            true => Err(handle_auth_error(&http, res).await),
-            false => Ok(handle_successful_login(res).await),
-        },
-        Err(err) => panic!("❌ {err}"),
+    headers = http::dropbox_content_type_x_www_form_urlencoded_header(&mut headers)?;
+    let body = http::dropbox_oauth2_token_body().await?;
+    let res = retry::send_text("oauth2/token", || {
+        http.post("https://api.dropbox.com/oauth2/token")
+            .headers(headers.clone())
+            .body(body.clone())
+    })
+    .await?;
+    match res.contains("error") {
+        true => {
+            handle_auth_error(http, res).await?;
+            Err(DeepFreezeError::Auth(
+                "could not exchange the authorization code".to_string(),
+            ))
+        }
+        false => handle_successful_login(res).await,
     }
 }
 
-async fn handle_successful_login(res: String) {
-    let json = json::from_res(&res);
-    let team_id = json.get("team_id").unwrap().as_str().unwrap();
-    let refresh_token = json.get("refresh_token").unwrap().as_str().unwrap();
-    let access_token = json.get("access_token").unwrap().as_str().unwrap();
-    setenv("DROPBOX_TEAM_ID", team_id.to_string()).await;
+async fn handle_successful_login(res: String) -> Result<()> {
+    let json = json::from_res(&res)?;
+    let team_id = json::get_str(&json, "team_id")?;
+    let refresh_token = json::get_str(&json, "refresh_token")?;
+    let access_token = json::get_str(&json, "access_token")?;
+    setenv("DROPBOX_TEAM_ID", team_id.to_string()).await?;
     println!("🔑 Team ID set");
-    setenv("DROPBOX_REFRESH_TOKEN", refresh_token.to_string()).await;
+    setenv("DROPBOX_REFRESH_TOKEN", refresh_token.to_string()).await?;
     println!("🔑 Refresh token set");
-    setenv("DROPBOX_ACCESS_TOKEN", access_token.to_string()).await;
+    setenv("DROPBOX_ACCESS_TOKEN", access_token.to_string()).await?;
     println!("🔑 Login: Access token set");
+    Ok(())
 }
 
-async fn get_authorization_code() {
+async fn get_authorization_code() -> Result<()> {
     let url = http::dropbox_authorization_code_url();
     print!("\n🚦 You need to be logged in to DropBox\n\n");
-    open::that_detached(&url).unwrap();
+    open::that_detached(&url).ok();
     println!("🌐 Open this URL in your browser (one might have opened already):");
     print!("\n🌐 {}\n\n", url);
     println!("🔐 and authorize the app.");
-    let authorization_code = prompt("🪪  Paste the authorization code you see here").await;
-    setenv("DROPBOX_AUTHORIZATION_CODE", authorization_code).await;
+    let authorization_code = prompt("🪪  Paste the authorization code you see here").await?;
+    setenv("DROPBOX_AUTHORIZATION_CODE", authorization_code).await?;
     println!("🔑 Authorization code set");
+    Ok(())
 }
 
-pub async fn refresh_token(http: &HTTPClient) -> String {
+pub async fn refresh_token(http: &HTTPClient) -> Result<String> {
     println!("🔑 Refreshing access token...");
     let mut headers = HeaderMap::new();
-    headers = http::dropbox_content_type_x_www_form_urlencoded_header(&mut headers);
-    let body = http::dropbox_refresh_token_body().await;
-    match http
-        .post("https://api.dropbox.com/oauth2/token")
-        .headers(headers)
-        .body(body)
-        .send()
-        .await
-        .unwrap()
-        .text()
-        .await
-    {
-        Ok(res) => match res.contains("error") {
-            true => {
-                dbg!(&res);
-                handle_auth_error(&http, res).await
-            }
-            false => {
-                let json = json::from_res(&res);
-                let access_token = json.get("access_token").unwrap().as_str().unwrap();
-                setenv("DROPBOX_ACCESS_TOKEN", access_token.to_string()).await;
-                get_current_account(&http).await
-            }
-        },
-        Err(err) => panic!("❌ {err}"),
+    headers = http::dropbox_content_type_x_www_form_urlencoded_header(&mut headers)?;
+    let body = http::dropbox_refresh_token_body().await?;
+    let res = retry::send_text("oauth2/token", || {
+        http.post("https://api.dropbox.com/oauth2/token")
+            .headers(headers.clone())
+            .body(body.clone())
+    })
+    .await?;
+    match res.contains("error") {
+        true => {
+            dbg!(&res);
+            handle_auth_error(http, res).await
+        }
+        false => {
+            let json = json::from_res(&res)?;
+            let access_token = json::get_str(&json, "access_token")?;
+            setenv("DROPBOX_ACCESS_TOKEN", access_token.to_string()).await?;
+            get_current_account(http).await
+        }
     }
 }
 
-async fn get_current_account(http: &HTTPClient) -> String {
+async fn get_current_account(http: &HTTPClient) -> Result<String> {
     let mut headers = http::HeaderMap::new();
-    headers = http::dropbox_authorization_header(&mut headers);
+    headers = http::dropbox_authorization_header(&mut headers)?;
     // headers = http::dropbox_select_admin_header(&mut headers);
-    headers = http::dropbox_select_user_header(&mut headers);
-    match http
-        .post("https://api.dropboxapi.com/2/users/get_current_account")
-        .headers(headers)
-        .send()
-        .await
-        .unwrap()
-        .text()
-        .await
-    {
-        Ok(res) => match res.contains("error") {
-            true => handle_auth_error(&http, res).await,
-            false => res,
-        },
-        Err(err) => panic!("❌ {err}"),
+    headers = http::dropbox_select_user_header(&mut headers)?;
+    let res = retry::send_text("users/get_current_account", || {
+        http.post("https://api.dropboxapi.com/2/users/get_current_account")
+            .headers(headers.clone())
+    })
+    .await?;
+    match res.contains("error") {
+        true => handle_auth_error(http, res).await,
+        false => Ok(res),
     }
 }
 
 #[async_recursion::async_recursion(?Send)]
-async fn handle_auth_error(http: &HTTPClient, res: String) -> String {
+async fn handle_auth_error(http: &HTTPClient, res: String) -> Result<String> {
     println!("❌  Error in auth");
-    let json = json::from_res(&res);
-    match json
+    let json: JSON = serde_json::from_str(&res)?;
+    let tag = json
         .get("error")
-        .unwrap()
-        .get(".tag")
-        .unwrap()
-        .as_str()
-        .unwrap()
-    {
+        .and_then(|error| match error {
+            JSON::String(error) => Some(error.as_str()),
+            error => error.get(".tag").and_then(|tag| tag.as_str()),
+        })
+        .unwrap_or_default();
+    match tag {
         "expired_access_token" => {
             println!("🚫  Access token expired");
             refresh_token(http).await
         }
         "invalid_access_token" => {
             println!("🚫  Access token invalid");
-            "error".to_string()
+            Err(DeepFreezeError::Auth("invalid access token".to_string()))
         }
-        result => panic!("❌  unhandled auth error {result}"),
+        _ => Err(DeepFreezeError::Auth(format!(
+            "unhandled auth error {}",
+            json::error_summary(&json)
+        ))),
     }
 }
 
-async fn select_team_member(http: &HTTPClient, sqlite: &DBConnection) {
-    let res = dropbox::get_team_members_list(&http).await;
-    let json = json::from_res(&res);
-    let members = json.get("members").unwrap().as_array().unwrap();
-    let options: Vec<String> = members
-        .into_iter()
-        .map(|member| {
-            let email = member
-                .get("profile")
-                .unwrap()
-                .get("email")
-                .unwrap()
-                .as_str()
-                .unwrap();
-            format!("{}", email)
-        })
-        .collect();
-    let ans: Result<String, InquireError> =
-        Select::new("Which team member are you?", options).prompt();
-    match ans {
-        Ok(choice) => {
-            let member = members
-                .into_iter()
-                .find(|member| {
-                    member
-                        .get("profile")
-                        .unwrap()
-                        .get("email")
-                        .unwrap()
-                        .as_str()
-                        .unwrap()
-                        == choice
-                })
-                .unwrap()
-                .get("profile")
-                .unwrap();
-            db::insert_user(sqlite, member).await;
-        }
-        Err(_) => {
-            println!("🚫  Error selecting team member");
-            std::process::exit(1);
-        }
-    }
+async fn select_team_member(http: &HTTPClient, sqlite: &DBConnection) -> Result<()> {
+    let res = dropbox::get_team_members_list(http).await?;
+    let json = json::from_res(&res)?;
+    let members = json
+        .get("members")
+        .and_then(|members| members.as_array())
+        .ok_or_else(|| DeepFreezeError::Dropbox(format!("no team members in {json}")))?;
+    let email = |member: &JSON| {
+        member
+            .get("profile")
+            .and_then(|profile| profile.get("email"))
+            .and_then(|email| email.as_str())
+            .map(str::to_string)
+    };
+    let options: Vec<String> = members.iter().filter_map(email).collect();
+    let choice = Select::new("Which team member are you?", options)
+        .prompt()
+        .map_err(|err| DeepFreezeError::Auth(format!("error selecting team member: {err}")))?;
+    let member = members
+        .iter()
+        .find(|member| email(member).as_deref() == Some(choice.as_str()))
+        .and_then(|member| member.get("profile"))
+        .ok_or_else(|| DeepFreezeError::Auth(format!("team member {choice} not found")))?;
+    db::insert_user(sqlite, member).await
 }
 
-pub async fn check_account(http: &HTTPClient, sqlite: &DBConnection) {
+pub async fn check_account(http: &HTTPClient, sqlite: &DBConnection) -> Result<()> {
     if getenv("DROPBOX_REFRESH_TOKEN").is_err() {
-        login(http).await;
+        login(http).await?;
     }
     if getenv("DROPBOX_TEAM_MEMBER_ID").is_err() {
-        select_team_member(http, sqlite).await;
+        select_team_member(http, sqlite).await?;
     }
     print!("\n🪪  Checking account...\n");
-    let res = get_current_account(&http).await;
-    let json = json::from_res(&res);
-    db::insert_user(sqlite, &json).await;
-    print!(
-        "👤  Logged in as {}\n\n",
-        &json.get("email").unwrap().as_str().unwrap()
-    );
+    let res = get_current_account(http).await?;
+    let json = json::from_res(&res)?;
+    db::insert_user(sqlite, &json).await?;
+    print!("👤  Logged in as {}\n\n", json::get_str(&json, "email")?);
     if getenv("DROPBOX_BASE_FOLDER").is_err() {
-        dropbox::choose_folder(http, sqlite).await;
+        dropbox::choose_folder(http, sqlite).await?;
     }
+    Ok(())
 }
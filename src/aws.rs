@@ -1,41 +1,63 @@
-use crate::{db, localfs, progress, util};
+use crate::error::{DeepFreezeError, Result};
+use crate::shutdown::Shutdown;
+use crate::{checksum, db, localfs, progress, retry, util};
 use db::DBConnection;
 use deep_freeze::{
     TrackableBodyStream, MAX_CHUNKS, MAX_CHUNK_SIZE, MAX_UPLOAD_SIZE, MIN_CHUNK_SIZE,
 };
-use util::setenv;
+use util::{getenv, setenv};
 
-use aws_config::{meta::region::RegionProviderChain, BehaviorVersion, SdkConfig};
+use aws_config::{
+    meta::region::RegionProviderChain, retry::RetryConfig, BehaviorVersion, SdkConfig,
+};
 use aws_sdk_s3::{
-    config::Region,
-    error::SdkError,
+    config::{http::HttpResponse, Region},
+    error::{ProvideErrorMetadata, SdkError},
     operation::{
+        abort_multipart_upload::{AbortMultipartUploadError, AbortMultipartUploadOutput},
         complete_multipart_upload::{CompleteMultipartUploadError, CompleteMultipartUploadOutput},
         create_multipart_upload::{CreateMultipartUploadError, CreateMultipartUploadOutput},
         delete_object::{DeleteObjectError, DeleteObjectOutput},
         get_object_attributes::GetObjectAttributesOutput,
+        head_object::HeadObjectOutput,
         list_buckets::{ListBucketsError, ListBucketsOutput},
-        put_object::{PutObjectError, PutObjectOutput},
+        list_multipart_uploads::ListMultipartUploadsError,
+        list_parts::ListPartsError,
+        put_object::PutObjectOutput,
         upload_part::{UploadPartError, UploadPartOutput},
     },
-    types::{CompletedMultipartUpload, CompletedPart, ObjectAttributes, StorageClass},
+    types::{
+        ChecksumAlgorithm, CompletedMultipartUpload, CompletedPart, GlacierJobParameters,
+        MultipartUpload, ObjectAttributes, Part, RestoreRequest, StorageClass, Tag, Tagging, Tier,
+    },
     Client, Error,
 };
 
+use aws_sdk_s3::primitives::ByteStream;
 use aws_sdk_secretsmanager::Client as SecretsClient;
 use aws_smithy_types::byte_stream::Length;
-use aws_sdk_s3::primitives::ByteStream;
+use futures::{Stream, StreamExt};
+use hyper::body::Bytes;
 use indicatif::HumanBytes;
-use std::path::PathBuf;
+use std::{collections::HashMap, path::PathBuf};
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::task::JoinSet;
 
 pub type AWSClient = Client;
+/// User metadata stored with an object, e.g. how it was encrypted.
+pub type Metadata = HashMap<String, String>;
+
+const DEFAULT_PARALLEL_PARTS: usize = 4;
 
 pub async fn new_config() -> SdkConfig {
     let region_provider = RegionProviderChain::first_try(Region::new("us-east-1"))
         .or_default_provider()
         .or_else(Region::new("us-east-1"));
+    // Retries are handled by `retry::with_retry`, so the SDK's own are turned off to keep
+    // `MAX_ATTEMPTS` the one place that decides how often a request is tried.
     aws_config::defaults(BehaviorVersion::latest())
         .region(region_provider)
+        .retry_config(RetryConfig::disabled())
         .load()
         .await
 }
@@ -45,23 +67,19 @@ pub async fn new_secrets_client() -> SecretsClient {
     SecretsClient::new(&sdk_config)
 }
 
-pub async fn get_app_secret() -> &'static str {
+pub async fn get_app_secret() -> Result<&'static str> {
     let secrets = crate::aws::new_secrets_client().await;
-    let resp = secrets
-        .get_secret_value()
-        .secret_id("DropboxAppSecret")
-        .send()
-        .await
-        .unwrap();
+    let resp = retry::with_retry("GetSecretValue", || {
+        secrets
+            .get_secret_value()
+            .secret_id("DropboxAppSecret")
+            .send()
+    })
+    .await?;
     let secretstring = resp.secret_string().unwrap_or("No value!").to_string();
-    let secretjson = crate::json::from_res(&secretstring);
-    let app_secret = secretjson
-        .get("dropbox_app_secret")
-        .unwrap()
-        .as_str()
-        .unwrap()
-        .to_owned();
-    crate::util::coerce_static_str(app_secret)
+    let secretjson = crate::json::from_res(&secretstring)?;
+    let app_secret = crate::json::get_str(&secretjson, "dropbox_app_secret")?.to_owned();
+    Ok(crate::util::coerce_static_str(app_secret))
 }
 
 pub async fn new_client() -> Client {
@@ -72,50 +90,44 @@ pub async fn new_client() -> Client {
 pub async fn list_buckets(
     client: &Client,
 ) -> Result<ListBucketsOutput, SdkError<ListBucketsError>> {
-    match client.list_buckets().send().await {
+    match retry::with_retry("ListBuckets", || client.list_buckets().send()).await {
         Ok(res) => Ok(res),
         Err(err) => Err(err),
     }
 }
 
-pub async fn choose_bucket(client: &Client, sqlite: &DBConnection) {
-    let buckets = list_buckets(&client).await.unwrap();
+pub async fn choose_bucket(client: &Client, sqlite: &DBConnection) -> Result<()> {
+    let buckets = list_buckets(client).await?;
     let bucket_names = buckets
-        .buckets
-        .unwrap()
+        .buckets()
         .iter()
-        .map(|b| b.name.as_ref().unwrap().to_string())
+        .filter_map(|b| b.name().map(str::to_string))
         .collect::<Vec<String>>();
-    match inquire::Select::new(
+    let choice = inquire::Select::new(
         "🗄️  Which S3 Bucket shall we freeze your files in?",
         bucket_names,
     )
     .with_page_size(20)
-    .prompt()
-    {
-        Ok(choice) => {
-            println!("🗄️  You chose {choice}");
-            setenv("AWS_S3_BUCKET", choice.to_string()).await;
-            // let aws_region = get_bucket_region(&client, &choice).await.unwrap();
-            // dbg!(&aws_region);
-            // let aws_region = get_bucket_region(&aws, "font.vegify.app")
-            //     .await
-            //     .unwrap();
-            // dbg!(&aws_region.location_constraint().unwrap().as_str());
-            // dbg!(&aws_region);
-            // let config = get_bucket_acceleration_config(client, choice)
-            //     .await
-            //     .unwrap();
-            // if &config.status().unwrap().as_str() == &"Enabled" {
-            //     println!("🚀  Acceleration enabled");
-            //     setenv("AWS_S3_BUCKET_ACCELERATION", "true".to_string());
-            // } else {
-            //     setenv("AWS_S3_BUCKET_ACCELERATION", "false".to_string());
-            // }
-            db::insert_config(&sqlite);
-        }
-        Err(err) => panic!("❌  Error choosing folder {err}"),
-    }
+    .prompt()?;
+    println!("🗄️  You chose {choice}");
+    setenv("AWS_S3_BUCKET", choice.to_string()).await?;
+    // let aws_region = get_bucket_region(&client, &choice).await.unwrap();
+    // dbg!(&aws_region);
+    // let aws_region = get_bucket_region(&aws, "font.vegify.app")
+    //     .await
+    //     .unwrap();
+    // dbg!(&aws_region.location_constraint().unwrap().as_str());
+    // dbg!(&aws_region);
+    // let config = get_bucket_acceleration_config(client, choice)
+    //     .await
+    //     .unwrap();
+    // if &config.status().unwrap().as_str() == &"Enabled" {
+    //     println!("🚀  Acceleration enabled");
+    //     setenv("AWS_S3_BUCKET_ACCELERATION", "true".to_string());
+    // } else {
+    //     setenv("AWS_S3_BUCKET_ACCELERATION", "false".to_string());
+    // }
+    db::insert_config(sqlite)
 }
 
 // pub async fn _get_bucket_acceleration_config(
@@ -155,13 +167,16 @@ pub async fn get_s3_attrs(
     bucket: &str,
     key: &String,
 ) -> Result<GetObjectAttributesOutput, Error> {
-    let res = client
-        .get_object_attributes()
-        .bucket(bucket)
-        .key(key)
-        .object_attributes(ObjectAttributes::ObjectSize)
-        .send()
-        .await?;
+    let res = retry::with_retry("GetObjectAttributes", || {
+        client
+            .get_object_attributes()
+            .bucket(bucket)
+            .key(key)
+            .object_attributes(ObjectAttributes::ObjectSize)
+            .object_attributes(ObjectAttributes::Checksum)
+            .send()
+    })
+    .await?;
     Ok::<GetObjectAttributesOutput, Error>(res)
 }
 
@@ -169,14 +184,19 @@ pub async fn create_multipart_upload(
     client: &Client,
     bucket: &str,
     key: &str,
+    metadata: &Metadata,
 ) -> Result<CreateMultipartUploadOutput, SdkError<CreateMultipartUploadError>> {
-    match client
-        .create_multipart_upload()
-        .bucket(bucket)
-        .key(key)
-        .storage_class(StorageClass::DeepArchive)
-        .send()
-        .await
+    match retry::with_retry("CreateMultipartUpload", || {
+        client
+            .create_multipart_upload()
+            .bucket(bucket)
+            .key(key)
+            .storage_class(StorageClass::DeepArchive)
+            .checksum_algorithm(ChecksumAlgorithm::Sha256)
+            .set_metadata(Some(metadata.clone()))
+            .send()
+    })
+    .await
     {
         Ok(res) => Ok(res),
         Err(err) => {
@@ -187,6 +207,8 @@ pub async fn create_multipart_upload(
     }
 }
 
+/// Uploads one part, retrying just this part on transient failures. `stream` has to be
+/// replayable (read from a file range or from memory) so each attempt can resend it.
 pub async fn upload_part(
     client: &Client,
     key: &str,
@@ -194,16 +216,24 @@ pub async fn upload_part(
     upload_id: &str,
     stream: ByteStream,
     part_number: i32,
+    checksum_sha256: &str,
 ) -> Result<UploadPartOutput, SdkError<UploadPartError>> {
-    match client
-        .upload_part()
-        .key(key)
-        .bucket(bucket)
-        .upload_id(upload_id)
-        .body(stream)
-        .part_number(part_number)
-        .send()
-        .await
+    let body = stream.into_inner();
+    match retry::with_retry(&format!("UploadPart {part_number}"), || {
+        client
+            .upload_part()
+            .key(key)
+            .bucket(bucket)
+            .upload_id(upload_id)
+            .body(ByteStream::new(
+                body.try_clone().expect("part bodies are replayable"),
+            ))
+            .part_number(part_number)
+            .checksum_algorithm(ChecksumAlgorithm::Sha256)
+            .checksum_sha256(checksum_sha256)
+            .send()
+    })
+    .await
     {
         Ok(res) => Ok(res),
         Err(err) => {
@@ -221,65 +251,221 @@ async fn complete_multipart_upload(
     completed_multipart_upload: CompletedMultipartUpload,
     upload_id: &str,
 ) -> Result<CompleteMultipartUploadOutput, SdkError<CompleteMultipartUploadError>> {
-    match client
-        .complete_multipart_upload()
-        .bucket(bucket)
-        .key(key)
-        .multipart_upload(completed_multipart_upload)
-        .upload_id(upload_id)
-        .send()
-        .await
+    match retry::with_retry("CompleteMultipartUpload", || {
+        client
+            .complete_multipart_upload()
+            .bucket(bucket)
+            .key(key)
+            .multipart_upload(completed_multipart_upload.clone())
+            .upload_id(upload_id)
+            .send()
+    })
+    .await
     {
         Ok(res) => Ok(res),
         Err(err) => Err(err),
     }
 }
 
-pub async fn multipart_upload(
+pub async fn abort_multipart_upload(
     client: &Client,
+    bucket: &str,
     key: &str,
-    local_path: &str,
+    upload_id: &str,
+) -> Result<AbortMultipartUploadOutput, SdkError<AbortMultipartUploadError>> {
+    match retry::with_retry("AbortMultipartUpload", || {
+        client
+            .abort_multipart_upload()
+            .bucket(bucket)
+            .key(key)
+            .upload_id(upload_id)
+            .send()
+    })
+    .await
+    {
+        Ok(res) => {
+            println!("🗑️  Aborted multipart upload for s3://{bucket}/{key}");
+            Ok(res)
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// Lists every multipart upload in `bucket` that was started but never completed or aborted.
+pub async fn list_multipart_uploads(
+    client: &Client,
     bucket: &str,
-    m: &crate::progress::MultiProgress,
-) -> Result<CompleteMultipartUploadOutput, SdkError<CompleteMultipartUploadError>> {
-    let res = create_multipart_upload(client, bucket, key).await.unwrap();
-    let upload_id = res.upload_id().unwrap();
+) -> Result<Vec<MultipartUpload>, SdkError<ListMultipartUploadsError>> {
+    let mut uploads: Vec<MultipartUpload> = Vec::new();
+    let mut key_marker: Option<String> = None;
+    let mut upload_id_marker: Option<String> = None;
+    loop {
+        let res = retry::with_retry("ListMultipartUploads", || {
+            client
+                .list_multipart_uploads()
+                .bucket(bucket)
+                .set_key_marker(key_marker.clone())
+                .set_upload_id_marker(upload_id_marker.clone())
+                .send()
+        })
+        .await?;
+        uploads.extend_from_slice(res.uploads());
+        match res.is_truncated() {
+            Some(true) => {
+                key_marker = res.next_key_marker().map(str::to_string);
+                upload_id_marker = res.next_upload_id_marker().map(str::to_string);
+            }
+            _ => return Ok(uploads),
+        }
+    }
+}
+
+/// Lists every part S3 holds for an unfinished multipart upload.
+pub async fn list_parts(
+    client: &Client,
+    bucket: &str,
+    key: &str,
+    upload_id: &str,
+) -> Result<Vec<Part>, SdkError<ListPartsError>> {
+    let mut parts: Vec<Part> = Vec::new();
+    let mut part_number_marker: Option<String> = None;
+    loop {
+        let res = retry::with_retry("ListParts", || {
+            client
+                .list_parts()
+                .bucket(bucket)
+                .key(key)
+                .upload_id(upload_id)
+                .set_part_number_marker(part_number_marker.clone())
+                .send()
+        })
+        .await?;
+        parts.extend_from_slice(res.parts());
+        match (res.is_truncated(), res.next_part_number_marker()) {
+            (Some(true), Some(marker)) => part_number_marker = Some(marker.to_string()),
+            _ => return Ok(parts),
+        }
+    }
+}
+
+/// Picks up the multipart upload recorded for `source_id` if S3 still knows about it,
+/// returning its id together with the parts that are already up, stopping at the first
+/// missing one. Otherwise any stale upload is aborted and a new one is started and recorded.
+async fn resume_or_create_multipart_upload(
+    client: &Client,
+    (sqlite, source_id): (&DBConnection, &str),
+    (key, bucket): (&str, &str),
+    metadata: &Metadata,
+) -> Result<(String, Vec<CompletedPart>)> {
+    if let Some((s3_key, upload_id)) = db::get_multipart_upload(sqlite, source_id)? {
+        match list_parts(client, bucket, &s3_key, &upload_id).await {
+            Ok(listed) if s3_key == key => {
+                let saved = db::get_multipart_parts(sqlite, source_id)?;
+                let upload_parts = resumable_parts(&listed, &saved);
+                println!(
+                    "⏯️  Resuming upload of s3://{bucket}/{key} at part {}",
+                    upload_parts.len() + 1
+                );
+                return Ok((upload_id, upload_parts));
+            }
+            Ok(_) => (),
+            Err(err) => println!("🚫  Could not resume upload {upload_id}: {err}"),
+        }
+        abort_multipart_upload(client, bucket, &s3_key, &upload_id)
+            .await
+            .ok();
+        db::delete_multipart_upload(sqlite, source_id)?;
+    }
+    let res = create_multipart_upload(client, bucket, key, metadata).await?;
+    let upload_id = res.upload_id().unwrap_or_default().to_string();
+    db::insert_multipart_upload(sqlite, source_id, key, &upload_id)?;
+    Ok((upload_id, Vec::new()))
+}
+
+/// The leading run of parts S3 reports as uploaded, numbered 1, 2, 3... without a gap.
+/// A part's checksum comes from S3 when it returns one, otherwise from what was saved in
+/// the database when the part finished.
+fn resumable_parts(listed: &[Part], saved: &[(i32, String, String)]) -> Vec<CompletedPart> {
     let mut upload_parts: Vec<CompletedPart> = Vec::new();
+    for part_number in 1.. {
+        let Some(part) = listed
+            .iter()
+            .find(|part| part.part_number() == Some(part_number))
+        else {
+            break;
+        };
+        let saved_checksum = saved
+            .iter()
+            .find(|(saved_number, _, _)| *saved_number == part_number)
+            .map(|(_, _, checksum_sha256)| checksum_sha256.as_str());
+        let Some(checksum_sha256) = part.checksum_sha256().or(saved_checksum) else {
+            break;
+        };
+        upload_parts.push(
+            CompletedPart::builder()
+                .e_tag(part.e_tag().unwrap_or_default())
+                .part_number(part_number)
+                .checksum_sha256(checksum_sha256)
+                .build(),
+        );
+    }
+    upload_parts
+}
+
+fn record_part(sqlite: &DBConnection, source_id: &str, part: &CompletedPart) -> Result<()> {
+    db::insert_multipart_part(
+        sqlite,
+        source_id,
+        part.part_number().unwrap_or_default(),
+        part.e_tag().unwrap_or_default(),
+        part.checksum_sha256().unwrap_or_default(),
+    )
+}
 
-    let file_size = localfs::get_local_size(&local_path).await as u64;
-    let (chunk_size, chunk_count, size_of_last_chunk) = chunk_math(file_size);
+pub async fn multipart_upload(
+    client: &Client,
+    (sqlite, source_id): (&DBConnection, &str),
+    (key, bucket): (&str, &str),
+    local_path: &str,
+    metadata: &Metadata,
+    (m, shutdown): (&crate::progress::MultiProgress, &Shutdown),
+) -> Result<String> {
+    let (upload_id, mut upload_parts) =
+        resume_or_create_multipart_upload(client, (sqlite, source_id), (key, bucket), metadata)
+            .await?;
+    let upload_id = upload_id.as_str();
+
+    let file_size = localfs::get_local_size(local_path).await? as u64;
+    let (chunk_size, chunk_count, size_of_last_chunk) = chunk_math(file_size)?;
 
     let pb = m.add(progress::new(file_size, "file_transfer"));
     pb.set_prefix("⬆️  Upload  ");
     upload_parts = handle_multipart_chunks_upload(
         (chunk_size, chunk_count, size_of_last_chunk),
-        (&key, &local_path, &bucket, &upload_id),
-        (&client, &mut upload_parts),
-        &pb,
+        (key, local_path, bucket, upload_id),
+        (client, &mut upload_parts),
+        (sqlite, source_id),
+        (&pb, shutdown),
     )
-    .await;
+    .await?;
+    let s3_hash = composite_checksum(&upload_parts);
     let completed_multipart_upload: CompletedMultipartUpload = CompletedMultipartUpload::builder()
         .set_parts(Some(upload_parts))
         .build();
     pb.set_prefix("⏳  Completing upload. ");
-    match complete_multipart_upload(
-        &client,
-        &bucket,
-        &key,
-        completed_multipart_upload,
-        &upload_id,
-    )
-    .await
+    match complete_multipart_upload(client, bucket, key, completed_multipart_upload, upload_id)
+        .await
     {
-        Ok(res) => {
+        Ok(_) => {
+            db::delete_multipart_upload(sqlite, source_id)?;
             pb.set_prefix("✅  Upload   ");
             pb.finish();
-            Ok(res)
+            Ok(s3_hash)
         }
         Err(err) => {
             dbg!(&err);
             println!("🚫  {err}");
-            Err(err)
+            Err(err.into())
         }
     }
 }
@@ -288,9 +474,14 @@ async fn handle_multipart_chunks_upload(
     (chunk_size, chunk_count, size_of_last_chunk): (u64, u64, u64),
     (key, local_path, bucket, upload_id): (&str, &str, &str, &str),
     (client, upload_parts): (&Client, &mut Vec<CompletedPart>),
-    pb: &crate::progress::Progress,
-) -> Vec<CompletedPart> {
-    for chunk_index in 0..chunk_count {
+    (sqlite, source_id): (&DBConnection, &str),
+    (pb, shutdown): (&crate::progress::Progress, &Shutdown),
+) -> Result<Vec<CompletedPart>> {
+    let resumed = upload_parts.len() as u64;
+    pb.set_position(resumed * chunk_size);
+    for chunk_index in resumed..chunk_count {
+        // Every finished part is recorded, so stopping here leaves the upload resumable.
+        shutdown.checkpoint(|| format!("{chunk_index} of {chunk_count} parts uploaded"))?;
         let this_chunk = if chunk_count - 1 == chunk_index {
             size_of_last_chunk
         } else {
@@ -304,121 +495,408 @@ async fn handle_multipart_chunks_upload(
             .length(Length::Exact(this_chunk))
             .build()
             .await
-            .unwrap();
+            .map_err(|err| DeepFreezeError::S3(err.to_string()))?;
+        let part_checksum = checksum::s3_checksum(
+            &checksum::sha256_of_file_range(local_path, uploaded, this_chunk).await?,
+        );
         //Chunk index needs to start at 0, but part numbers start at 1.
         let part_number = (chunk_index as i32) + 1;
-        let upload_part_res = upload_part(&client, &key, &bucket, &upload_id, stream, part_number)
-            .await
-            .unwrap();
+        let upload_part_res = upload_part(
+            client,
+            key,
+            bucket,
+            upload_id,
+            stream,
+            part_number,
+            &part_checksum,
+        )
+        .await?;
 
-        upload_parts.push(
-            CompletedPart::builder()
-                .e_tag(upload_part_res.e_tag.unwrap_or_default())
-                .part_number(part_number)
-                .build(),
-        );
+        let part = CompletedPart::builder()
+            .e_tag(upload_part_res.e_tag.unwrap_or_default())
+            .part_number(part_number)
+            .checksum_sha256(part_checksum)
+            .build();
+        record_part(sqlite, source_id, &part)?;
+        upload_parts.push(part);
         pb.set_position(uploaded + this_chunk);
     }
-    upload_parts.to_owned()
+    Ok(upload_parts.to_owned())
+}
+
+/// The composite checksum S3 will report for an object assembled from these parts.
+fn composite_checksum(upload_parts: &[CompletedPart]) -> String {
+    let part_checksums = upload_parts
+        .iter()
+        .map(|part| part.checksum_sha256().unwrap_or_default())
+        .collect::<Vec<&str>>();
+    checksum::s3_composite_checksum(&part_checksums)
 }
 
 pub async fn singlepart_upload(
     client: &Client,
-    key: &str,
+    (key, bucket): (&str, &str),
     local_path: &str,
-    bucket: &str,
+    checksum_sha256: &str,
+    metadata: &Metadata,
     m: &crate::progress::MultiProgress,
-) -> Result<PutObjectOutput, SdkError<PutObjectError>> {
-    let mut body = TrackableBodyStream::try_from(PathBuf::from(local_path))
-        .map_err(|e| {
-            panic!("Could not open sample file: {}", e);
-        })
-        .unwrap();
+) -> Result<PutObjectOutput> {
     let pb = m.add(crate::progress::new(
-        body.content_length() as u64,
+        localfs::get_local_size(local_path).await? as u64,
         "file_transfer",
     ));
     pb.set_prefix("⬆️   Upload   ");
-    body.set_callback(move |tot_size: u64, sent: u64, cur_buf: u64| {
-        pb.inc(cur_buf as u64);
-        if sent == tot_size {
-            pb.set_prefix("✅  Upload   ");
-            pb.finish();
-        }
-    });
     // err in prod 7/4/2023 ~6pm
     // thread 'main' panicked at 'called `Result::unwrap()` on an `Err` value: DispatchFailure(DispatchFailure { source: ConnectorError { kind: Other(Some(TransientError)), source: hyper::Error(IncompleteMessage), connection: Unknown } })', src/aws.rs:176:10
     // note: run with `RUST_BACKTRACE=1` environment variable to display a backtrace
-    match client
-        .put_object()
-        .storage_class(StorageClass::DeepArchive)
-        .bucket(bucket)
-        .key(key)
-        .content_length(body.content_length())
-        .body(body.to_s3_stream())
-        .send()
-        .await
+    // Each attempt reopens the file, since a body that has been partly sent can't be replayed.
+    // A file that can't be opened any more fails the request before it is sent, which
+    // isn't retried.
+    match retry::with_retry("PutObject", || {
+        let body = TrackableBodyStream::try_from(PathBuf::from(local_path));
+        let pb = pb.clone();
+        async move {
+            let mut body = body.map_err(SdkError::construction_failure)?;
+            pb.set_position(0);
+            body.set_callback(move |tot_size: u64, sent: u64, cur_buf: u64| {
+                pb.inc(cur_buf);
+                if sent == tot_size {
+                    pb.set_prefix("✅  Upload   ");
+                    pb.finish();
+                }
+            });
+            client
+                .put_object()
+                .storage_class(StorageClass::DeepArchive)
+                .bucket(bucket)
+                .key(key)
+                .content_length(body.content_length())
+                .checksum_algorithm(ChecksumAlgorithm::Sha256)
+                .checksum_sha256(checksum_sha256)
+                .set_metadata(Some(metadata.clone()))
+                .body(body.to_s3_stream())
+                .send()
+                .await
+        }
+    })
+    .await
     {
         Ok(res) => Ok(res),
         Err(err) => {
             dbg!(&err);
             println!("🚫  Upload failed");
-            Err(err)
+            Err(io_or_s3_error(err))
         }
     }
 }
 
+/// The IO error a request failed with before it was sent, such as a body file that
+/// couldn't be opened, or else the S3 error.
+fn io_or_s3_error<E>(err: SdkError<E, HttpResponse>) -> DeepFreezeError
+where
+    E: std::error::Error + Send + Sync + 'static,
+{
+    match err {
+        SdkError::ConstructionFailure(_) => match err.into_source() {
+            Ok(source) => match source.downcast::<std::io::Error>() {
+                Ok(err) => DeepFreezeError::Io(*err),
+                Err(source) => DeepFreezeError::S3(source.to_string()),
+            },
+            Err(err) => err.into(),
+        },
+        err => err.into(),
+    }
+}
+
 pub async fn upload_to_s3(
     client: &Client,
-    key: &str,
+    (sqlite, source_id): (&DBConnection, &str),
+    (key, bucket): (&str, &str),
     local_path: &str,
-    bucket: &str,
-    m: &crate::progress::MultiProgress,
-) -> Result<(), Box<(dyn std::error::Error + 'static)>> {
-    match localfs::get_local_size(&local_path).await {
+    metadata: &Metadata,
+    (m, shutdown): (&crate::progress::MultiProgress, &Shutdown),
+) -> Result<String> {
+    match localfs::get_local_size(local_path).await? {
         // 0 => panic!("file has no size"),
-        size if size >= MAX_UPLOAD_SIZE as i64 => panic!("file is too big"),
+        size if size >= MAX_UPLOAD_SIZE as i64 => Err(too_big(size as u64)),
         size if size < MAX_CHUNK_SIZE as i64 => {
-            match singlepart_upload(&client, &key, &local_path, &bucket, &m).await {
-                Ok(_) => Ok(()),
+            let s3_hash = checksum::s3_checksum(
+                &checksum::sha256_of_file_range(local_path, 0, size as u64).await?,
+            );
+            match singlepart_upload(client, (key, bucket), local_path, &s3_hash, metadata, m).await
+            {
+                Ok(_) => Ok(s3_hash),
                 Err(err) => {
                     println!("🚫  {err}");
                     // Err(SdkError::from(err))
                     // Err(PutObjectError::from(err).into())
-                    Err(err.into())
+                    Err(err)
                 }
             }
         }
-        _ => match multipart_upload(&client, &key, &local_path, &bucket, &m).await {
-            Ok(_) => Ok(()),
+        _ => match multipart_upload(
+            client,
+            (sqlite, source_id),
+            (key, bucket),
+            local_path,
+            metadata,
+            (m, shutdown),
+        )
+        .await
+        {
+            Ok(s3_hash) => Ok(s3_hash),
             Err(err) => {
                 println!("🚫  {err}");
-                Err(err.into())
+                Err(err)
             }
         },
     }
 }
 
+fn get_parallel_parts() -> usize {
+    getenv("PARALLEL_PARTS")
+        .unwrap_or_default()
+        .parse::<usize>()
+        .unwrap_or(DEFAULT_PARALLEL_PARTS)
+        .max(1)
+}
+
+/// Uploads a byte stream of known `size` without staging it on disk, with `metadata`. Objects smaller
+/// than one part go up in a single `PutObject`; anything larger is cut into `chunk_math`
+/// sized parts, with at most `PARALLEL_PARTS` parts held in memory at once.
+///
+/// `verify` runs once the stream is exhausted but before the object is written (or the
+/// multipart upload completed), so a failed check never leaves an object behind.
+///
+/// A multipart upload left unfinished by an earlier run is resumed: the bytes of parts
+/// S3 already holds are still read from the stream (and so still hashed), but not re-sent.
+/// Once `shutdown` is requested no more parts are started; the upload is left open with
+/// the parts already sent recorded, for the next run to resume. The same goes for a part
+/// that outlasts its retries or a source stream that breaks off: only a failed size
+/// check or `verify` aborts the upload and forgets its parts.
+pub async fn stream_upload<S, E, V>(
+    client: &Client,
+    (sqlite, source_id): (&DBConnection, &str),
+    (key, bucket): (&str, &str),
+    (size, mut stream): (u64, S),
+    verify: V,
+    metadata: &Metadata,
+    (m, shutdown): (&crate::progress::MultiProgress, &Shutdown),
+) -> Result<String>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+    DeepFreezeError: From<E>,
+    V: FnOnce() -> Result<()>,
+{
+    if size >= MAX_UPLOAD_SIZE {
+        return Err(too_big(size));
+    }
+    let pb = m.add(progress::new(size, "file_transfer"));
+    pb.set_prefix("⬆️   Stream   ");
+    if size < MIN_CHUNK_SIZE {
+        let mut body: Vec<u8> = Vec::with_capacity(size as usize);
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk?;
+            pb.inc(chunk.len() as u64);
+            body.extend_from_slice(&chunk);
+        }
+        check_streamed_size(body.len() as u64, size)?;
+        verify()?;
+        let s3_hash = checksum::s3_checksum(&checksum::sha256(&body));
+        let body = Bytes::from(body);
+        retry::with_retry("PutObject", || {
+            client
+                .put_object()
+                .storage_class(StorageClass::DeepArchive)
+                .bucket(bucket)
+                .key(key)
+                .content_length(size as i64)
+                .checksum_algorithm(ChecksumAlgorithm::Sha256)
+                .checksum_sha256(&s3_hash)
+                .set_metadata(Some(metadata.clone()))
+                .body(ByteStream::from(body.clone()))
+                .send()
+        })
+        .await?;
+        pb.set_prefix("✅  Stream   ");
+        pb.finish();
+        return Ok(s3_hash);
+    }
+    let (upload_id, resumed) =
+        resume_or_create_multipart_upload(client, (sqlite, source_id), (key, bucket), metadata)
+            .await?;
+    let upload_id = upload_id.as_str();
+    let (upload_parts, received) = handle_multipart_stream_upload(
+        client,
+        (sqlite, source_id),
+        (key, bucket, upload_id),
+        (size, stream, resumed),
+        (&pb, shutdown),
+    )
+    .await?;
+    if let Err(err) = check_streamed_size(received, size).and_then(|_| verify()) {
+        println!("🚫  {err}");
+        db::delete_multipart_upload(sqlite, source_id)?;
+        abort_multipart_upload(client, bucket, key, upload_id).await?;
+        return Err(err);
+    }
+    let s3_hash = composite_checksum(&upload_parts);
+    let completed_multipart_upload: CompletedMultipartUpload = CompletedMultipartUpload::builder()
+        .set_parts(Some(upload_parts))
+        .build();
+    pb.set_prefix("⏳  Completing upload. ");
+    complete_multipart_upload(client, bucket, key, completed_multipart_upload, upload_id).await?;
+    db::delete_multipart_upload(sqlite, source_id)?;
+    pb.set_prefix("✅  Stream   ");
+    pb.finish();
+    Ok(s3_hash)
+}
+
+/// Sends the parts of `stream` S3 doesn't hold yet, recording each one as it completes.
+/// Returns every part, in order, and how many bytes the stream gave.
+async fn handle_multipart_stream_upload<S, E>(
+    client: &Client,
+    (sqlite, source_id): (&DBConnection, &str),
+    (key, bucket, upload_id): (&str, &str, &str),
+    (size, mut stream, resumed): (u64, S, Vec<CompletedPart>),
+    (pb, shutdown): (&crate::progress::Progress, &Shutdown),
+) -> Result<(Vec<CompletedPart>, u64)>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+    DeepFreezeError: From<E>,
+{
+    let (chunk_size, chunk_count, _) = chunk_math(size)?;
+    let chunk_size = chunk_size as usize;
+    let parallel_parts = get_parallel_parts();
+    let mut in_flight = JoinSet::new();
+    let skip_parts = resumed.len() as i32;
+    let mut upload_parts: Vec<CompletedPart> = Vec::with_capacity(chunk_count as usize);
+    upload_parts.extend(resumed);
+    let mut buffer: Vec<u8> = Vec::with_capacity(chunk_size);
+    let mut received: u64 = 0;
+    let mut part_number: i32 = 0;
+    loop {
+        if shutdown.requested() {
+            while let Some(res) = in_flight.join_next().await {
+                let part = res??;
+                record_part(sqlite, source_id, &part)?;
+                upload_parts.push(part);
+            }
+            shutdown
+                .checkpoint(|| format!("{} of {chunk_count} parts uploaded", upload_parts.len()))?;
+        }
+        let next = stream.next().await;
+        let finished = next.is_none();
+        if let Some(chunk) = next {
+            let chunk = chunk?;
+            received += chunk.len() as u64;
+            pb.set_position(received);
+            buffer.extend_from_slice(&chunk);
+        }
+        while buffer.len() >= chunk_size || (finished && !buffer.is_empty()) {
+            let rest = buffer.split_off(chunk_size.min(buffer.len()));
+            let part = Bytes::from(std::mem::replace(&mut buffer, rest));
+            part_number += 1;
+            if part_number <= skip_parts {
+                continue;
+            }
+            if in_flight.len() >= parallel_parts {
+                if let Some(res) = in_flight.join_next().await {
+                    let part = res??;
+                    record_part(sqlite, source_id, &part)?;
+                    upload_parts.push(part);
+                }
+            }
+            pb.set_prefix(format!("⬆️   Stream: Chunk {part_number}/{chunk_count} | "));
+            let (client, key, bucket, upload_id) = (
+                client.clone(),
+                key.to_string(),
+                bucket.to_string(),
+                upload_id.to_string(),
+            );
+            in_flight.spawn(async move {
+                let part_checksum = checksum::s3_checksum(&checksum::sha256(&part));
+                let stream = ByteStream::from(part);
+                let res = upload_part(
+                    &client,
+                    &key,
+                    &bucket,
+                    &upload_id,
+                    stream,
+                    part_number,
+                    &part_checksum,
+                )
+                .await?;
+                Ok::<CompletedPart, SdkError<UploadPartError>>(
+                    CompletedPart::builder()
+                        .e_tag(res.e_tag.unwrap_or_default())
+                        .part_number(part_number)
+                        .checksum_sha256(part_checksum)
+                        .build(),
+                )
+            });
+        }
+        if finished {
+            break;
+        }
+    }
+    while let Some(res) = in_flight.join_next().await {
+        let part = res??;
+        record_part(sqlite, source_id, &part)?;
+        upload_parts.push(part);
+    }
+    upload_parts.sort_by_key(|part| part.part_number());
+    Ok((upload_parts, received))
+}
+
+fn check_streamed_size(received: u64, size: u64) -> Result<()> {
+    match received == size {
+        true => Ok(()),
+        false => Err(DeepFreezeError::Integrity(format!(
+            "Streamed {} but expected {}",
+            HumanBytes(received),
+            HumanBytes(size)
+        ))),
+    }
+}
+
+fn too_big(size: u64) -> DeepFreezeError {
+    DeepFreezeError::S3(format!(
+        "{} is over the {} S3 object size limit",
+        HumanBytes(size),
+        HumanBytes(MAX_UPLOAD_SIZE)
+    ))
+}
+
+/// Checks the object at `key` is `expected_size` bytes (the Dropbox size, or what it
+/// grew to when encrypted) and, given `s3_hash`, has that checksum.
 pub async fn confirm_upload_size(
-    sqlite: &sqlite::ConnectionWithFullMutex,
     aws: &Client,
     bucket: &str,
-    dropbox_id: &str,
     key: &String,
-) -> Result<(), Box<(dyn std::error::Error + 'static)>> {
-    let s3_attrs: GetObjectAttributesOutput = get_s3_attrs(&aws, &bucket, &key).await?;
+    expected_size: i64,
+    s3_hash: Option<&str>,
+) -> Result<()> {
+    let s3_attrs: GetObjectAttributesOutput = get_s3_attrs(aws, bucket, key).await?;
     let s3_size = s3_attrs.object_size().unwrap_or_default();
-    let dropbox_size = db::get_dropbox_size(&sqlite, &dropbox_id);
-    match s3_size == dropbox_size {
-        true => return Ok(()),
-        false => {
-            return Err(format!(
-                "DropBox file size {} does not match S3 {}",
-                HumanBytes(dropbox_size.try_into().unwrap()),
-                HumanBytes(s3_size.try_into().unwrap())
-            )
-            .into());
+    if s3_size != expected_size {
+        return Err(DeepFreezeError::Integrity(format!(
+            "Expected size {} does not match S3 {}",
+            HumanBytes(expected_size as u64),
+            HumanBytes(s3_size as u64)
+        )));
+    }
+    let s3_checksum = s3_attrs
+        .checksum()
+        .and_then(|checksum| checksum.checksum_sha256())
+        .unwrap_or_default();
+    match s3_hash {
+        Some(s3_hash) if !checksum::s3_checksums_match(s3_checksum, s3_hash) => {
+            Err(DeepFreezeError::Integrity(format!(
+                "S3 checksum {s3_checksum} does not match local {s3_hash}"
+            )))
         }
+        _ => Ok(()),
     }
 }
 
@@ -427,7 +905,11 @@ pub async fn delete_from_s3(
     bucket: &str,
     key: &str,
 ) -> Result<DeleteObjectOutput, SdkError<DeleteObjectError>> {
-    match client.delete_object().bucket(bucket).key(key).send().await {
+    match retry::with_retry("DeleteObject", || {
+        client.delete_object().bucket(bucket).key(key).send()
+    })
+    .await
+    {
         Ok(res) => {
             println!("🗑️  Deleted s3://{}/{}", bucket, key);
             Ok(res)
@@ -436,6 +918,96 @@ pub async fn delete_from_s3(
     }
 }
 
+/// Tags an archived object whose file was deleted or moved away in Dropbox, so lifecycle
+/// rules or a later cleanup can find it. The object itself is left in place.
+pub async fn tag_deleted_in_source(client: &Client, bucket: &str, key: &str) -> Result<()> {
+    let tagging = Tagging::builder()
+        .tag_set(
+            Tag::builder()
+                .key("deep-freeze-source")
+                .value("deleted")
+                .build()?,
+        )
+        .build()?;
+    retry::with_retry("PutObjectTagging", || {
+        client
+            .put_object_tagging()
+            .bucket(bucket)
+            .key(key)
+            .tagging(tagging.clone())
+            .send()
+    })
+    .await?;
+    println!("🏷️  Tagged s3://{bucket}/{key} as deleted in Dropbox");
+    Ok(())
+}
+
+/// Asks S3 to bring a Deep Archive object back for `days`, at `tier`. A restore that is
+/// already under way counts as requested.
+pub async fn restore_object(
+    client: &Client,
+    bucket: &str,
+    key: &str,
+    (tier, days): (Tier, i32),
+) -> Result<()> {
+    let restore_request = RestoreRequest::builder()
+        .days(days)
+        .glacier_job_parameters(GlacierJobParameters::builder().tier(tier).build()?)
+        .build();
+    match retry::with_retry("RestoreObject", || {
+        client
+            .restore_object()
+            .bucket(bucket)
+            .key(key)
+            .restore_request(restore_request.clone())
+            .send()
+    })
+    .await
+    {
+        Ok(_) => Ok(()),
+        Err(err) if err.code() == Some("RestoreAlreadyInProgress") => Ok(()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// The object's storage class, metadata and restore status (`x-amz-restore`).
+pub async fn head_object(client: &Client, bucket: &str, key: &str) -> Result<HeadObjectOutput> {
+    Ok(retry::with_retry("HeadObject", || {
+        client.head_object().bucket(bucket).key(key).send()
+    })
+    .await?)
+}
+
+/// Downloads an object, which has to be restored first if it is in Deep Archive.
+pub async fn download_object(
+    client: &Client,
+    (key, bucket): (&str, &str),
+    local_path: &str,
+    m: &crate::progress::MultiProgress,
+) -> Result<()> {
+    let res = retry::with_retry("GetObject", || {
+        client.get_object().bucket(bucket).key(key).send()
+    })
+    .await?;
+    let pb = m.add(progress::new(
+        res.content_length().unwrap_or_default() as u64,
+        "file_transfer",
+    ));
+    pb.set_prefix("⬇️   Download ");
+    localfs::create_download_folder(local_path).await?;
+    let mut file = BufWriter::new(localfs::create_local_file(local_path).await?);
+    let mut body = res.body;
+    while let Some(bytes) = body.next().await {
+        let bytes = bytes.map_err(|err| DeepFreezeError::S3(err.to_string()))?;
+        file.write_all(&bytes).await?;
+        pb.inc(bytes.len() as u64);
+    }
+    file.flush().await?;
+    pb.set_prefix("✅  Download ");
+    pb.finish();
+    Ok(())
+}
+
 pub async fn _empty_test_bucket() {
     println!("🗑️  Emptying test bucket");
     let aws = new_client().await;
@@ -448,10 +1020,10 @@ pub async fn _empty_test_bucket() {
         .unwrap()
         .contents
         .unwrap_or(Vec::new());
-    if objects.len() == 0 {
+    if objects.is_empty() {
         return;
     }
-    while objects.len() > 0 {
+    while !objects.is_empty() {
         for object in objects {
             let key = object.key.unwrap();
             delete_from_s3(&aws, &bucket, &key).await.unwrap();
@@ -467,7 +1039,7 @@ pub async fn _empty_test_bucket() {
     }
 }
 
-pub fn calculate_chunk_count(file_size: u64, chunk_size: u64) -> (u64, u64) {
+pub fn calculate_chunk_count(file_size: u64, chunk_size: u64) -> Result<(u64, u64)> {
     let mut chunk_count = (file_size / chunk_size) + 1;
     let mut size_of_last_chunk = file_size % chunk_size;
     if size_of_last_chunk == 0 {
@@ -475,17 +1047,21 @@ pub fn calculate_chunk_count(file_size: u64, chunk_size: u64) -> (u64, u64) {
         chunk_count -= 1;
     }
     if chunk_count > MAX_CHUNKS {
-        panic!("Too many chunks! Try increasing your chunk size.")
+        return Err(DeepFreezeError::Config(
+            "Too many chunks! Try increasing your chunk size.".to_string(),
+        ));
     }
     if chunk_count == 0 {
-        panic!("No chunks! Try decreasing your chunk size.")
+        return Err(DeepFreezeError::Config(
+            "No chunks! Try decreasing your chunk size.".to_string(),
+        ));
     }
-    (chunk_count, size_of_last_chunk)
+    Ok((chunk_count, size_of_last_chunk))
 }
 
-pub fn chunk_math(file_size: u64) -> (u64, u64, u64) {
+pub fn chunk_math(file_size: u64) -> Result<(u64, u64, u64)> {
     if file_size == 0 {
-        panic!("Bad file size.");
+        return Err(DeepFreezeError::Integrity("Bad file size.".to_string()));
     }
     let mut chunk_size = MIN_CHUNK_SIZE;
     while file_size / chunk_size > MAX_CHUNKS {
@@ -494,8 +1070,8 @@ pub fn chunk_math(file_size: u64) -> (u64, u64, u64) {
     while chunk_size > MAX_CHUNK_SIZE {
         chunk_size -= 1000;
     }
-    let (chunk_count, size_of_last_chunk) = calculate_chunk_count(file_size, chunk_size);
-    (chunk_size, chunk_count, size_of_last_chunk)
+    let (chunk_count, size_of_last_chunk) = calculate_chunk_count(file_size, chunk_size)?;
+    Ok((chunk_size, chunk_count, size_of_last_chunk))
 }
 
 #[cfg(test)]
@@ -511,24 +1087,25 @@ mod tests {
         env::set_var("SILENT", "true");
         let aws = crate::aws::new_client().await;
         let key = String::from("test-s3-upload.txt");
-        let local_path = String::from(format!("./test/{key}"));
+        let local_path = format!("./test/{key}");
 
-        assert_eq!(
-            true,
+        assert!(
             crate::aws::upload_to_s3(
                 &aws,
-                &key,
+                (&crate::db::connect(":memory:").unwrap(), "id:test"),
+                (&key, BUCKET),
                 &local_path,
-                &BUCKET,
-                &&progress::new_multi_progress(),
+                &crate::aws::Metadata::new(),
+                (
+                    &progress::new_multi_progress(),
+                    &crate::shutdown::Shutdown::default(),
+                ),
             )
             .await
             .is_ok(),
             "🚫  file upload unsuccessful"
         );
-        assert!(crate::aws::delete_from_s3(&aws, &BUCKET, &key)
-            .await
-            .is_ok());
+        assert!(crate::aws::delete_from_s3(&aws, BUCKET, &key).await.is_ok());
     }
 
     #[tokio::test]
@@ -537,49 +1114,125 @@ mod tests {
         env::set_var("SILENT", "true");
         let aws = crate::aws::new_client().await;
         let key = String::from("test-s3-get-attrs.txt");
-        let local_path = String::from(format!("./test/{key}"));
-        let local_size = crate::localfs::get_local_size(&local_path).await;
+        let local_path = format!("./test/{key}");
+        let local_size = crate::localfs::get_local_size(&local_path).await.unwrap();
         crate::aws::upload_to_s3(
             &aws,
-            &key,
+            (&crate::db::connect(":memory:").unwrap(), "id:test"),
+            (&key, BUCKET),
             &local_path,
-            &BUCKET,
-            &&progress::new_multi_progress(),
+            &crate::aws::Metadata::new(),
+            (
+                &progress::new_multi_progress(),
+                &crate::shutdown::Shutdown::default(),
+            ),
         )
         .await
         .unwrap();
-        let attrs = crate::aws::get_s3_attrs(&aws, &BUCKET, &key).await.unwrap();
+        let attrs = crate::aws::get_s3_attrs(&aws, BUCKET, &key).await.unwrap();
         assert_eq!(
             attrs.object_size().unwrap_or_default(),
             local_size,
             "🚫  sizes don't match"
         );
         assert!(
-            crate::aws::delete_from_s3(&aws, &BUCKET, &key)
-                .await
-                .is_ok(),
+            crate::aws::delete_from_s3(&aws, BUCKET, &key).await.is_ok(),
             "🚫  file deletion unsuccessful"
         );
     }
 
+    #[tokio::test]
+    async fn it_streams_to_s3() {
+        dotenv::dotenv().ok();
+        env::set_var("SILENT", "true");
+        let aws = crate::aws::new_client().await;
+        let key = String::from("5-MiB-generated.txt");
+        let local_path = format!("./test/{key}");
+        let bytes = hyper::body::Bytes::from(std::fs::read(&local_path).unwrap());
+        let size = bytes.len() as u64;
+        let stream = futures::stream::iter(vec![Ok::<_, std::io::Error>(bytes)]);
+        assert!(
+            crate::aws::stream_upload(
+                &aws,
+                (&crate::db::connect(":memory:").unwrap(), "id:test"),
+                (&key, BUCKET),
+                (size, stream),
+                || Ok(()),
+                &crate::aws::Metadata::new(),
+                (
+                    &progress::new_multi_progress(),
+                    &crate::shutdown::Shutdown::default(),
+                ),
+            )
+            .await
+            .is_ok(),
+            "🚫  stream upload unsuccessful"
+        );
+        let attrs = crate::aws::get_s3_attrs(&aws, BUCKET, &key).await.unwrap();
+        assert_eq!(attrs.object_size().unwrap_or_default(), size as i64);
+        assert!(crate::aws::delete_from_s3(&aws, BUCKET, &key).await.is_ok());
+    }
+
+    #[test]
+    fn it_resumes_from_the_first_missing_part() {
+        let listed = [1, 2, 4].map(|part_number| {
+            aws_sdk_s3::types::Part::builder()
+                .part_number(part_number)
+                .e_tag(format!("etag-{part_number}"))
+                .build()
+        });
+        let saved = [1, 2, 4].map(|part_number| {
+            (
+                part_number,
+                format!("etag-{part_number}"),
+                format!("sum-{part_number}"),
+            )
+        });
+        let parts = crate::aws::resumable_parts(&listed, &saved);
+        assert_eq!(
+            parts
+                .iter()
+                .map(|part| part.part_number())
+                .collect::<Vec<_>>(),
+            vec![Some(1), Some(2)]
+        );
+        assert_eq!(parts[1].checksum_sha256(), Some("sum-2"));
+        assert!(crate::aws::resumable_parts(&listed, &[]).is_empty());
+    }
+
+    #[test]
+    fn it_fails_an_upload_whose_file_is_gone_with_an_io_error() {
+        use aws_sdk_s3::error::SdkError;
+        use aws_sdk_s3::operation::put_object::PutObjectError;
+        let missing = std::io::Error::from(std::io::ErrorKind::NotFound);
+        let err: SdkError<PutObjectError, _> = SdkError::construction_failure(missing);
+        assert!(matches!(
+            crate::aws::io_or_s3_error(err),
+            crate::error::DeepFreezeError::Io(_)
+        ));
+    }
+
     #[tokio::test]
     async fn it_deletes_from_s3() {
         dotenv::dotenv().ok();
         env::set_var("SILENT", "true");
         let aws = crate::aws::new_client().await;
         let key = String::from("test-s3-delete.txt");
-        let local_path = String::from(format!("./test/{key}"));
+        let local_path = format!("./test/{key}");
         crate::aws::upload_to_s3(
             &aws,
-            &key,
+            (&crate::db::connect(":memory:").unwrap(), "id:test"),
+            (&key, BUCKET),
             &local_path,
-            &BUCKET,
-            &&progress::new_multi_progress(),
+            &crate::aws::Metadata::new(),
+            (
+                &progress::new_multi_progress(),
+                &crate::shutdown::Shutdown::default(),
+            ),
         )
         .await
         .unwrap();
-        assert_eq!(
-            true,
+        assert!(
             crate::aws::delete_from_s3(&aws, "deep-freeze-test", "test-s3-delete.txt")
                 .await
                 .is_ok(),
@@ -0,0 +1,416 @@
+use crate::aws::{self, AWSClient};
+use crate::checksum::{self, DropboxContentHasher};
+use crate::crypto;
+use crate::db::{self, DBConnection, SmallFile};
+use crate::error::{DeepFreezeError, Result};
+use crate::localfs;
+use crate::progress::{self, MultiProgress};
+use crate::shutdown::Shutdown;
+use crate::util::{self, getenv};
+use deep_freeze::source::Source;
+use deep_freeze::MAX_CHUNK_SIZE;
+
+use futures::StreamExt;
+use indicatif::HumanBytes;
+use std::collections::BTreeMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::fs::File;
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+
+pub const DEFAULT_BUNDLE_THRESHOLD: i64 = 1024 * 1024;
+pub const DEFAULT_BUNDLE_SIZE: u64 = 256 * 1024 * 1024;
+const BLOCK_SIZE: u64 = 512;
+/// Two empty blocks end a tar archive.
+const TRAILER: [u8; 1024] = [0; 1024];
+
+/// Files smaller than this many bytes are packed into bundles.
+pub fn get_bundle_threshold() -> i64 {
+    getenv("BUNDLE_THRESHOLD")
+        .unwrap_or_default()
+        .parse::<i64>()
+        .unwrap_or(DEFAULT_BUNDLE_THRESHOLD)
+}
+
+/// How big a bundle grows before the next one is started. Kept well under one part so a
+/// bundle always goes up in a single `PutObject`.
+pub fn get_bundle_size() -> u64 {
+    getenv("BUNDLE_SIZE")
+        .unwrap_or_default()
+        .parse::<u64>()
+        .unwrap_or(DEFAULT_BUNDLE_SIZE)
+        .min(MAX_CHUNK_SIZE / 2)
+}
+
+/// Small files of one Dropbox folder, to be archived together as one tar.
+#[derive(Debug, PartialEq)]
+pub struct Bundle {
+    pub folder: String,
+    pub members: Vec<SmallFile>,
+}
+
+impl Bundle {
+    /// Sits in the folder's own prefix, named after its members so the same files always
+    /// make the same key.
+    pub fn key(&self, source_kind: &str) -> Result<String> {
+        let ids = self
+            .members
+            .iter()
+            .map(|member| member.source_id.as_str())
+            .collect::<Vec<&str>>()
+            .join(",");
+        let name = hex::encode(&checksum::sha256(ids.as_bytes())[..8]);
+        util::standardize_path(
+            source_kind,
+            &format!("{}/deep-freeze-bundle-{name}.tar", self.folder),
+        )
+    }
+}
+
+/// Groups `candidates` by folder and cuts each folder's files into bundles of about
+/// `target` bytes of tar. A folder (or remainder) with a single small file is left to
+/// the workers, since a bundle of one saves nothing.
+pub fn plan_bundles(candidates: Vec<SmallFile>, target: u64) -> Vec<Bundle> {
+    let mut folders: BTreeMap<String, (String, Vec<SmallFile>)> = BTreeMap::new();
+    for candidate in candidates {
+        let folder = match candidate.source_path.rsplit_once('/') {
+            Some((folder, _)) => folder.to_string(),
+            None => String::new(),
+        };
+        folders
+            .entry(folder.to_lowercase())
+            .or_insert_with(|| (folder, Vec::new()))
+            .1
+            .push(candidate);
+    }
+    let mut bundles = Vec::new();
+    for (folder, files) in folders.into_values() {
+        let mut members: Vec<SmallFile> = Vec::new();
+        let mut size: u64 = 0;
+        for file in files {
+            let entry_size =
+                BLOCK_SIZE + file.dropbox_size as u64 + padding(file.dropbox_size as u64);
+            if !members.is_empty() && size + entry_size > target {
+                bundles.push(Bundle {
+                    folder: folder.clone(),
+                    members: std::mem::take(&mut members),
+                });
+                size = 0;
+            }
+            size += entry_size;
+            members.push(file);
+        }
+        bundles.push(Bundle { folder, members });
+    }
+    bundles.retain(|bundle| bundle.members.len() > 1);
+    bundles
+}
+
+/// Packs the small files still to migrate into tar bundles and archives each bundle as
+/// one object, so Deep Archive's per-object overhead and request fees are paid once per
+/// bundle instead of once per file. Every member's offset and length in its bundle is
+/// recorded in `bundle_members`.
+///
+/// A member that fails to download or doesn't match its `content_hash` is left out of
+/// the bundle and recorded like any other failed file. Once `shutdown` is requested no
+/// further bundles are started.
+pub async fn migrate_bundles<S: Source<Error = DeepFreezeError>>(
+    source: &S,
+    aws: &AWSClient,
+    sqlite: &DBConnection,
+    m: &MultiProgress,
+    shutdown: &Shutdown,
+) -> Result<()> {
+    let skip = getenv("SKIP").unwrap_or_default();
+    let dedup = getenv("DEDUP").unwrap_or_default() == "true";
+    let mut candidates: Vec<SmallFile> = Vec::new();
+    for file in db::get_bundle_candidates(sqlite, get_bundle_threshold())? {
+        // Files an archived object already holds are left to the workers to deduplicate.
+        if skip.split(',').any(|id| id == file.source_id)
+            || (dedup && db::find_canonical(sqlite, &file.source_id)?.is_some())
+        {
+            continue;
+        }
+        candidates.push(file);
+    }
+    let bundles = plan_bundles(candidates, get_bundle_size());
+    if bundles.is_empty() {
+        return Ok(());
+    }
+    let files: usize = bundles.iter().map(|bundle| bundle.members.len()).sum();
+    println!(
+        "📦  Packing {files} small files into {} bundles",
+        bundles.len()
+    );
+    for bundle in bundles {
+        if shutdown.requested() {
+            break;
+        }
+        source.prepare().await?;
+        let key = bundle.key(source.kind())?;
+        let migrated = tokio::select! {
+            migrated = migrate_bundle(source, aws, sqlite, (&bundle, &key), (m, shutdown)) => migrated,
+            _ = shutdown.aborted() => {
+                Err(DeepFreezeError::Interrupted("aborted mid-bundle".to_string()))
+            }
+        };
+        let err = match migrated {
+            Ok(()) => continue,
+            Err(err) => err,
+        };
+        println!("🚫  {err}");
+        for member in &bundle.members {
+            db::delete_bundle_member(sqlite, &member.source_id)?;
+            match &err {
+                DeepFreezeError::Interrupted(reason) => {
+                    db::record_interruption(sqlite, &member.source_id, reason)?
+                }
+                err => db::record_failure(sqlite, &member.source_id, &err.to_string())?,
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn migrate_bundle<S: Source<Error = DeepFreezeError>>(
+    source: &S,
+    aws: &AWSClient,
+    sqlite: &DBConnection,
+    (bundle, key): (&Bundle, &str),
+    (m, shutdown): (&MultiProgress, &Shutdown),
+) -> Result<()> {
+    let bucket = getenv("AWS_S3_BUCKET")?;
+    let local_path = localfs::temp_path(key);
+    let total: i64 = bundle
+        .members
+        .iter()
+        .map(|member| member.dropbox_size)
+        .sum();
+    let pb = m.add(progress::new(total as u64, "file_transfer"));
+    pb.set_prefix("📦  Bundle   ");
+    localfs::create_download_folder(&local_path).await?;
+    let mut file = localfs::create_local_file(&local_path).await?;
+    let mut packed: Vec<(String, u64, u64)> = Vec::new();
+    let mut end: u64 = 0;
+    for member in &bundle.members {
+        match append_member(source, sqlite, &mut file, member, end).await {
+            Ok((offset, entry_end)) => {
+                packed.push((member.source_id.clone(), offset, member.dropbox_size as u64));
+                end = entry_end;
+            }
+            Err(err) => {
+                file.flush().await?;
+                file.set_len(end).await?;
+                file.seek(std::io::SeekFrom::Start(end)).await?;
+                match err {
+                    DeepFreezeError::HashMismatch(err) => {
+                        println!("🧨  {err}");
+                        db::set_hash_mismatch(sqlite, &member.source_id)?;
+                    }
+                    err => db::record_failure(sqlite, &member.source_id, &err.to_string())?,
+                }
+            }
+        }
+        pb.inc(member.dropbox_size as u64);
+    }
+    file.write_all(&TRAILER).await?;
+    file.flush().await?;
+    pb.finish();
+    if packed.is_empty() {
+        return Ok(localfs::delete_local_file(&local_path).await?);
+    }
+
+    db::insert_bundle_members(sqlite, key, &packed)?;
+    let tar_size = localfs::get_local_size(&local_path).await?;
+    let (upload_path, metadata) = match crypto::envelope_for(sqlite, (key, key), tar_size as u64)? {
+        Some(envelope) => {
+            let encrypted_path = format!("{local_path}.enc");
+            crypto::encrypt_file(&envelope, &local_path, &encrypted_path).await?;
+            (encrypted_path, envelope.metadata())
+        }
+        None => (local_path.clone(), aws::Metadata::new()),
+    };
+    let s3_hash = aws::upload_to_s3(
+        aws,
+        (sqlite, key),
+        (key, &bucket),
+        &upload_path,
+        &metadata,
+        (m, shutdown),
+    )
+    .await?;
+    let local_size = localfs::get_local_size(&upload_path).await?;
+    let s3_attrs = aws::get_s3_attrs(aws, &bucket, &key.to_string()).await?;
+    let s3_checksum = s3_attrs
+        .checksum()
+        .and_then(|checksum| checksum.checksum_sha256())
+        .unwrap_or_default();
+    if s3_attrs.object_size().unwrap_or_default() != local_size
+        || !checksum::s3_checksums_match(s3_checksum, &s3_hash)
+    {
+        aws::delete_from_s3(aws, &bucket, key).await?;
+        return Err(DeepFreezeError::Integrity(format!(
+            "bundle s3://{bucket}/{key} doesn't match the {} tar that was uploaded",
+            HumanBytes(local_size as u64)
+        )));
+    }
+    db::set_bundle_migrated(sqlite, key, &s3_hash)?;
+    localfs::delete_local_file(&local_path).await?;
+    if upload_path != local_path {
+        localfs::delete_local_file(&upload_path).await?;
+    }
+    println!(
+        "📦  {} files in s3://{bucket}/{key} ({})",
+        packed.len(),
+        HumanBytes(local_size as u64)
+    );
+    Ok(())
+}
+
+/// Streams one file from its source into the tar after its header, hashing it on the
+/// way. Returns the offset of its contents in the tar and where its entry ends.
+async fn append_member<S: Source<Error = DeepFreezeError>>(
+    source: &S,
+    sqlite: &DBConnection,
+    file: &mut File,
+    member: &SmallFile,
+    start: u64,
+) -> Result<(u64, u64)> {
+    let size = member.dropbox_size as u64;
+    let name = member
+        .source_path
+        .rsplit_once('/')
+        .map_or(member.source_path.as_str(), |(_, name)| name);
+    let header = tar_header(name, size, member.source_mtime)?;
+    let mut stream = source.fetch(&member.source_id, 0).await?;
+    file.write_all(&header).await?;
+    let offset = start + header.len() as u64;
+    let mut hasher = DropboxContentHasher::new();
+    let mut written: u64 = 0;
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk?;
+        hasher.update(&chunk);
+        file.write_all(&chunk).await?;
+        written += chunk.len() as u64;
+    }
+    if written != size {
+        return Err(DeepFreezeError::Integrity(format!(
+            "downloaded {written} bytes of {size}"
+        )));
+    }
+    db::check_content_hash(
+        sqlite,
+        &member.source_id,
+        (&member.dropbox_hash, hasher.finalize()),
+    )?;
+    let padding = padding(size);
+    file.write_all(&TRAILER[..padding as usize]).await?;
+    Ok((offset, offset + size + padding))
+}
+
+/// The header block(s) of a regular file entry; names over 100 bytes get a GNU long name
+/// entry in front. The entry keeps the file's `mtime` where the source reported one, and
+/// is stamped with the time of bundling otherwise.
+fn tar_header(name: &str, size: u64, mtime: Option<i64>) -> Result<Vec<u8>> {
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(tar::EntryType::Regular);
+    header.set_size(size);
+    header.set_mode(0o644);
+    header.set_mtime(match mtime {
+        Some(mtime) => mtime.max(0) as u64,
+        None => SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs(),
+    });
+    let mut builder = tar::Builder::new(Vec::new());
+    builder.append_data(&mut header, name, std::io::empty())?;
+    Ok(builder.get_ref().clone())
+}
+
+/// Zeroes that round an entry of `size` bytes up to a whole block.
+fn padding(size: u64) -> u64 {
+    (BLOCK_SIZE - size % BLOCK_SIZE) % BLOCK_SIZE
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::SmallFile;
+
+    fn small_file(source_path: &str, dropbox_size: i64) -> SmallFile {
+        SmallFile {
+            source_id: format!("id:{source_path}"),
+            source_path: source_path.to_string(),
+            dropbox_hash: String::new(),
+            dropbox_size,
+            source_mtime: None,
+        }
+    }
+
+    #[test]
+    fn it_bundles_small_files_by_folder() {
+        let candidates = vec![
+            small_file("/Archive/a/1.txt", 100),
+            small_file("/Archive/a/2.txt", 100),
+            small_file("/Archive/a/3.txt", 100),
+            small_file("/Archive/A/4.txt", 100),
+            small_file("/Archive/b/5.txt", 100),
+        ];
+        let bundles = crate::bundle::plan_bundles(candidates.clone(), 2048);
+        let members = |bundle: &crate::bundle::Bundle| {
+            bundle
+                .members
+                .iter()
+                .map(|member| member.source_path.as_str())
+                .collect::<Vec<&str>>()
+                .join(" ")
+        };
+        assert_eq!(bundles.len(), 2);
+        assert_eq!(bundles[0].folder, "/Archive/a");
+        assert_eq!(members(&bundles[0]), "/Archive/a/1.txt /Archive/a/2.txt");
+        assert_eq!(members(&bundles[1]), "/Archive/a/3.txt /Archive/A/4.txt");
+
+        let bundles = crate::bundle::plan_bundles(candidates, 4096);
+        assert_eq!(bundles.len(), 1);
+        assert_eq!(bundles[0].members.len(), 4);
+    }
+
+    #[test]
+    fn it_writes_entries_tar_can_read() {
+        let long_name = format!("{}.txt", "x".repeat(120));
+        let mut tar: Vec<u8> = Vec::new();
+        let mut offsets = Vec::new();
+        for (name, data, mtime) in [
+            ("a.txt", &b"hello"[..], Some(1_600_000_000)),
+            (long_name.as_str(), &[7; 600][..], None),
+        ] {
+            tar.extend(crate::bundle::tar_header(name, data.len() as u64, mtime).unwrap());
+            offsets.push(tar.len() as u64);
+            tar.extend(data);
+            tar.extend(vec![0; crate::bundle::padding(data.len() as u64) as usize]);
+        }
+        tar.extend(crate::bundle::TRAILER);
+
+        let mut archive = tar::Archive::new(&tar[..]);
+        let entries = archive
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                let entry = entry.unwrap();
+                (
+                    entry.path().unwrap().to_string_lossy().to_string(),
+                    entry.raw_file_position(),
+                    entry.size(),
+                    entry.header().mtime().unwrap(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            entries[0],
+            ("a.txt".to_string(), offsets[0], 5, 1_600_000_000)
+        );
+        assert_eq!(entries[1].0, long_name);
+        assert_eq!((entries[1].1, entries[1].2), (offsets[1], 600));
+        // A file without a modification time is stamped with when it was bundled.
+        assert!(entries[1].3 > 1_600_000_000);
+    }
+}
@@ -0,0 +1,240 @@
+use aws_smithy_types::base64;
+use sha2::{Digest, Sha256};
+use std::{fmt, io::SeekFrom};
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncSeekExt},
+};
+
+pub const DROPBOX_BLOCK_SIZE: usize = 4194304; // 4 MiB in bytes
+
+/// Incrementally computes Dropbox's `content_hash`: the file is split into 4 MiB blocks,
+/// each block is hashed with SHA-256, and the concatenated block digests are hashed again.
+///
+/// See <https://www.dropbox.com/developers/reference/content-hash>
+#[derive(Default)]
+pub struct DropboxContentHasher {
+    overall: Sha256,
+    block: Sha256,
+    block_pos: usize,
+}
+
+impl DropboxContentHasher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            if self.block_pos == DROPBOX_BLOCK_SIZE {
+                self.overall.update(self.block.finalize_reset());
+                self.block_pos = 0;
+            }
+            let take = (DROPBOX_BLOCK_SIZE - self.block_pos).min(data.len());
+            self.block.update(&data[..take]);
+            self.block_pos += take;
+            data = &data[take..];
+        }
+    }
+
+    /// Returns the lowercase hex digest, in the same format Dropbox reports.
+    pub fn finalize(mut self) -> String {
+        if self.block_pos > 0 {
+            self.overall.update(self.block.finalize());
+        }
+        hex::encode(self.overall.finalize())
+    }
+}
+
+pub async fn dropbox_content_hash_of_file(local_path: &str) -> std::io::Result<String> {
+    Ok(dropbox_content_hasher_of_file(local_path).await?.finalize())
+}
+
+/// A hasher that has already seen the contents of `local_path`, for carrying on with the
+/// rest of a partly downloaded file.
+pub async fn dropbox_content_hasher_of_file(
+    local_path: &str,
+) -> std::io::Result<DropboxContentHasher> {
+    let mut file = File::open(local_path).await?;
+    let mut hasher = DropboxContentHasher::new();
+    let mut buf = vec![0; DROPBOX_BLOCK_SIZE];
+    loop {
+        let read = file.read(&mut buf).await?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buf[..read]);
+    }
+    Ok(hasher)
+}
+
+pub fn sha256(data: &[u8]) -> Vec<u8> {
+    Sha256::digest(data).to_vec()
+}
+
+/// SHA-256 of `length` bytes of a local file starting at `offset`, i.e. one multipart chunk.
+pub async fn sha256_of_file_range(
+    local_path: &str,
+    offset: u64,
+    length: u64,
+) -> std::io::Result<Vec<u8>> {
+    let mut file = File::open(local_path).await?;
+    file.seek(SeekFrom::Start(offset)).await?;
+    let mut file = file.take(length);
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; DROPBOX_BLOCK_SIZE];
+    loop {
+        let read = file.read(&mut buf).await?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buf[..read]);
+    }
+    Ok(hasher.finalize().to_vec())
+}
+
+/// Encodes a raw digest the way S3 expects in `x-amz-checksum-sha256`.
+pub fn s3_checksum(digest: &[u8]) -> String {
+    base64::encode(digest)
+}
+
+/// The checksum S3 reports for a multipart object: the SHA-256 of the concatenated part
+/// digests, suffixed with the number of parts.
+pub fn s3_composite_checksum(part_checksums: &[&str]) -> String {
+    let mut hasher = Sha256::new();
+    for part_checksum in part_checksums {
+        hasher.update(base64::decode(part_checksum).unwrap_or_default());
+    }
+    format!(
+        "{}-{}",
+        base64::encode(hasher.finalize()),
+        part_checksums.len()
+    )
+}
+
+/// Compares two S3 checksums, ignoring the `-<parts>` suffix that only some S3 APIs
+/// append to multipart checksums.
+pub fn s3_checksums_match(left: &str, right: &str) -> bool {
+    let strip = |checksum: &str| checksum.split('-').next().unwrap_or_default().to_string();
+    strip(left) == strip(right)
+}
+
+/// The bytes received from Dropbox do not hash to the `content_hash` recorded in the DB.
+#[derive(Debug)]
+pub struct HashMismatch {
+    pub expected: String,
+    pub actual: String,
+}
+
+impl fmt::Display for HashMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Dropbox content_hash {} does not match downloaded {}",
+            self.expected, self.actual
+        )
+    }
+}
+
+impl std::error::Error for HashMismatch {}
+
+pub fn verify_dropbox_hash(expected: &str, actual: String) -> Result<(), HashMismatch> {
+    match expected == actual {
+        true => Ok(()),
+        false => Err(HashMismatch {
+            expected: expected.to_string(),
+            actual,
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::checksum::{
+        s3_checksum, s3_checksums_match, s3_composite_checksum, sha256, DropboxContentHasher,
+        DROPBOX_BLOCK_SIZE,
+    };
+
+    #[test]
+    fn it_hashes_an_empty_file() {
+        assert_eq!(
+            DropboxContentHasher::new().finalize(),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+    }
+
+    #[test]
+    fn it_hashes_a_single_block_twice() {
+        let mut hasher = DropboxContentHasher::new();
+        hasher.update(b"hello");
+        // sha256(sha256("hello"))
+        assert_eq!(
+            hasher.finalize(),
+            "9595c9df90075148eb06860365df33584b75bff782a510c6cd4883a419833d50"
+        );
+    }
+
+    #[test]
+    fn it_hashes_the_same_regardless_of_chunking() {
+        let data: Vec<u8> = (0..(DROPBOX_BLOCK_SIZE * 2 + 1234))
+            .map(|i| (i % 251) as u8)
+            .collect();
+        let mut whole = DropboxContentHasher::new();
+        whole.update(&data);
+        let mut chunked = DropboxContentHasher::new();
+        for chunk in data.chunks(65536 + 17) {
+            chunked.update(chunk);
+        }
+        assert_eq!(whole.finalize(), chunked.finalize());
+    }
+
+    #[tokio::test]
+    async fn it_hashes_a_local_file() {
+        let local_path = std::env::temp_dir().join("deep-freeze-checksum-file.txt");
+        let data: Vec<u8> = (0..5_000_000u32).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&local_path, &data).unwrap();
+        let local_path = local_path.to_str().unwrap();
+        let mut hasher = DropboxContentHasher::new();
+        hasher.update(&data);
+        assert_eq!(
+            crate::checksum::dropbox_content_hash_of_file(local_path)
+                .await
+                .unwrap(),
+            hasher.finalize()
+        );
+        std::fs::remove_file(local_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn it_hashes_a_file_range() {
+        let local_path = std::env::temp_dir().join("deep-freeze-checksum-range.txt");
+        let data: Vec<u8> = (0..1024u32).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&local_path, &data).unwrap();
+        let local_path = local_path.to_str().unwrap();
+        assert_eq!(
+            crate::checksum::sha256_of_file_range(local_path, 100, 200)
+                .await
+                .unwrap(),
+            sha256(&data[100..300])
+        );
+        std::fs::remove_file(local_path).unwrap();
+    }
+
+    #[test]
+    fn it_builds_a_composite_s3_checksum() {
+        let first = s3_checksum(&sha256(b"first part"));
+        let second = s3_checksum(&sha256(b"second part"));
+        let mut concatenated = sha256(b"first part");
+        concatenated.extend(sha256(b"second part"));
+        let composite = s3_composite_checksum(&[&first, &second]);
+        assert_eq!(
+            composite,
+            format!("{}-2", s3_checksum(&sha256(&concatenated)))
+        );
+        assert!(s3_checksums_match(
+            &composite,
+            &s3_checksum(&sha256(&concatenated))
+        ));
+        assert!(!s3_checksums_match(&composite, &first));
+    }
+}
@@ -0,0 +1,153 @@
+use crate::aws::Metadata;
+use crate::error::Result;
+use crate::localfs;
+use crate::util::getenv;
+
+use std::ffi::OsStr;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+/// What `paths.compression` and the object metadata call the format.
+pub const COMPRESSION: &str = "zstd";
+/// Text-like files, which compress well. Photos, video, audio and archives are already
+/// compressed and are left alone unless listed.
+pub const DEFAULT_COMPRESS_TYPES: &str =
+    "text/*,application/json,application/xml,application/sql,application/x-yaml,log,tsv";
+/// zstd's own default: most of the ratio of the higher levels for a fraction of the CPU.
+const LEVEL: i32 = zstd::DEFAULT_COMPRESSION_LEVEL;
+
+/// Whether `COMPRESS` is on and `source_path` is one of the `COMPRESS_TYPES`.
+pub fn should_compress(source_path: &str) -> bool {
+    if getenv("COMPRESS").unwrap_or_default() != "true" {
+        return false;
+    }
+    let types = getenv("COMPRESS_TYPES").unwrap_or_else(|_| DEFAULT_COMPRESS_TYPES.to_string());
+    matches_types(source_path, &types)
+}
+
+/// `types` is a comma separated allow-list of extensions (`log`), MIME types
+/// (`application/json`) and MIME type families (`text/*`), the MIME type of a path being
+/// guessed from its extension.
+fn matches_types(path: &str, types: &str) -> bool {
+    let Some(extension) = Path::new(path)
+        .extension()
+        .and_then(OsStr::to_str)
+        .map(str::to_lowercase)
+    else {
+        return false;
+    };
+    let mime = mime_guess::from_ext(&extension).first();
+    types
+        .split(',')
+        .map(|allowed| allowed.trim().to_lowercase())
+        .filter(|allowed| !allowed.is_empty())
+        .any(|allowed| match (allowed.split_once('/'), &mime) {
+            (Some((family, "*")), Some(mime)) => mime.type_() == family,
+            (Some(_), Some(mime)) => mime.essence_str() == allowed,
+            (Some(_), None) => false,
+            (None, _) => allowed.trim_start_matches('.') == extension,
+        })
+}
+
+/// Compresses `source` into `destination`, returning the compressed size. When that
+/// wouldn't be any smaller, `destination` is removed again and `None` returned, so the
+/// file is archived as it is.
+pub async fn compress_file(source: &str, destination: &str) -> Result<Option<u64>> {
+    let (source_path, destination_path) = (source.to_string(), destination.to_string());
+    let (original, compressed) = tokio::task::spawn_blocking(move || -> Result<(u64, u64)> {
+        let reader = std::fs::File::open(&source_path)?;
+        let original = reader.metadata()?.len();
+        let mut writer = BufWriter::new(std::fs::File::create(&destination_path)?);
+        zstd::stream::copy_encode(reader, &mut writer, LEVEL)?;
+        writer.flush()?;
+        Ok((original, std::fs::metadata(&destination_path)?.len()))
+    })
+    .await??;
+    if compressed >= original {
+        localfs::delete_local_file(destination).await?;
+        return Ok(None);
+    }
+    Ok(Some(compressed))
+}
+
+/// Decompresses a file compressed by `compress_file`.
+pub async fn decompress_file(source: &str, destination: &str) -> Result<()> {
+    let (source, destination) = (source.to_string(), destination.to_string());
+    tokio::task::spawn_blocking(move || -> Result<()> {
+        let reader = std::fs::File::open(&source)?;
+        let mut writer = BufWriter::new(std::fs::File::create(&destination)?);
+        zstd::stream::copy_decode(reader, &mut writer)?;
+        writer.flush()?;
+        Ok(())
+    })
+    .await?
+}
+
+/// Whether an object's metadata says it was compressed by `compress_file`.
+pub fn is_compressed(metadata: &Metadata) -> bool {
+    metadata
+        .get("deep-freeze-compression")
+        .is_some_and(|compression| compression == COMPRESSION)
+}
+
+/// Object metadata for a compressed object, so it can be restored without the database.
+pub fn metadata(original_size: u64) -> Metadata {
+    Metadata::from([
+        (
+            "deep-freeze-compression".to_string(),
+            COMPRESSION.to_string(),
+        ),
+        (
+            "deep-freeze-original-size".to_string(),
+            original_size.to_string(),
+        ),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn it_picks_files_by_extension_or_mime_type() {
+        let types = "text/*, application/json, .log";
+        assert!(crate::compress::matches_types("/Reports/2020.CSV", types));
+        assert!(crate::compress::matches_types("/data/dump.json", types));
+        assert!(crate::compress::matches_types("/var/app.log", types));
+        assert!(!crate::compress::matches_types("/Photos/a.jpg", types));
+        assert!(!crate::compress::matches_types("/archive.zip", types));
+        assert!(!crate::compress::matches_types("/README", types));
+        assert!(crate::compress::matches_types(
+            "/notes.md",
+            crate::compress::DEFAULT_COMPRESS_TYPES
+        ));
+    }
+
+    #[tokio::test]
+    async fn it_only_keeps_smaller_files() {
+        let source = "./test/compress-source.csv";
+        let destination = "./test/compress-source.csv.zst";
+        let text = "date,amount\n2020-01-01,1\n".repeat(1000);
+        tokio::fs::write(source, &text).await.unwrap();
+        let compressed = crate::compress::compress_file(source, destination)
+            .await
+            .unwrap()
+            .unwrap();
+        let bytes = tokio::fs::read(destination).await.unwrap();
+        assert_eq!(bytes.len() as u64, compressed);
+        assert_eq!(zstd::decode_all(bytes.as_slice()).unwrap(), text.as_bytes());
+        crate::compress::decompress_file(destination, source)
+            .await
+            .unwrap();
+        assert_eq!(tokio::fs::read_to_string(source).await.unwrap(), text);
+        tokio::fs::remove_file(destination).await.unwrap();
+
+        tokio::fs::write(source, [7u8]).await.unwrap();
+        assert_eq!(
+            crate::compress::compress_file(source, destination)
+                .await
+                .unwrap(),
+            None
+        );
+        assert!(!std::path::Path::new(destination).exists());
+        tokio::fs::remove_file(source).await.unwrap();
+    }
+}
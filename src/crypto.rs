@@ -0,0 +1,510 @@
+use crate::aws::Metadata;
+use crate::db::{self, DBConnection, EncryptedObject};
+use crate::error::{DeepFreezeError, Result};
+use crate::util::{getenv, setenv};
+
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng};
+use aes_gcm::{Aes256Gcm, Nonce};
+use argon2::Argon2;
+use futures::{Stream, StreamExt};
+use hyper::body::Bytes;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use std::pin::Pin;
+use std::sync::OnceLock;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufWriter};
+
+/// AES-256-GCM over fixed-size plaintext segments, each sealed on its own with a nonce of
+/// the object's 7-byte prefix, the 4-byte big-endian segment index and a 1-byte flag set
+/// only on the last segment (the STREAM construction). Segments can't be reordered,
+/// dropped or cut off without failing authentication, and any range of them can be
+/// decrypted without the rest, so multipart parts stay independent.
+pub const SCHEME: &str = "aes-256-gcm-stream-64k";
+pub const SEGMENT_SIZE: u64 = 64 * 1024;
+const TAG_SIZE: u64 = 16;
+const NONCE_PREFIX_SIZE: usize = 7;
+const KEY_SIZE: usize = 32;
+const SALT_SIZE: usize = 16;
+
+static MASTER_KEY: OnceLock<MasterKey> = OnceLock::new();
+
+/// The locally held key that wraps every object's data key. It never leaves this machine:
+/// S3 and the database only see data keys wrapped by it, and its `id`.
+pub struct MasterKey {
+    /// `key-file:` and a fingerprint of the key, or `passphrase:` and the hex Argon2 salt,
+    /// which is all it takes to derive the key again from the passphrase.
+    pub id: String,
+    cipher: Aes256Gcm,
+}
+
+impl MasterKey {
+    /// A key file holds the 32-byte key itself, raw or as 64 hex digits.
+    pub fn from_key_file(contents: &[u8]) -> Result<MasterKey> {
+        let key = match contents.len() {
+            KEY_SIZE => contents.to_vec(),
+            _ => hex::decode(String::from_utf8_lossy(contents).trim())
+                .ok()
+                .filter(|key| key.len() == KEY_SIZE)
+                .ok_or_else(|| {
+                    DeepFreezeError::Encryption(
+                        "the key file must hold 32 bytes, raw or hex encoded".to_string(),
+                    )
+                })?,
+        };
+        let fingerprint = hex::encode(&Sha256::digest(&key)[..8]);
+        Ok(MasterKey {
+            id: format!("key-file:{fingerprint}"),
+            cipher: Aes256Gcm::new_from_slice(&key)?,
+        })
+    }
+
+    pub fn from_passphrase(passphrase: &str, salt: &[u8]) -> Result<MasterKey> {
+        let mut key = [0u8; KEY_SIZE];
+        Argon2::default().hash_password_into(passphrase.as_bytes(), salt, &mut key)?;
+        Ok(MasterKey {
+            id: format!("passphrase:{}", hex::encode(salt)),
+            cipher: Aes256Gcm::new_from_slice(&key)?,
+        })
+    }
+
+    /// Seals `data_key` under this key, as the hex of a random nonce and the ciphertext.
+    fn wrap(&self, data_key: &[u8]) -> Result<String> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let wrapped = self.cipher.encrypt(&nonce, data_key)?;
+        Ok(hex::encode([nonce.as_slice(), &wrapped].concat()))
+    }
+
+    fn unwrap(&self, wrapped_key: &str) -> Result<Aes256Gcm> {
+        let wrapped = hex::decode(wrapped_key)?;
+        if wrapped.len() < 12 {
+            return Err(DeepFreezeError::Encryption(
+                "wrapped key is too short".to_string(),
+            ));
+        }
+        let (nonce, wrapped) = wrapped.split_at(12);
+        let data_key = self.cipher.decrypt(Nonce::from_slice(nonce), wrapped)?;
+        Ok(Aes256Gcm::new_from_slice(&data_key)?)
+    }
+}
+
+/// Loads the key `ENCRYPTION` asks for, once, before anything is uploaded. A passphrase
+/// is read from `ENCRYPTION_PASSPHRASE` or asked for, and never written to the .env file;
+/// only its salt is.
+pub async fn init() -> Result<()> {
+    let master = match getenv("ENCRYPTION").unwrap_or_default().as_str() {
+        "key-file" => {
+            let path = getenv("ENCRYPTION_KEY_FILE")?;
+            MasterKey::from_key_file(&tokio::fs::read(&path).await?)?
+        }
+        "passphrase" => {
+            let (salt, new) = match getenv("ENCRYPTION_SALT") {
+                Ok(salt) => (hex::decode(salt)?, false),
+                Err(_) => {
+                    let mut salt = vec![0u8; SALT_SIZE];
+                    OsRng.fill_bytes(&mut salt);
+                    setenv("ENCRYPTION_SALT", hex::encode(&salt)).await?;
+                    (salt, true)
+                }
+            };
+            let passphrase = match getenv("ENCRYPTION_PASSPHRASE") {
+                Ok(passphrase) => passphrase,
+                // A new passphrase is typed twice, since a typo would lock the archive.
+                Err(_) => match new {
+                    true => inquire::Password::new("🔐  Encryption passphrase").prompt()?,
+                    false => inquire::Password::new("🔐  Encryption passphrase")
+                        .without_confirmation()
+                        .prompt()?,
+                },
+            };
+            MasterKey::from_passphrase(&passphrase, &salt)?
+        }
+        _ => return Ok(()),
+    };
+    println!("🔐 Encrypting uploads with {}", master.id);
+    MASTER_KEY.set(master).ok();
+    Ok(())
+}
+
+pub fn master_key() -> Option<&'static MasterKey> {
+    MASTER_KEY.get()
+}
+
+/// How one object is encrypted: a random data key, kept only wrapped by the master key,
+/// and the nonce prefix of its segments.
+pub struct Envelope {
+    pub key_id: String,
+    pub wrapped_key: String,
+    pub nonce_prefix: [u8; NONCE_PREFIX_SIZE],
+    pub plaintext_size: u64,
+    cipher: Aes256Gcm,
+}
+
+impl Envelope {
+    pub fn new(master: &MasterKey, plaintext_size: u64) -> Result<Envelope> {
+        let data_key = Aes256Gcm::generate_key(OsRng);
+        let mut nonce_prefix = [0u8; NONCE_PREFIX_SIZE];
+        OsRng.fill_bytes(&mut nonce_prefix);
+        Ok(Envelope {
+            key_id: master.id.clone(),
+            wrapped_key: master.wrap(&data_key)?,
+            nonce_prefix,
+            plaintext_size,
+            cipher: Aes256Gcm::new(&data_key),
+        })
+    }
+
+    /// The envelope `record` describes, with its data key unwrapped by `master`.
+    pub fn open(master: &MasterKey, record: &EncryptedObject) -> Result<Envelope> {
+        if record.scheme != SCHEME || record.key_id != master.id {
+            return Err(DeepFreezeError::Encryption(format!(
+                "{} was encrypted as {} with {}, not {SCHEME} with {}",
+                record.s3_key, record.scheme, record.key_id, master.id
+            )));
+        }
+        let nonce_prefix = hex::decode(&record.nonce_prefix)?
+            .try_into()
+            .map_err(|_| DeepFreezeError::Encryption("bad nonce prefix".to_string()))?;
+        Ok(Envelope {
+            key_id: record.key_id.clone(),
+            wrapped_key: record.wrapped_key.clone(),
+            nonce_prefix,
+            plaintext_size: record.plaintext_size as u64,
+            cipher: master.unwrap(&record.wrapped_key)?,
+        })
+    }
+
+    pub fn record(&self, s3_key: &str) -> EncryptedObject {
+        EncryptedObject {
+            s3_key: s3_key.to_string(),
+            key_id: self.key_id.clone(),
+            scheme: SCHEME.to_string(),
+            wrapped_key: self.wrapped_key.clone(),
+            nonce_prefix: hex::encode(self.nonce_prefix),
+            plaintext_size: self.plaintext_size as i64,
+        }
+    }
+
+    /// The same description as `record`, as S3 user metadata, so the object can be
+    /// decrypted with the master key alone.
+    pub fn metadata(&self) -> Metadata {
+        Metadata::from([
+            ("deep-freeze-encryption".to_string(), SCHEME.to_string()),
+            ("deep-freeze-key-id".to_string(), self.key_id.clone()),
+            (
+                "deep-freeze-wrapped-key".to_string(),
+                self.wrapped_key.clone(),
+            ),
+            (
+                "deep-freeze-nonce-prefix".to_string(),
+                hex::encode(self.nonce_prefix),
+            ),
+            (
+                "deep-freeze-plaintext-size".to_string(),
+                self.plaintext_size.to_string(),
+            ),
+        ])
+    }
+
+    pub fn ciphertext_size(&self) -> u64 {
+        ciphertext_size(self.plaintext_size)
+    }
+
+    pub fn encrypt_segment(&self, index: u64, plaintext: &[u8]) -> Result<Vec<u8>> {
+        Ok(self.cipher.encrypt(&self.nonce(index), plaintext)?)
+    }
+
+    pub fn decrypt_segment(&self, index: u64, ciphertext: &[u8]) -> Result<Vec<u8>> {
+        Ok(self.cipher.decrypt(&self.nonce(index), ciphertext)?)
+    }
+
+    fn nonce(&self, index: u64) -> Nonce<<Aes256Gcm as AeadCore>::NonceSize> {
+        let last = index + 1 == segments(self.plaintext_size);
+        let mut nonce = [0u8; 12];
+        nonce[..NONCE_PREFIX_SIZE].copy_from_slice(&self.nonce_prefix);
+        nonce[NONCE_PREFIX_SIZE..11].copy_from_slice(&(index as u32).to_be_bytes());
+        nonce[11] = last as u8;
+        nonce.into()
+    }
+}
+
+/// Segments in an object of `plaintext_size` bytes; even an empty one has one.
+pub fn segments(plaintext_size: u64) -> u64 {
+    plaintext_size.div_ceil(SEGMENT_SIZE).max(1)
+}
+
+pub fn ciphertext_size(plaintext_size: u64) -> u64 {
+    plaintext_size + segments(plaintext_size) * TAG_SIZE
+}
+
+/// The envelope to upload `plaintext_size` bytes to `key` with, or `None` when encryption
+/// is off. A multipart upload being resumed reuses the envelope its parts were sealed with,
+/// so the ciphertext comes out the same; anything else gets a new data key and nonce
+/// prefix, as reusing them for other plaintext would break GCM.
+pub fn envelope_for(
+    sqlite: &DBConnection,
+    (source_id, key): (&str, &str),
+    plaintext_size: u64,
+) -> Result<Option<Envelope>> {
+    let Some(master) = master_key() else {
+        db::delete_encrypted_object(sqlite, key)?;
+        return Ok(None);
+    };
+    let resuming = db::get_multipart_upload(sqlite, source_id)?
+        .is_some_and(|(upload_key, _)| upload_key == key);
+    if let Some(record) = db::get_encrypted_object(sqlite, key)? {
+        if resuming && record.plaintext_size == plaintext_size as i64 {
+            if let Ok(envelope) = Envelope::open(master, &record) {
+                return Ok(Some(envelope));
+            }
+        }
+    }
+    let envelope = Envelope::new(master, plaintext_size)?;
+    db::insert_encrypted_object(sqlite, &envelope.record(key))?;
+    Ok(Some(envelope))
+}
+
+/// The size S3 should report for `plaintext_size` bytes archived at `key`.
+pub fn stored_size(sqlite: &DBConnection, key: &str, plaintext_size: i64) -> Result<i64> {
+    match db::get_encrypted_object(sqlite, key)? {
+        Some(_) => Ok(ciphertext_size(plaintext_size as u64) as i64),
+        None => Ok(plaintext_size),
+    }
+}
+
+/// Encrypts the file at `source` into `destination`, segment by segment.
+pub async fn encrypt_file(envelope: &Envelope, source: &str, destination: &str) -> Result<()> {
+    let mut reader = tokio::fs::File::open(source).await?;
+    let mut writer = BufWriter::new(tokio::fs::File::create(destination).await?);
+    let mut segment = Vec::with_capacity(SEGMENT_SIZE as usize);
+    for index in 0..segments(envelope.plaintext_size) {
+        segment.clear();
+        (&mut reader)
+            .take(SEGMENT_SIZE)
+            .read_to_end(&mut segment)
+            .await?;
+        writer
+            .write_all(&envelope.encrypt_segment(index, &segment)?)
+            .await?;
+    }
+    writer.flush().await?;
+    Ok(())
+}
+
+/// The envelope an object was encrypted with, from the metadata `Envelope::metadata`
+/// stored with it, or `None` for an object that isn't encrypted.
+pub fn envelope_from_metadata(key: &str, metadata: &Metadata) -> Result<Option<Envelope>> {
+    let Some(scheme) = metadata.get("deep-freeze-encryption") else {
+        return Ok(None);
+    };
+    let field = |name: &str| {
+        metadata
+            .get(&format!("deep-freeze-{name}"))
+            .cloned()
+            .ok_or_else(|| DeepFreezeError::Encryption(format!("{key} has no {name} metadata")))
+    };
+    let record = EncryptedObject {
+        s3_key: key.to_string(),
+        key_id: field("key-id")?,
+        scheme: scheme.clone(),
+        wrapped_key: field("wrapped-key")?,
+        nonce_prefix: field("nonce-prefix")?,
+        plaintext_size: field("plaintext-size")?
+            .parse()
+            .map_err(|_| DeepFreezeError::Encryption(format!("{key} has a bad plaintext-size")))?,
+    };
+    let master = master_key().ok_or_else(|| {
+        DeepFreezeError::Encryption(format!(
+            "{key} is encrypted with {}, run with --encryption",
+            record.key_id
+        ))
+    })?;
+    Envelope::open(master, &record).map(Some)
+}
+
+/// Decrypts the file at `source`, encrypted by `encrypt_file` or `encrypt_stream`, into
+/// `destination`. Fails on any segment that was changed, moved or cut off.
+pub async fn decrypt_file(envelope: &Envelope, source: &str, destination: &str) -> Result<()> {
+    let mut reader = tokio::fs::File::open(source).await?;
+    let mut writer = BufWriter::new(tokio::fs::File::create(destination).await?);
+    let mut segment = Vec::with_capacity((SEGMENT_SIZE + TAG_SIZE) as usize);
+    for index in 0..segments(envelope.plaintext_size) {
+        segment.clear();
+        (&mut reader)
+            .take(SEGMENT_SIZE + TAG_SIZE)
+            .read_to_end(&mut segment)
+            .await?;
+        writer
+            .write_all(&envelope.decrypt_segment(index, &segment)?)
+            .await?;
+    }
+    if reader.read(&mut [0u8; 1]).await? > 0 {
+        return Err(DeepFreezeError::Encryption(format!(
+            "{source} is longer than {} encrypted bytes",
+            envelope.ciphertext_size()
+        )));
+    }
+    writer.flush().await?;
+    Ok(())
+}
+
+/// Encrypts a plaintext stream of `envelope.plaintext_size` bytes into a stream of whole
+/// ciphertext segments, for `aws::stream_upload`.
+pub fn encrypt_stream<S, E>(envelope: Envelope, stream: S) -> EncryptStream<S>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+    DeepFreezeError: From<E>,
+{
+    EncryptStream {
+        inner: stream,
+        envelope,
+        buffer: Vec::with_capacity(SEGMENT_SIZE as usize),
+        index: 0,
+        finished: false,
+    }
+}
+
+pub struct EncryptStream<S> {
+    inner: S,
+    envelope: Envelope,
+    buffer: Vec<u8>,
+    index: u64,
+    finished: bool,
+}
+
+impl<S, E> Stream for EncryptStream<S>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+    DeepFreezeError: From<E>,
+{
+    type Item = Result<Bytes>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        let segment_size = SEGMENT_SIZE as usize;
+        loop {
+            if this.finished {
+                return Poll::Ready(None);
+            }
+            let last = this.index + 1 == segments(this.envelope.plaintext_size);
+            // The last segment is only sealed once the stream ends, so its flag can't be
+            // set on a segment that turns out not to be last.
+            if !last && this.buffer.len() >= segment_size {
+                let rest = this.buffer.split_off(segment_size);
+                let segment = std::mem::replace(&mut this.buffer, rest);
+                let sealed = this.envelope.encrypt_segment(this.index, &segment);
+                this.index += 1;
+                return Poll::Ready(Some(sealed.map(Bytes::from)));
+            }
+            match ready!(this.inner.poll_next_unpin(cx)) {
+                Some(Ok(chunk)) => this.buffer.extend_from_slice(&chunk),
+                Some(Err(err)) => {
+                    this.finished = true;
+                    return Poll::Ready(Some(Err(err.into())));
+                }
+                None => {
+                    this.finished = true;
+                    let received = this.index * SEGMENT_SIZE + this.buffer.len() as u64;
+                    if !last || received != this.envelope.plaintext_size {
+                        return Poll::Ready(Some(Err(DeepFreezeError::Integrity(format!(
+                            "streamed {received} bytes to encrypt, expected {}",
+                            this.envelope.plaintext_size
+                        )))));
+                    }
+                    let sealed = this.envelope.encrypt_segment(this.index, &this.buffer);
+                    return Poll::Ready(Some(sealed.map(Bytes::from)));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::crypto::{Envelope, MasterKey, SEGMENT_SIZE};
+    use futures::StreamExt;
+
+    fn decrypt(envelope: &Envelope, ciphertext: &[u8]) -> Vec<u8> {
+        ciphertext
+            .chunks(SEGMENT_SIZE as usize + 16)
+            .enumerate()
+            .flat_map(|(index, segment)| envelope.decrypt_segment(index as u64, segment).unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn it_encrypts_files_and_streams_alike() {
+        let master = MasterKey::from_key_file(&[7u8; 32]).unwrap();
+        let plaintext = (0..2 * SEGMENT_SIZE + 100)
+            .map(|byte| byte as u8)
+            .collect::<Vec<u8>>();
+        let envelope = Envelope::new(&master, plaintext.len() as u64).unwrap();
+        assert_eq!(envelope.ciphertext_size(), plaintext.len() as u64 + 3 * 16);
+
+        let source = "./test/crypto-plaintext.bin";
+        let destination = "./test/crypto-plaintext.bin.enc";
+        tokio::fs::write(source, &plaintext).await.unwrap();
+        crate::crypto::encrypt_file(&envelope, source, destination)
+            .await
+            .unwrap();
+        let from_file = tokio::fs::read(destination).await.unwrap();
+
+        let chunks = plaintext
+            .chunks(1000)
+            .map(|chunk| Ok::<_, std::io::Error>(hyper::body::Bytes::copy_from_slice(chunk)))
+            .collect::<Vec<_>>();
+        let record = envelope.record("a.bin");
+        let reopened = Envelope::open(&master, &record).unwrap();
+        let from_stream = crate::crypto::encrypt_stream(reopened, futures::stream::iter(chunks))
+            .map(|segment| segment.unwrap().to_vec())
+            .concat()
+            .await;
+        assert_eq!(from_file, from_stream);
+        assert_eq!(from_file.len() as u64, envelope.ciphertext_size());
+        assert_eq!(decrypt(&envelope, &from_file), plaintext);
+
+        tokio::fs::write(destination, &from_file).await.unwrap();
+        crate::crypto::decrypt_file(&envelope, destination, source)
+            .await
+            .unwrap();
+        assert_eq!(tokio::fs::read(source).await.unwrap(), plaintext);
+        tokio::fs::write(destination, &from_file[..from_file.len() - 1])
+            .await
+            .unwrap();
+        assert!(crate::crypto::decrypt_file(&envelope, destination, source)
+            .await
+            .is_err());
+        tokio::fs::remove_file(source).await.unwrap();
+        tokio::fs::remove_file(destination).await.unwrap();
+    }
+
+    #[test]
+    fn it_decrypts_segments_independently_and_in_place_only() {
+        let master = MasterKey::from_passphrase("correct horse", b"sixteen byte salt").unwrap();
+        let envelope = Envelope::new(&master, 3 * SEGMENT_SIZE).unwrap();
+        let segment = vec![1u8; SEGMENT_SIZE as usize];
+        let second = envelope.encrypt_segment(1, &segment).unwrap();
+        assert_eq!(envelope.decrypt_segment(1, &second).unwrap(), segment);
+        assert!(envelope.decrypt_segment(0, &second).is_err());
+        // The last segment is sealed differently, so an object can't be cut short.
+        assert!(envelope.decrypt_segment(2, &second).is_err());
+    }
+
+    #[test]
+    fn it_needs_the_same_master_key_to_unwrap() {
+        let master = MasterKey::from_passphrase("correct horse", b"sixteen byte salt").unwrap();
+        assert_eq!(
+            master.id,
+            format!("passphrase:{}", hex::encode("sixteen byte salt"))
+        );
+        let record = Envelope::new(&master, 0).unwrap().record("a.bin");
+        assert!(Envelope::open(&master, &record).is_ok());
+        let other = MasterKey::from_passphrase("battery staple", b"sixteen byte salt").unwrap();
+        assert!(Envelope::open(&other, &record).is_err());
+        let key_file = MasterKey::from_key_file(hex::encode([7u8; 32]).as_bytes()).unwrap();
+        assert_eq!(
+            key_file.id,
+            MasterKey::from_key_file(&[7u8; 32]).unwrap().id
+        );
+    }
+}
@@ -1,8 +1,10 @@
-use crate::{json, localfs, util};
+use crate::error::{DeepFreezeError, Result};
+use crate::{checksum, json, localfs, util};
+use deep_freeze::source::SourceEntry;
 
 use indicatif::HumanBytes;
-use sedregex::find_and_replace;
-use sqlite::{self, Connection, ConnectionWithFullMutex};
+use sqlite::{self, Connection, ConnectionWithFullMutex, State, Value};
+use std::collections::HashSet;
 
 use json::JSON;
 use util::{getenv, setenv};
@@ -10,22 +12,55 @@ use util::{getenv, setenv};
 pub type DBConnection = ConnectionWithFullMutex;
 pub type DBRow = sqlite::Row;
 
-pub fn connect(dbpath: &str) -> DBConnection {
-    init(Connection::open_with_full_mutex(dbpath).unwrap())
+pub fn connect(dbpath: &str) -> Result<DBConnection> {
+    init(Connection::open_with_full_mutex(dbpath)?)
 }
 
-pub async fn reset(dbpath: &str) {
-    localfs::delete_local_file(dbpath).await;
-    connect(dbpath);
+pub async fn reset(dbpath: &str) -> Result<()> {
+    localfs::delete_local_file(dbpath).await?;
+    connect(dbpath)?;
+    Ok(())
 }
 
-pub fn report_status(sqlite: &DBConnection) {
-    let total_rows = count_rows(&sqlite);
-    let total_size = get_total_size(&sqlite);
-    let migrated_rows = count_migrated(&sqlite);
-    let migrated_size = get_migrated_size(&sqlite);
-    let unmigrated_size = get_unmigrated_size(&sqlite);
-    let unmigrated_rows = total_rows - migrated_rows;
+/// Runs one statement with `params` bound to its `?` placeholders, in order. Values are
+/// never spliced into the SQL, so paths and tokens are stored exactly as given.
+fn execute(connection: &DBConnection, query: &str, params: &[Value]) -> Result<()> {
+    let mut statement = connection.prepare(query)?;
+    statement.bind(params)?;
+    while statement.next()? == State::Row {}
+    Ok(())
+}
+
+/// Runs `f` inside a single transaction, rolling everything back if it fails.
+fn transaction<T>(connection: &DBConnection, f: impl FnOnce() -> Result<T>) -> Result<T> {
+    connection.execute("BEGIN;")?;
+    match f() {
+        Ok(value) => {
+            connection.execute("COMMIT;")?;
+            Ok(value)
+        }
+        Err(err) => {
+            connection.execute("ROLLBACK;").ok();
+            Err(err)
+        }
+    }
+}
+
+/// Reads the single integer a `COUNT(*)` or `SUM(...)` query returns, treating NULL as 0.
+fn read_i64(connection: &DBConnection, query: &str, params: &[Value]) -> Result<i64> {
+    match connection.prepare(query)?.into_iter().bind(params)?.next() {
+        Some(row) => Ok(row?.try_read::<Option<i64>, _>(0)?.unwrap_or_default()),
+        None => Ok(0),
+    }
+}
+
+pub fn report_status(sqlite: &DBConnection) -> Result<()> {
+    let total_rows = count_rows(sqlite)?;
+    let total_size = get_total_size(sqlite)?;
+    let migrated_rows = count_migrated(sqlite)?;
+    let migrated_size = get_migrated_size(sqlite)?;
+    let unmigrated_size = get_unmigrated_size(sqlite)?;
+    let unmigrated_rows = count_unmigrated(sqlite)?;
 
     let percent = if migrated_rows > 0 {
         (100 * migrated_size / total_size).abs()
@@ -50,123 +85,315 @@ pub fn report_status(sqlite: &DBConnection) {
         HumanBytes(unmigrated_size as u64)
     );
 
+    let mismatched_rows = count_hash_mismatches(sqlite)?;
+    if mismatched_rows > 0 {
+        println!("🧨  Hash mismatch: {mismatched_rows} files");
+    }
+
+    let deleted_rows = count_deleted(sqlite)?;
+    if deleted_rows > 0 {
+        println!("🪦  Deleted in Dropbox: {deleted_rows} files");
+    }
+
+    let failed_rows = count_failures(sqlite)?;
+    if failed_rows > 0 {
+        println!("💥  Failed: {failed_rows} files");
+    }
+
+    let (bundled_rows, bundles) = count_bundled(sqlite)?;
+    if bundled_rows > 0 {
+        println!("📦  Bundled: {bundled_rows} files in {bundles} tar bundles");
+    }
+
+    let (deduplicated_rows, dedup_saved) = count_deduplicated(sqlite)?;
+    if deduplicated_rows > 0 {
+        println!(
+            "🪞  Deduplicated: {deduplicated_rows} files, saving {}",
+            HumanBytes(dedup_saved as u64)
+        );
+    }
+
+    let (compressed_rows, compression_saved) = count_compressed(sqlite)?;
+    if compressed_rows > 0 {
+        println!(
+            "🗜️   Compressed: {compressed_rows} files, saving {}",
+            HumanBytes(compression_saved as u64)
+        );
+    }
+
+    let interrupted_rows = count_interruptions(sqlite)?;
+    if interrupted_rows > 0 {
+        println!("⏸️   Interrupted: {interrupted_rows} files");
+    }
+
     match percent {
         0 => println!("🤷 {percent}% done"),
         100 => println!("🎉 All files migrated"),
         _ => print!("🎉  {percent}% done!\n\n"),
     }
+    Ok(())
 }
 
-pub fn init(connection: DBConnection) -> DBConnection {
-    match connection.execute(
-        "
-            CREATE TABLE IF NOT EXISTS paths (
-                dropbox_id TEXT PRIMARY KEY,
-                dropbox_path TEXT NOT NULL,
-                dropbox_size INTEGER NOT NULL,
-                dropbox_hash TEXT NOT NULL,
-                migrated INTEGER NOT NULL DEFAULT -1,
-                local_path TEXT UNIQUE DEFAULT NULL,
-                local_size INTEGER DEFAULT NULL,
-                s3_key TEXT UNIQUE DEFAULT NULL,
-                s3_size INTEGER DEFAULT NULL,
-                s3_hash TEXT UNIQUE DEFAULT NULL,
-                skip INTEGER NOT NULL DEFAULT 0
-            );
-            CREATE TABLE IF NOT EXISTS user (
-                dropbox_user_id TEXT UNIQUE NOT NULL,
-                dropbox_team_member_id TEXT UNIQUE NOT NULL,
-                dropbox_email TEXT UNIQUE NOT NULL,
-                dropbox_refresh_token TEXT UNIQUE NOT NULL,
-                dropbox_access_token TEXT UNIQUE NOT NULL,
-                dropbox_authorization_code TEXT UNIQUE NOT NULL,
-                dropbox_root_namespace_id STRING UNIQUE NOT NULL,
-                dropbox_home_namespace_id STRING UNIQUE NOT NULL,
-                aws_access_key_id TEXT UNIQUE NOT NULL,
-                aws_secret_access_key TEXT UNIQUE NOT NULL
-            );
-            CREATE TABLE IF NOT EXISTS config (
-                dropbox_base_folder TEXT,
-                s3_bucket TEXT,
-                aws_region TEXT
-            );
-            ",
-    ) {
-        Ok(_) => {
-            print!("📁  Database initialized\n\n");
-            connection
-        }
-        Err(err) => panic!("❌  {err}"),
+/// The schema, one step per version: `MIGRATIONS[0]` brings an empty database to version 1,
+/// `MIGRATIONS[1]` brings version 1 to version 2, and so on. `init` runs the steps a
+/// database hasn't seen yet, each in its own transaction. Released steps must never be
+/// edited; change the schema by appending a new one.
+const MIGRATIONS: &[&str] = &[
+    // 1: the original schema. `IF NOT EXISTS` lets databases from before `schema_version`
+    // existed adopt it.
+    "
+        CREATE TABLE IF NOT EXISTS paths (
+            dropbox_id TEXT PRIMARY KEY,
+            dropbox_path TEXT NOT NULL,
+            dropbox_size INTEGER NOT NULL,
+            dropbox_hash TEXT NOT NULL,
+            migrated INTEGER NOT NULL DEFAULT -1,
+            local_path TEXT UNIQUE DEFAULT NULL,
+            local_size INTEGER DEFAULT NULL,
+            s3_key TEXT UNIQUE DEFAULT NULL,
+            s3_size INTEGER DEFAULT NULL,
+            s3_hash TEXT UNIQUE DEFAULT NULL,
+            skip INTEGER NOT NULL DEFAULT 0
+        );
+        CREATE TABLE IF NOT EXISTS user (
+            dropbox_user_id TEXT UNIQUE NOT NULL,
+            dropbox_team_member_id TEXT UNIQUE NOT NULL,
+            dropbox_email TEXT UNIQUE NOT NULL,
+            dropbox_refresh_token TEXT UNIQUE NOT NULL,
+            dropbox_access_token TEXT UNIQUE NOT NULL,
+            dropbox_authorization_code TEXT UNIQUE NOT NULL,
+            dropbox_root_namespace_id STRING UNIQUE NOT NULL,
+            dropbox_home_namespace_id STRING UNIQUE NOT NULL,
+            aws_access_key_id TEXT UNIQUE NOT NULL,
+            aws_secret_access_key TEXT UNIQUE NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS config (
+            dropbox_base_folder TEXT,
+            s3_bucket TEXT,
+            aws_region TEXT
+        );
+    ",
+    // 2: worker claims, resumable multipart uploads and per-file failures.
+    "
+        CREATE TABLE IF NOT EXISTS claims (
+            dropbox_id TEXT PRIMARY KEY
+        );
+        CREATE TABLE IF NOT EXISTS multipart_uploads (
+            dropbox_id TEXT PRIMARY KEY,
+            s3_key TEXT NOT NULL,
+            upload_id TEXT NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS multipart_parts (
+            dropbox_id TEXT NOT NULL,
+            part_number INTEGER NOT NULL,
+            e_tag TEXT NOT NULL,
+            checksum_sha256 TEXT NOT NULL,
+            PRIMARY KEY (dropbox_id, part_number)
+        );
+        CREATE TABLE IF NOT EXISTS failures (
+            dropbox_id TEXT PRIMARY KEY,
+            error TEXT NOT NULL
+        );
+    ",
+    // 3: files with identical content share an S3 checksum, so `s3_hash` can't be UNIQUE.
+    "
+        CREATE TABLE paths_v3 (
+            dropbox_id TEXT PRIMARY KEY,
+            dropbox_path TEXT NOT NULL,
+            dropbox_size INTEGER NOT NULL,
+            dropbox_hash TEXT NOT NULL,
+            migrated INTEGER NOT NULL DEFAULT -1,
+            local_path TEXT UNIQUE DEFAULT NULL,
+            local_size INTEGER DEFAULT NULL,
+            s3_key TEXT UNIQUE DEFAULT NULL,
+            s3_size INTEGER DEFAULT NULL,
+            s3_hash TEXT DEFAULT NULL,
+            skip INTEGER NOT NULL DEFAULT 0
+        );
+        INSERT INTO paths_v3 (dropbox_id, dropbox_path, dropbox_size, dropbox_hash, migrated, local_path, local_size, s3_key, s3_size, s3_hash, skip)
+            SELECT dropbox_id, dropbox_path, dropbox_size, dropbox_hash, migrated, local_path, local_size, s3_key, s3_size, s3_hash, skip FROM paths;
+        DROP TABLE paths;
+        ALTER TABLE paths_v3 RENAME TO paths;
+    ",
+    // 4: the `list_folder` cursor `sync` continues from.
+    "
+        ALTER TABLE config ADD COLUMN dropbox_cursor TEXT DEFAULT NULL;
+    ",
+    // 5: tombstones for files deleted in Dropbox.
+    "
+        ALTER TABLE paths ADD COLUMN deleted INTEGER NOT NULL DEFAULT 0;
+    ",
+    // 6: files a shutdown interrupted mid-transfer.
+    "
+        CREATE TABLE IF NOT EXISTS interruptions (
+            dropbox_id TEXT PRIMARY KEY,
+            reason TEXT NOT NULL,
+            interrupted_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+        );
+    ",
+    // 7: small files packed into tar bundles, with where each one sits in its bundle.
+    "
+        CREATE TABLE IF NOT EXISTS bundle_members (
+            dropbox_id TEXT PRIMARY KEY,
+            bundle_key TEXT NOT NULL,
+            offset INTEGER NOT NULL,
+            length INTEGER NOT NULL
+        );
+    ",
+    // 8: how each client-side encrypted object was encrypted.
+    "
+        CREATE TABLE IF NOT EXISTS encrypted_objects (
+            s3_key TEXT PRIMARY KEY,
+            key_id TEXT NOT NULL,
+            scheme TEXT NOT NULL,
+            wrapped_key TEXT NOT NULL,
+            nonce_prefix TEXT NOT NULL,
+            plaintext_size INTEGER NOT NULL
+        );
+    ",
+    // 9: compression, and the compressed size that is archived instead of `dropbox_size`.
+    "
+        ALTER TABLE paths ADD COLUMN compression TEXT DEFAULT NULL;
+        ALTER TABLE paths ADD COLUMN compressed_size INTEGER DEFAULT NULL;
+    ",
+    // 10: files archived as references to the object of an identical file.
+    "
+        CREATE TABLE IF NOT EXISTS duplicates (
+            dropbox_id TEXT PRIMARY KEY,
+            canonical_id TEXT NOT NULL,
+            s3_key TEXT NOT NULL
+        );
+    ",
+    // 11: objects requested back from Deep Archive by `restore`.
+    "
+        CREATE TABLE IF NOT EXISTS restores (
+            s3_key TEXT PRIMARY KEY,
+            tier TEXT NOT NULL,
+            days INTEGER NOT NULL,
+            requested_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+            restored_at TEXT DEFAULT NULL,
+            downloaded_at TEXT DEFAULT NULL
+        );
+    ",
+    // 12: files can come from sources other than Dropbox, so each one is known by its
+    // source's stable id and path, along with the kind of source.
+    "
+        ALTER TABLE paths RENAME COLUMN dropbox_id TO source_id;
+        ALTER TABLE paths RENAME COLUMN dropbox_path TO source_path;
+        ALTER TABLE paths ADD COLUMN source_kind TEXT NOT NULL DEFAULT 'dropbox';
+        ALTER TABLE claims RENAME COLUMN dropbox_id TO source_id;
+        ALTER TABLE multipart_uploads RENAME COLUMN dropbox_id TO source_id;
+        ALTER TABLE multipart_parts RENAME COLUMN dropbox_id TO source_id;
+        ALTER TABLE failures RENAME COLUMN dropbox_id TO source_id;
+        ALTER TABLE interruptions RENAME COLUMN dropbox_id TO source_id;
+        ALTER TABLE bundle_members RENAME COLUMN dropbox_id TO source_id;
+        ALTER TABLE duplicates RENAME COLUMN dropbox_id TO source_id;
+    ",
+    // 13: when each file was last modified, for sources that report it.
+    "
+        ALTER TABLE paths ADD COLUMN source_mtime INTEGER DEFAULT NULL;
+    ",
+    // 14: sources whose listing ran to the end, so an interrupted one is started again.
+    // Catalogs from before were only ever listed once, so theirs count as finished.
+    "
+        CREATE TABLE listings (source_kind TEXT PRIMARY KEY);
+        INSERT INTO listings SELECT DISTINCT source_kind FROM paths;
+    ",
+];
+
+pub fn init(connection: DBConnection) -> Result<DBConnection> {
+    connection.execute("CREATE TABLE IF NOT EXISTS schema_version (version INTEGER NOT NULL);")?;
+    let found = schema_version(&connection)?;
+    let supported = MIGRATIONS.len() as i64;
+    if found > supported {
+        return Err(DeepFreezeError::NewerSchema { found, supported });
+    }
+    for (version, migration) in (1..).zip(MIGRATIONS).skip(found as usize) {
+        transaction(&connection, || {
+            connection.execute(migration)?;
+            execute(&connection, "DELETE FROM schema_version;", &[])?;
+            execute(
+                &connection,
+                "INSERT INTO schema_version (version) VALUES (?);",
+                &[Value::Integer(version)],
+            )
+        })?;
+        println!("📁  Database migrated to schema version {version}");
     }
+    print!("📁  Database initialized\n\n");
+    Ok(connection)
 }
 
-pub fn insert_dropbox_paths(connection: &DBConnection, entries: &Vec<serde_json::Value>) {
-    let statement = build_insert_rows_statement(&entries);
-    match connection.execute(&statement) {
-        Ok(_) => println!("🎉 File list updated"),
-        Err(err) => {
-            println!("❌  Error in statement: {statement}");
-            panic!("{}", err);
-        }
-    }
+pub fn schema_version(connection: &DBConnection) -> Result<i64> {
+    read_i64(connection, "SELECT MAX(version) FROM schema_version", &[])
 }
 
-fn build_insert_rows_statement(entries: &Vec<serde_json::Value>) -> String {
-    let mut statement = entries
-        .iter()
-        .filter(|row| row.get(".tag").unwrap().as_str().unwrap() == "file")
-        .map(|row| {
-            let mut dropbox_path = row
-                .clone()
-                .get("path_display")
-                .unwrap()
-                .to_string()
-                .to_owned();
-            dropbox_path = find_and_replace(&dropbox_path, &["s/\'/\'\'/g"])
-                .unwrap()
-                .to_string();
-            let dropbox_id = row.get("id").unwrap().to_string().to_owned();
-            let dropbox_hash = row.get("content_hash").unwrap().to_string().to_owned();
-            let dropbox_size = row.get("size").unwrap().to_string().to_owned();
-            return format!(
-                "('{}', '{}', {}, '{}', -1), ",
-                dropbox_id, dropbox_path, dropbox_size, dropbox_hash
-            );
-        })
-        .collect::<Vec<_>>()
-        .join("");
-    statement = format!(
-        "INSERT OR IGNORE INTO paths (dropbox_id, dropbox_path, dropbox_size, dropbox_hash, migrated) VALUES {};",
-        statement
-    );
-    find_and_replace(&statement, &["s/, ;/;/g", "s/\"//g"])
-        .unwrap()
-        .to_string()
-        .to_owned()
-}
-
-pub fn insert_config(sqlite: &DBConnection) {
-    let dropbox_base_folder = getenv("DROPBOX_BASE_FOLDER").unwrap_or(String::new());
-    let s3_bucket = getenv("S3_BUCKET").unwrap_or(String::new());
-    let aws_region = getenv("AWS_REGION").unwrap_or(String::new());
-    let statement = format!(
-        "INSERT OR REPLACE INTO config (dropbox_base_folder, s3_bucket, aws_region) VALUES ('{}', '{}', '{}');",
-        dropbox_base_folder, s3_bucket, aws_region
-    );
-    match sqlite.execute(&statement) {
-        Ok(_) => print!("\n📁  Configuration updated\n\n"),
-        Err(err) => {
-            println!("❌  Error in statement: {statement}");
-            panic!("{}", err);
+/// Inserts the files from one page of a source's listing in a single transaction. Rows
+/// already in the database are left as they are.
+pub fn insert_source_entries(
+    connection: &DBConnection,
+    source_kind: &str,
+    entries: &[SourceEntry],
+) -> Result<()> {
+    transaction(connection, || {
+        let mut statement = connection.prepare(
+            "INSERT OR IGNORE INTO paths (source_id, source_path, dropbox_size, dropbox_hash, source_kind, source_mtime, migrated) VALUES (?, ?, ?, ?, ?, ?, -1);",
+        )?;
+        for entry in entries {
+            statement.reset()?;
+            statement.bind::<&[Value]>(&[
+                entry.id.as_str().into(),
+                entry.path.as_str().into(),
+                (entry.size as i64).into(),
+                entry.hash.as_str().into(),
+                source_kind.into(),
+                entry.modified.map_or(Value::Null, Value::Integer),
+            ])?;
+            statement.next()?;
         }
+        Ok(())
+    })?;
+    println!("🎉 File list updated");
+    Ok(())
+}
+
+/// Refuses a database that holds files from a source other than `source_kind`. Each
+/// database catalogs one source: its listing, claims and status all cover every row, so
+/// another source's rows would be fetched from the wrong place.
+pub fn check_source_kind(connection: &DBConnection, source_kind: &str) -> Result<()> {
+    match connection
+        .prepare("SELECT source_kind FROM paths WHERE source_kind != ? LIMIT 1;")?
+        .into_iter()
+        .bind((1, source_kind))?
+        .next()
+    {
+        Some(row) => Err(DeepFreezeError::Config(format!(
+            "{} holds files from {}, use another --dbfile for {source_kind}",
+            getenv("DBFILE").unwrap_or_default(),
+            row?.try_read::<&str, _>(0)?
+        ))),
+        None => Ok(()),
     }
 }
 
-pub async fn insert_user(connection: &DBConnection, member: &JSON) {
-    let dropbox_user_id = member.get("account_id").unwrap().as_str().unwrap();
-    let dropbox_team_member_id = member.get("team_member_id").unwrap().as_str().unwrap();
-    setenv("DROPBOX_TEAM_MEMBER_ID", dropbox_team_member_id.to_string()).await;
-    let dropbox_email = member.get("email").unwrap().as_str().unwrap();
+pub fn insert_config(sqlite: &DBConnection) -> Result<()> {
+    let dropbox_base_folder = getenv("DROPBOX_BASE_FOLDER").unwrap_or_default();
+    let s3_bucket = getenv("S3_BUCKET").unwrap_or_default();
+    let aws_region = getenv("AWS_REGION").unwrap_or_default();
+    execute(
+        sqlite,
+        "INSERT OR REPLACE INTO config (dropbox_base_folder, s3_bucket, aws_region) VALUES (?, ?, ?);",
+        &[dropbox_base_folder.into(), s3_bucket.into(), aws_region.into()],
+    )?;
+    print!("\n📁  Configuration updated\n\n");
+    Ok(())
+}
+
+pub async fn insert_user(connection: &DBConnection, member: &JSON) -> Result<()> {
+    let dropbox_user_id = json::get_str(member, "account_id")?;
+    let dropbox_team_member_id = json::get_str(member, "team_member_id")?;
+    setenv("DROPBOX_TEAM_MEMBER_ID", dropbox_team_member_id.to_string()).await?;
+    let dropbox_email = json::get_str(member, "email")?;
     let default = JSON::String("0".to_string());
     let dropbox_root_namespace_id = member
         .get("root_info")
@@ -179,7 +406,7 @@ pub async fn insert_user(connection: &DBConnection, member: &JSON) {
         "DROPBOX_ROOT_NAMESPACE_ID",
         dropbox_root_namespace_id.to_string(),
     )
-    .await;
+    .await?;
     let dropbox_home_namespace_id = member
         .get("root_info")
         .unwrap_or(&default)
@@ -191,139 +418,1616 @@ pub async fn insert_user(connection: &DBConnection, member: &JSON) {
         "DROPBOX_HOME_NAMESPACE_ID",
         dropbox_home_namespace_id.to_string(),
     )
-    .await;
-    let dropbox_refresh_token = getenv("DROPBOX_REFRESH_TOKEN").unwrap_or(String::new());
-    let dropbox_access_token = getenv("DROPBOX_ACCESS_TOKEN").unwrap_or(String::new());
-    let dropbox_authorization_code = getenv("DROPBOX_AUTHORIZATION_CODE").unwrap_or(String::new());
-    let aws_access_key_id = getenv("AWS_ACCESS_KEY_ID").unwrap_or(String::new());
-    let aws_secret_access_key = getenv("AWS_SECRET_ACCESS_KEY").unwrap_or(String::new());
-    let statement = format!(
-            "INSERT OR REPLACE INTO user (dropbox_user_id, dropbox_team_member_id, dropbox_email, dropbox_root_namespace_id, dropbox_home_namespace_id, dropbox_refresh_token, dropbox_access_token, dropbox_authorization_code, aws_access_key_id, aws_secret_access_key) VALUES ('{}', '{}', '{}', '{}', '{}', '{}', '{}', '{}','{}','{}');",
-            dropbox_user_id, dropbox_team_member_id, dropbox_email, dropbox_root_namespace_id, dropbox_home_namespace_id, dropbox_refresh_token, dropbox_access_token, dropbox_authorization_code, aws_access_key_id, aws_secret_access_key
-        );
-    match connection.execute(&statement) {
-        Ok(_) => {
-            println!("👤  User {dropbox_email} updated");
-            ()
+    .await?;
+    let dropbox_refresh_token = getenv("DROPBOX_REFRESH_TOKEN").unwrap_or_default();
+    let dropbox_access_token = getenv("DROPBOX_ACCESS_TOKEN").unwrap_or_default();
+    let dropbox_authorization_code = getenv("DROPBOX_AUTHORIZATION_CODE").unwrap_or_default();
+    let aws_access_key_id = getenv("AWS_ACCESS_KEY_ID").unwrap_or_default();
+    let aws_secret_access_key = getenv("AWS_SECRET_ACCESS_KEY").unwrap_or_default();
+    execute(
+        connection,
+        "INSERT OR REPLACE INTO user (dropbox_user_id, dropbox_team_member_id, dropbox_email, dropbox_root_namespace_id, dropbox_home_namespace_id, dropbox_refresh_token, dropbox_access_token, dropbox_authorization_code, aws_access_key_id, aws_secret_access_key) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?);",
+        &[
+            dropbox_user_id.into(),
+            dropbox_team_member_id.into(),
+            dropbox_email.into(),
+            dropbox_root_namespace_id.into(),
+            dropbox_home_namespace_id.into(),
+            dropbox_refresh_token.into(),
+            dropbox_access_token.into(),
+            dropbox_authorization_code.into(),
+            aws_access_key_id.into(),
+            aws_secret_access_key.into(),
+        ],
+    )?;
+    println!("👤  User {dropbox_email} updated");
+    Ok(())
+}
+
+/// What happens to the archived S3 object of a file that was deleted or moved in Dropbox.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum DeletionPolicy {
+    /// Leave the object alone. A moved file keeps pointing at it through `paths.s3_key`.
+    #[default]
+    Keep,
+    /// Archive a moved file again under its new key, leaving the old object in place.
+    Copy,
+    /// Like `Copy`, and tag the objects of moved and deleted files as deleted in the source.
+    Tag,
+}
+
+impl std::str::FromStr for DeletionPolicy {
+    type Err = DeepFreezeError;
+
+    fn from_str(policy: &str) -> Result<Self> {
+        match policy {
+            "" | "keep" => Ok(DeletionPolicy::Keep),
+            "copy" => Ok(DeletionPolicy::Copy),
+            "tag" => Ok(DeletionPolicy::Tag),
+            other => Err(DeepFreezeError::Config(format!(
+                "DELETION_POLICY must be keep, copy or tag, not {other}"
+            ))),
         }
-        Err(err) => {
-            print!("\n\n❌  Error in statement:\n\n{:?}\n\n", statement);
-            panic!("{}", err);
+    }
+}
+
+/// What a `sync` changed in the catalog.
+#[derive(Debug, Default, PartialEq)]
+pub struct SyncSummary {
+    pub added: i64,
+    pub changed: i64,
+    pub moved: i64,
+    pub deleted: i64,
+    /// Keys of archived objects whose file is no longer at that path in Dropbox, to be
+    /// tagged under `DeletionPolicy::Tag`. Bundled and deduplicated files are left out:
+    /// their object still holds other files.
+    pub removed_keys: Vec<String>,
+}
+
+/// What the catalog knows about a file before a `sync` page is applied.
+struct KnownPath {
+    source_path: String,
+    dropbox_hash: String,
+    migrated: i64,
+    s3_key: Option<String>,
+    deleted: bool,
+    /// Its archived object also holds, or stands in for, other files: it is in a tar
+    /// bundle, is a duplicate, or has duplicates.
+    shared: bool,
+}
+
+impl KnownPath {
+    /// Where the file's archived object lives: the key recorded when it was moved under
+    /// `DeletionPolicy::Keep`, or else the key of its current path.
+    fn s3_key(&self, base_folder: &str) -> Result<String> {
+        match &self.s3_key {
+            Some(s3_key) => Ok(s3_key.clone()),
+            None => util::dropbox_key(base_folder, &self.source_path),
         }
     }
 }
 
-pub fn count_rows(connection: &DBConnection) -> i64 {
-    connection
-        .prepare("SELECT COUNT(*) FROM paths")
-        .unwrap()
+fn get_known_path(connection: &DBConnection, source_id: &str) -> Result<Option<KnownPath>> {
+    match connection
+        .prepare(
+            "SELECT source_path, dropbox_hash, migrated, s3_key, deleted,
+                source_id IN (SELECT source_id FROM bundle_members)
+                    OR source_id IN (SELECT source_id FROM duplicates)
+                    OR source_id IN (SELECT canonical_id FROM duplicates) AS shared
+                FROM paths WHERE source_id = ?;",
+        )?
         .into_iter()
-        .map(|row| row.unwrap())
-        .map(|row| row.read::<i64, _>(0))
+        .bind((1, source_id))?
         .next()
-        .unwrap()
+    {
+        Some(row) => {
+            let row = row?;
+            Ok(Some(KnownPath {
+                source_path: row.try_read::<&str, _>("source_path")?.to_string(),
+                dropbox_hash: row.try_read::<&str, _>("dropbox_hash")?.to_string(),
+                migrated: row.try_read::<i64, _>("migrated")?,
+                s3_key: row
+                    .try_read::<Option<&str>, _>("s3_key")?
+                    .map(str::to_string),
+                deleted: row.try_read::<i64, _>("deleted")? > 0,
+                shared: row.try_read::<i64, _>("shared")? > 0,
+            }))
+        }
+        None => Ok(None),
+    }
 }
 
-pub fn count_migrated(connection: &DBConnection) -> i64 {
-    let query = "SELECT COUNT(*) FROM paths WHERE migrated = 1";
-    connection
-        .prepare(query)
-        .unwrap()
+/// Applies one page of `list_folder` results to a catalog that already holds files:
+///
+/// - new files are inserted;
+/// - files whose `content_hash` no longer matches are marked changed (`migrated = -3`)
+///   so they are archived again;
+/// - files whose path changed are moved, recognised by their stable `source_id`, and
+///   `policy` decides whether an archived one is archived again under its new key;
+/// - `deleted` entries tombstone the file, or every file under a deleted folder, and
+///   take it out of the migration.
+///
+/// Object keys are the Dropbox paths below `base_folder`.
+pub fn sync_dropbox_paths(
+    connection: &DBConnection,
+    entries: &[serde_json::Value],
+    (policy, base_folder): (DeletionPolicy, &str),
+    summary: &mut SyncSummary,
+) -> Result<()> {
+    transaction(connection, || {
+        for entry in entries {
+            match json::get_str(entry, ".tag")? {
+                "file" => sync_file(connection, entry, (policy, base_folder), summary)?,
+                "deleted" => sync_deletion(connection, entry, (policy, base_folder), summary)?,
+                _ => (),
+            }
+        }
+        Ok(())
+    })
+}
+
+fn sync_file(
+    connection: &DBConnection,
+    entry: &serde_json::Value,
+    (policy, base_folder): (DeletionPolicy, &str),
+    summary: &mut SyncSummary,
+) -> Result<()> {
+    let source_id = json::get_str(entry, "id")?;
+    let source_path = json::get_str(entry, "path_display")?;
+    let dropbox_hash = json::get_str(entry, "content_hash")?;
+    let dropbox_size = json::get_size(entry)?;
+    let Some(known) = get_known_path(connection, source_id)? else {
+        execute(
+            connection,
+            "INSERT INTO paths (source_id, source_path, dropbox_size, dropbox_hash, migrated) VALUES (?, ?, ?, ?, -1);",
+            &[source_id.into(), source_path.into(), dropbox_size.into(), dropbox_hash.into()],
+        )?;
+        summary.added += 1;
+        return Ok(());
+    };
+    if known.deleted {
+        execute(
+            connection,
+            "UPDATE paths SET deleted = 0, skip = 0 WHERE source_id = ?;",
+            &[source_id.into()],
+        )?;
+        println!("♻️   Restored: {source_path}");
+    }
+    let moved = known.source_path != source_path;
+    let changed = known.dropbox_hash != dropbox_hash;
+    let archived = known.migrated == 1;
+    if moved {
+        execute(
+            connection,
+            "UPDATE paths SET source_path = ? WHERE source_id = ?;",
+            &[source_path.into(), source_id.into()],
+        )?;
+        println!("🚚  Moved: {} → {source_path}", known.source_path);
+        summary.moved += 1;
+    }
+    if changed || (moved && archived && policy != DeletionPolicy::Keep) {
+        execute(
+            connection,
+            "UPDATE paths SET dropbox_size = ?, dropbox_hash = ?, migrated = -3, skip = 0, s3_key = NULL WHERE source_id = ?;",
+            &[dropbox_size.into(), dropbox_hash.into(), source_id.into()],
+        )?;
+        execute(
+            connection,
+            "DELETE FROM failures WHERE source_id = ?;",
+            &[source_id.into()],
+        )?;
+        delete_duplicate(connection, source_id)?;
+        if changed {
+            // Its object is about to be overwritten with the new content.
+            release_duplicates_of(connection, source_id)?;
+            println!("🔄  Changed: {source_path}");
+            summary.changed += 1;
+        }
+        if moved && archived && !known.shared && policy == DeletionPolicy::Tag {
+            summary.removed_keys.push(known.s3_key(base_folder)?);
+        }
+    } else if moved && archived {
+        execute(
+            connection,
+            "UPDATE paths SET s3_key = ? WHERE source_id = ?;",
+            &[known.s3_key(base_folder)?.into(), source_id.into()],
+        )?;
+    }
+    // Parts of an unfinished upload hold the old content or belong to the old key.
+    if changed || moved {
+        delete_multipart_upload(connection, source_id)?;
+    }
+    Ok(())
+}
+
+fn sync_deletion(
+    connection: &DBConnection,
+    entry: &serde_json::Value,
+    (policy, base_folder): (DeletionPolicy, &str),
+    summary: &mut SyncSummary,
+) -> Result<()> {
+    let path_lower = json::get_str(entry, "path_lower")?;
+    let ids = connection
+        .prepare(
+            "SELECT source_id FROM paths WHERE deleted < 1
+                AND (lower(source_path) = ?1 OR substr(lower(source_path), 1, length(?1) + 1) = ?1 || '/');",
+        )?
+        .into_iter()
+        .bind((1, path_lower))?
+        .map(|row| Ok(row?.try_read::<&str, _>(0)?.to_string()))
+        .collect::<Result<Vec<String>>>()?;
+    for source_id in ids {
+        let Some(known) = get_known_path(connection, &source_id)? else {
+            continue;
+        };
+        if known.migrated == 1 && !known.shared && policy == DeletionPolicy::Tag {
+            summary.removed_keys.push(known.s3_key(base_folder)?);
+        }
+        execute(
+            connection,
+            "UPDATE paths SET deleted = 1, skip = 1 WHERE source_id = ?;",
+            &[source_id.as_str().into()],
+        )?;
+        delete_multipart_upload(connection, &source_id)?;
+        println!("🪦  Deleted: {}", known.source_path);
+        summary.deleted += 1;
+    }
+    Ok(())
+}
+
+/// The `list_folder` cursor saved by the last listing, if any.
+/// Whether a listing of the source of `source_kind` has run to the end.
+pub fn is_listed(connection: &DBConnection, source_kind: &str) -> Result<bool> {
+    Ok(read_i64(
+        connection,
+        "SELECT COUNT(*) FROM listings WHERE source_kind = ?",
+        &[source_kind.into()],
+    )? > 0)
+}
+
+pub fn set_listed(connection: &DBConnection, source_kind: &str) -> Result<()> {
+    execute(
+        connection,
+        "INSERT OR IGNORE INTO listings (source_kind) VALUES (?);",
+        &[source_kind.into()],
+    )
+}
+
+pub fn get_cursor(connection: &DBConnection) -> Result<Option<String>> {
+    match connection
+        .prepare("SELECT dropbox_cursor FROM config WHERE dropbox_cursor IS NOT NULL LIMIT 1;")?
         .into_iter()
-        .map(|row| row.unwrap())
-        .map(|row| row.read::<i64, _>(0))
         .next()
-        .unwrap()
+    {
+        Some(row) => Ok(Some(row?.try_read::<&str, _>(0)?.to_string())),
+        None => Ok(None),
+    }
 }
 
-pub fn count_unmigrated(connection: &ConnectionWithFullMutex) -> i64 {
-    connection
-        .prepare("SELECT COUNT(*) FROM paths WHERE migrated < 1")
-        .unwrap()
+pub fn set_cursor(connection: &DBConnection, cursor: &str) -> Result<()> {
+    execute(
+        connection,
+        "UPDATE config SET dropbox_cursor = ?;",
+        &[cursor.into()],
+    )?;
+    if connection.change_count() == 0 {
+        execute(
+            connection,
+            "INSERT INTO config (dropbox_cursor) VALUES (?);",
+            &[cursor.into()],
+        )?;
+    }
+    Ok(())
+}
+
+pub fn count_rows(connection: &DBConnection) -> Result<i64> {
+    read_i64(connection, "SELECT COUNT(*) FROM paths", &[])
+}
+
+pub fn count_migrated(connection: &DBConnection) -> Result<i64> {
+    read_i64(
+        connection,
+        "SELECT COUNT(*) FROM paths WHERE migrated = 1",
+        &[],
+    )
+}
+
+pub fn count_hash_mismatches(connection: &DBConnection) -> Result<i64> {
+    read_i64(
+        connection,
+        "SELECT COUNT(*) FROM paths WHERE migrated = -2",
+        &[],
+    )
+}
+
+pub fn count_unmigrated(connection: &DBConnection) -> Result<i64> {
+    read_i64(
+        connection,
+        "SELECT COUNT(*) FROM paths WHERE migrated < 1 AND deleted < 1",
+        &[],
+    )
+}
+
+/// Rows a worker could still claim: not migrated, not skipped and not failed.
+pub fn count_pending(connection: &DBConnection) -> Result<i64> {
+    read_i64(
+        connection,
+        "SELECT COUNT(*) FROM paths WHERE migrated < 1 AND skip < 1",
+        &[],
+    )
+}
+
+pub fn count_deleted(connection: &DBConnection) -> Result<i64> {
+    read_i64(
+        connection,
+        "SELECT COUNT(*) FROM paths WHERE deleted = 1",
+        &[],
+    )
+}
+
+pub fn count_failures(connection: &DBConnection) -> Result<i64> {
+    read_i64(connection, "SELECT COUNT(*) FROM failures", &[])
+}
+
+/// Migrated files that live in a tar bundle, and how many bundles hold them.
+pub fn count_bundled(connection: &DBConnection) -> Result<(i64, i64)> {
+    let query = "FROM bundle_members JOIN paths ON paths.source_id = bundle_members.source_id
+        WHERE paths.migrated = 1";
+    Ok((
+        read_i64(connection, &format!("SELECT COUNT(*) {query}"), &[])?,
+        read_i64(
+            connection,
+            &format!("SELECT COUNT(DISTINCT bundle_key) {query}"),
+            &[],
+        )?,
+    ))
+}
+
+/// Migrated files archived as references to an identical file's object, and the bytes
+/// that saved.
+pub fn count_deduplicated(connection: &DBConnection) -> Result<(i64, i64)> {
+    let query = "FROM paths WHERE migrated = 1
+        AND source_id IN (SELECT source_id FROM duplicates)";
+    Ok((
+        read_i64(connection, &format!("SELECT COUNT(*) {query}"), &[])?,
+        read_i64(
+            connection,
+            &format!("SELECT SUM(dropbox_size) {query}"),
+            &[],
+        )?,
+    ))
+}
+
+/// Migrated files that were archived compressed, and the bytes that saved.
+pub fn count_compressed(connection: &DBConnection) -> Result<(i64, i64)> {
+    let query = "FROM paths WHERE migrated = 1 AND compressed_size IS NOT NULL";
+    Ok((
+        read_i64(connection, &format!("SELECT COUNT(*) {query}"), &[])?,
+        read_i64(
+            connection,
+            &format!("SELECT SUM(dropbox_size - compressed_size) {query}"),
+            &[],
+        )?,
+    ))
+}
+
+pub fn count_interruptions(connection: &DBConnection) -> Result<i64> {
+    read_i64(connection, "SELECT COUNT(*) FROM interruptions", &[])
+}
+
+/// Atomically claims the next unmigrated, unskipped row for a migration worker. A row
+/// stays claimed until `release_claims` runs, so each row is handed out once per pass.
+pub fn claim_next_path(connection: &DBConnection) -> Result<Option<DBRow>> {
+    let claimed = connection
+        .prepare(
+            "INSERT INTO claims (source_id)
+                SELECT source_id FROM paths
+                WHERE migrated < 1 AND skip < 1
+                AND source_id NOT IN (SELECT source_id FROM claims)
+                ORDER BY source_path ASC LIMIT 1
+                RETURNING source_id",
+        )?
         .into_iter()
-        .map(|row| row.unwrap())
-        .map(|row| row.read::<i64, _>(0))
+        .next();
+    let source_id = match claimed {
+        Some(row) => row?.try_read::<&str, _>(0)?.to_string(),
+        None => return Ok(None),
+    };
+    match connection
+        .prepare("SELECT * FROM paths WHERE source_id = ?;")?
+        .into_iter()
+        .bind((1, source_id.as_str()))?
         .next()
-        .unwrap()
+    {
+        Some(row) => Ok(Some(row?)),
+        None => Ok(None),
+    }
+}
+
+pub fn release_claims(connection: &DBConnection) -> Result<()> {
+    connection.execute("DELETE FROM claims;")?;
+    Ok(())
+}
+
+pub fn set_migrated(connection: &DBConnection, source_id: &str) -> Result<()> {
+    execute(
+        connection,
+        "UPDATE paths SET migrated = 1 WHERE source_id = ?;",
+        &[source_id.into()],
+    )?;
+    execute(
+        connection,
+        "DELETE FROM failures WHERE source_id = ?;",
+        &[source_id.into()],
+    )?;
+    execute(
+        connection,
+        "DELETE FROM interruptions WHERE source_id = ?;",
+        &[source_id.into()],
+    )?;
+    println!("🪺  Migrated: {source_id}");
+    Ok(())
+}
+
+/// A row changed in Dropbox (`migrated = -3`) stays changed until it has been uploaded
+/// again, since the object S3 still holds is the old content.
+pub fn set_unmigrated(connection: &DBConnection, source_id: &str) -> Result<()> {
+    execute(
+        connection,
+        "UPDATE paths SET migrated = 0 WHERE source_id = ? AND migrated != -3;",
+        &[source_id.into()],
+    )?;
+    println!("🪹  Not migrated: {source_id}");
+    Ok(())
+}
+
+/// Records the SHA-256 checksum (composite for multipart objects) S3 verified on upload.
+pub fn set_s3_hash(connection: &DBConnection, source_id: &str, s3_hash: &str) -> Result<()> {
+    execute(
+        connection,
+        "UPDATE paths SET s3_hash = ? WHERE source_id = ?;",
+        &[s3_hash.into(), source_id.into()],
+    )?;
+    println!("🔏  Checksum: {s3_hash}");
+    Ok(())
 }
 
-pub fn set_migrated(connection: &ConnectionWithFullMutex, dropbox_id: &str) {
-    match connection.execute(format!(
-        "UPDATE paths SET migrated = 1 WHERE dropbox_id = '{dropbox_id}';",
-    )) {
-        Ok(_) => println!("🪺  Migrated: {dropbox_id}"),
-        Err(err) => panic!("❌  {err}"),
+/// Records how a file is archived: `Some((compression, compressed_size))` when compressed,
+/// or `None` when its bytes go up as they are.
+pub fn set_compression(
+    connection: &DBConnection,
+    source_id: &str,
+    compression: Option<(&str, i64)>,
+) -> Result<()> {
+    let (compression, compressed_size) = match compression {
+        Some((compression, compressed_size)) => (compression.into(), compressed_size.into()),
+        None => (Value::Null, Value::Null),
+    };
+    execute(
+        connection,
+        "UPDATE paths SET compression = ?, compressed_size = ? WHERE source_id = ?;",
+        &[compression, compressed_size, source_id.into()],
+    )
+}
+
+/// The size of the bytes archived for a file before any encryption: its compressed size
+/// when it was compressed, its Dropbox size otherwise.
+pub fn get_archived_size(connection: &DBConnection, source_id: &str) -> Result<i64> {
+    match read_i64(
+        connection,
+        "SELECT compressed_size FROM paths WHERE source_id = ? AND compressed_size IS NOT NULL;",
+        &[source_id.into()],
+    )? {
+        0 => get_dropbox_size(connection, source_id),
+        compressed_size => Ok(compressed_size),
     }
 }
 
-pub fn set_unmigrated(connection: &ConnectionWithFullMutex, dropbox_id: &str) {
-    match connection.execute(format!(
-        "UPDATE paths SET migrated = 0 WHERE dropbox_id = '{dropbox_id}';",
-    )) {
-        Ok(_) => println!("🪹  Not migrated: {dropbox_id}"),
-        Err(err) => panic!("❌  {err}"),
+/// Checks the `content_hash` of a file's bytes against the one it was listed with. A
+/// file listed without one (SFTP) has it recorded instead, for `DEDUP` and `restore` to
+/// check against later.
+pub fn check_content_hash(
+    connection: &DBConnection,
+    source_id: &str,
+    (expected, actual): (&str, String),
+) -> Result<()> {
+    if expected.is_empty() {
+        return execute(
+            connection,
+            "UPDATE paths SET dropbox_hash = ? WHERE source_id = ?;",
+            &[actual.into(), source_id.into()],
+        );
     }
+    Ok(checksum::verify_dropbox_hash(expected, actual)?)
+}
+
+/// Marks a row whose downloaded bytes did not match its Dropbox `content_hash`. The row
+/// is also skipped, so it needs a rescan or a manual reset before it is tried again.
+pub fn set_hash_mismatch(connection: &DBConnection, source_id: &str) -> Result<()> {
+    execute(
+        connection,
+        "UPDATE paths SET migrated = -2, skip = 1 WHERE source_id = ?;",
+        &[source_id.into()],
+    )?;
+    println!("🧨  Hash mismatch: {source_id}");
+    Ok(())
+}
+
+pub fn set_skip(connection: &DBConnection, source_id: &str) -> Result<()> {
+    execute(
+        connection,
+        "UPDATE paths SET skip = 1 WHERE source_id = ?;",
+        &[source_id.into()],
+    )?;
+    println!("🪹   Skipping: {source_id}");
+    Ok(())
+}
+
+/// Records why migrating `source_id` failed and skips the row, so one bad file doesn't
+/// stop the others. The skip only lasts until `retry_failures` clears it at the start of
+/// the next run. `--status-only` reports how many failed.
+pub fn record_failure(connection: &DBConnection, source_id: &str, error: &str) -> Result<()> {
+    execute(
+        connection,
+        "INSERT OR REPLACE INTO failures (source_id, error) VALUES (?, ?);",
+        &[source_id.into(), error.into()],
+    )?;
+    println!("💥  Failed: {source_id}: {error}");
+    set_skip(connection, source_id)
+}
+
+/// Lets the rows that failed in an earlier run be claimed again, keeping their recorded
+/// errors until they migrate. Rows skipped for another reason (a hash mismatch, a
+/// deletion) stay skipped. Returns how many rows are tried again.
+pub fn retry_failures(connection: &DBConnection) -> Result<i64> {
+    execute(
+        connection,
+        "UPDATE paths SET skip = 0
+            WHERE source_id IN (SELECT source_id FROM failures)
+            AND migrated != -2 AND deleted < 1;",
+        &[],
+    )?;
+    Ok(connection.change_count() as i64)
+}
+
+/// Records why a shutdown stopped `source_id` mid-transfer. Unlike a failure the row is
+/// not skipped: the next run picks it up again from its checkpoint.
+pub fn record_interruption(connection: &DBConnection, source_id: &str, reason: &str) -> Result<()> {
+    execute(
+        connection,
+        "INSERT OR REPLACE INTO interruptions (source_id, reason) VALUES (?, ?);",
+        &[source_id.into(), reason.into()],
+    )?;
+    println!("⏸️   Interrupted: {source_id}: {reason}");
+    Ok(())
+}
+
+/// Remembers the multipart upload started for `source_id`, replacing (and forgetting the
+/// parts of) any upload recorded for it before.
+pub fn insert_multipart_upload(
+    connection: &DBConnection,
+    source_id: &str,
+    s3_key: &str,
+    upload_id: &str,
+) -> Result<()> {
+    delete_multipart_upload(connection, source_id)?;
+    execute(
+        connection,
+        "INSERT INTO multipart_uploads (source_id, s3_key, upload_id) VALUES (?, ?, ?);",
+        &[source_id.into(), s3_key.into(), upload_id.into()],
+    )
 }
 
-pub fn set_skip(connection: &DBConnection, dropbox_id: &str) {
-    match connection.execute(format!(
-        "UPDATE paths SET skip = 1 WHERE dropbox_id = '{dropbox_id}';",
-    )) {
-        Ok(_) => println!("🪹   Skipping: {dropbox_id}"),
-        Err(err) => panic!("❌  {err}"),
+/// The `(s3_key, upload_id)` of the unfinished multipart upload for `source_id`, if any.
+pub fn get_multipart_upload(
+    connection: &DBConnection,
+    source_id: &str,
+) -> Result<Option<(String, String)>> {
+    match connection
+        .prepare("SELECT s3_key, upload_id FROM multipart_uploads WHERE source_id = ?;")?
+        .into_iter()
+        .bind((1, source_id))?
+        .next()
+    {
+        Some(row) => {
+            let row = row?;
+            Ok(Some((
+                row.try_read::<&str, _>("s3_key")?.to_string(),
+                row.try_read::<&str, _>("upload_id")?.to_string(),
+            )))
+        }
+        None => Ok(None),
     }
 }
 
-pub fn get_total_size(connection: &DBConnection) -> i64 {
+pub fn insert_multipart_part(
+    connection: &DBConnection,
+    source_id: &str,
+    part_number: i32,
+    e_tag: &str,
+    checksum_sha256: &str,
+) -> Result<()> {
+    execute(
+        connection,
+        "INSERT OR REPLACE INTO multipart_parts (source_id, part_number, e_tag, checksum_sha256) VALUES (?, ?, ?, ?);",
+        &[
+            source_id.into(),
+            (part_number as i64).into(),
+            e_tag.into(),
+            checksum_sha256.into(),
+        ],
+    )
+}
+
+/// The `(part_number, e_tag, checksum_sha256)` of every part recorded for `source_id`.
+pub fn get_multipart_parts(
+    connection: &DBConnection,
+    source_id: &str,
+) -> Result<Vec<(i32, String, String)>> {
+    connection
+        .prepare(
+            "SELECT part_number, e_tag, checksum_sha256 FROM multipart_parts WHERE source_id = ? ORDER BY part_number ASC;",
+        )?
+        .into_iter()
+        .bind((1, source_id))?
+        .map(|row| {
+            let row = row?;
+            Ok((
+                row.try_read::<i64, _>("part_number")? as i32,
+                row.try_read::<&str, _>("e_tag")?.to_string(),
+                row.try_read::<&str, _>("checksum_sha256")?.to_string(),
+            ))
+        })
+        .collect()
+}
+
+pub fn delete_multipart_upload(connection: &DBConnection, source_id: &str) -> Result<()> {
+    execute(
+        connection,
+        "DELETE FROM multipart_parts WHERE source_id = ?;",
+        &[source_id.into()],
+    )?;
+    execute(
+        connection,
+        "DELETE FROM multipart_uploads WHERE source_id = ?;",
+        &[source_id.into()],
+    )
+}
+
+/// Whether `upload_id` is the recorded multipart upload of a row a later run will still
+/// try to migrate, and so could resume rather than start over. That includes rows that
+/// failed, which the next run tries again.
+pub fn is_resumable_upload(connection: &DBConnection, upload_id: &str) -> Result<bool> {
+    let count = read_i64(
+        connection,
+        "SELECT COUNT(*) FROM multipart_uploads
+            JOIN paths ON paths.source_id = multipart_uploads.source_id
+            WHERE multipart_uploads.upload_id = ?
+            AND paths.migrated < 1 AND paths.migrated != -2
+            AND (paths.skip < 1 OR paths.source_id IN (SELECT source_id FROM failures));",
+        &[upload_id.into()],
+    )?;
+    Ok(count > 0)
+}
+
+/// Every key the files of this database are archived, or would be archived, under: each
+/// file's own key, its bundle's, the original's it is a duplicate of, and the keys of the
+/// multipart uploads started for it.
+pub fn get_object_keys(connection: &DBConnection) -> Result<HashSet<String>> {
+    let mut keys = HashSet::new();
+    for row in connection
+        .prepare("SELECT source_kind, source_path, s3_key FROM paths;")?
+        .into_iter()
+    {
+        let row = row?;
+        keys.insert(match row.try_read::<Option<&str>, _>("s3_key")? {
+            Some(s3_key) => s3_key.to_string(),
+            None => util::standardize_path(
+                row.try_read::<&str, _>("source_kind")?,
+                row.try_read::<&str, _>("source_path")?,
+            )?,
+        });
+    }
+    for query in [
+        "SELECT bundle_key FROM bundle_members;",
+        "SELECT s3_key FROM duplicates;",
+        "SELECT s3_key FROM multipart_uploads;",
+    ] {
+        for row in connection.prepare(query)?.into_iter() {
+            keys.insert(row?.try_read::<&str, _>(0)?.to_string());
+        }
+    }
+    Ok(keys)
+}
+
+/// Drops whatever the database remembers about `upload_id` once it has been aborted.
+pub fn forget_multipart_upload(connection: &DBConnection, upload_id: &str) -> Result<()> {
+    execute(
+        connection,
+        "DELETE FROM multipart_parts WHERE source_id IN
+            (SELECT source_id FROM multipart_uploads WHERE upload_id = ?);",
+        &[upload_id.into()],
+    )?;
+    execute(
+        connection,
+        "DELETE FROM multipart_uploads WHERE upload_id = ?;",
+        &[upload_id.into()],
+    )
+}
+
+pub fn get_total_size(connection: &DBConnection) -> Result<i64> {
+    read_i64(connection, "SELECT SUM(dropbox_size) FROM paths", &[])
+}
+
+pub fn get_migrated_size(connection: &DBConnection) -> Result<i64> {
+    read_i64(
+        connection,
+        "SELECT SUM(dropbox_size) FROM paths WHERE migrated = 1",
+        &[],
+    )
+}
+
+pub fn get_unmigrated_size(connection: &DBConnection) -> Result<i64> {
+    read_i64(
+        connection,
+        "SELECT SUM(dropbox_size) FROM paths WHERE migrated < 1 AND deleted < 1",
+        &[],
+    )
+}
+
+/// A file still to migrate that is small enough to go into a tar bundle.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SmallFile {
+    pub source_id: String,
+    pub source_path: String,
+    pub dropbox_hash: String,
+    pub dropbox_size: i64,
+    /// When the file was last modified, for sources that report it.
+    pub source_mtime: Option<i64>,
+}
+
+/// Files under `threshold` bytes that no worker or bundle has taken on yet, in path
+/// order so files of the same folder come together.
+pub fn get_bundle_candidates(connection: &DBConnection, threshold: i64) -> Result<Vec<SmallFile>> {
+    connection
+        .prepare(
+            "SELECT source_id, source_path, dropbox_hash, dropbox_size, source_mtime FROM paths
+                WHERE migrated < 1 AND skip < 1 AND deleted < 1 AND dropbox_size < ?
+                AND source_id NOT IN (SELECT source_id FROM bundle_members)
+                ORDER BY source_path",
+        )?
+        .into_iter()
+        .bind((1, threshold))?
+        .map(|row| {
+            let row = row?;
+            Ok(SmallFile {
+                source_id: row.try_read::<&str, _>(0)?.to_string(),
+                source_path: row.try_read::<&str, _>(1)?.to_string(),
+                dropbox_hash: row.try_read::<&str, _>(2)?.to_string(),
+                dropbox_size: row.try_read::<i64, _>(3)?,
+                source_mtime: row.try_read::<Option<i64>, _>(4)?,
+            })
+        })
+        .collect()
+}
+
+/// Records where each `(source_id, offset, length)` sits in the tar at `bundle_key`,
+/// before the bundle is uploaded. The files stay unmigrated until `set_bundle_migrated`,
+/// so a bundle lost to a crash is found by the usual migration status check.
+pub fn insert_bundle_members(
+    connection: &DBConnection,
+    bundle_key: &str,
+    members: &[(String, u64, u64)],
+) -> Result<()> {
+    transaction(connection, || {
+        for (source_id, offset, length) in members {
+            execute(
+                connection,
+                "INSERT OR REPLACE INTO bundle_members (source_id, bundle_key, offset, length) VALUES (?, ?, ?, ?);",
+                &[
+                    source_id.as_str().into(),
+                    bundle_key.into(),
+                    (*offset as i64).into(),
+                    (*length as i64).into(),
+                ],
+            )?;
+        }
+        Ok(())
+    })
+}
+
+/// Marks every file in the bundle migrated, with the bundle's checksum as their `s3_hash`.
+pub fn set_bundle_migrated(
+    connection: &DBConnection,
+    bundle_key: &str,
+    s3_hash: &str,
+) -> Result<()> {
+    let members = "SELECT source_id FROM bundle_members WHERE bundle_key = ?1";
+    transaction(connection, || {
+        execute(
+            connection,
+            &format!("UPDATE paths SET migrated = 1, s3_hash = ?2 WHERE source_id IN ({members});"),
+            &[bundle_key.into(), s3_hash.into()],
+        )?;
+        execute(
+            connection,
+            &format!("DELETE FROM failures WHERE source_id IN ({members});"),
+            &[bundle_key.into()],
+        )?;
+        execute(
+            connection,
+            &format!("DELETE FROM interruptions WHERE source_id IN ({members});"),
+            &[bundle_key.into()],
+        )
+    })?;
+    println!("🪺  Migrated bundle: {bundle_key}");
+    Ok(())
+}
+
+/// The `(bundle_key, offset, length)` of a bundled file.
+pub fn get_bundle_member(
+    connection: &DBConnection,
+    source_id: &str,
+) -> Result<Option<(String, i64, i64)>> {
     match connection
-        .prepare("SELECT SUM(dropbox_size) FROM paths")
-        .unwrap()
+        .prepare("SELECT bundle_key, offset, length FROM bundle_members WHERE source_id = ?;")?
         .into_iter()
-        .map(|row| row.unwrap())
-        .map(|row| row.read::<i64, _>(0))
+        .bind((1, source_id))?
         .next()
     {
-        Some(size) => size as i64,
-        None => 0,
+        Some(row) => {
+            let row = row?;
+            Ok(Some((
+                row.try_read::<&str, _>(0)?.to_string(),
+                row.try_read::<i64, _>(1)?,
+                row.try_read::<i64, _>(2)?,
+            )))
+        }
+        None => Ok(None),
     }
 }
 
-pub fn get_migrated_size(connection: &DBConnection) -> i64 {
+/// Forgets that a file is in a bundle, once it has been archived on its own or its
+/// bundle turned out to be missing.
+pub fn delete_bundle_member(connection: &DBConnection, source_id: &str) -> Result<()> {
+    execute(
+        connection,
+        "DELETE FROM bundle_members WHERE source_id = ?;",
+        &[source_id.into()],
+    )
+}
+
+/// How the object at `s3_key` was encrypted, as `crypto::Envelope` records it. The
+/// data key is only ever stored wrapped by the master key named by `key_id`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EncryptedObject {
+    pub s3_key: String,
+    pub key_id: String,
+    pub scheme: String,
+    pub wrapped_key: String,
+    /// Hex encoded.
+    pub nonce_prefix: String,
+    pub plaintext_size: i64,
+}
+
+pub fn insert_encrypted_object(connection: &DBConnection, object: &EncryptedObject) -> Result<()> {
+    execute(
+        connection,
+        "INSERT OR REPLACE INTO encrypted_objects (s3_key, key_id, scheme, wrapped_key, nonce_prefix, plaintext_size) VALUES (?, ?, ?, ?, ?, ?);",
+        &[
+            object.s3_key.as_str().into(),
+            object.key_id.as_str().into(),
+            object.scheme.as_str().into(),
+            object.wrapped_key.as_str().into(),
+            object.nonce_prefix.as_str().into(),
+            object.plaintext_size.into(),
+        ],
+    )
+}
+
+pub fn get_encrypted_object(
+    connection: &DBConnection,
+    s3_key: &str,
+) -> Result<Option<EncryptedObject>> {
     match connection
-        .prepare("SELECT SUM(dropbox_size) FROM paths WHERE migrated = 1")
-        .unwrap()
+        .prepare("SELECT key_id, scheme, wrapped_key, nonce_prefix, plaintext_size FROM encrypted_objects WHERE s3_key = ?;")?
         .into_iter()
-        .map(|row| row.unwrap().try_read::<i64, _>(0))
+        .bind((1, s3_key))?
         .next()
-        .unwrap()
     {
-        Ok(size) => size,
-        Err(_) => 0,
+        Some(row) => {
+            let row = row?;
+            Ok(Some(EncryptedObject {
+                s3_key: s3_key.to_string(),
+                key_id: row.try_read::<&str, _>(0)?.to_string(),
+                scheme: row.try_read::<&str, _>(1)?.to_string(),
+                wrapped_key: row.try_read::<&str, _>(2)?.to_string(),
+                nonce_prefix: row.try_read::<&str, _>(3)?.to_string(),
+                plaintext_size: row.try_read::<i64, _>(4)?,
+            }))
+        }
+        None => Ok(None),
     }
 }
 
-pub fn get_unmigrated_size(connection: &DBConnection) -> i64 {
+/// Forgets the encryption of `s3_key`, once a plain object is uploaded there instead.
+pub fn delete_encrypted_object(connection: &DBConnection, s3_key: &str) -> Result<()> {
+    execute(
+        connection,
+        "DELETE FROM encrypted_objects WHERE s3_key = ?;",
+        &[s3_key.into()],
+    )
+}
+
+/// An archived file with the same `content_hash` and size as `source_id`, whose own
+/// object (not a bundle, nor another file's) can stand in for it.
+pub fn find_canonical(connection: &DBConnection, source_id: &str) -> Result<Option<DBRow>> {
     match connection
-        .prepare("SELECT SUM(dropbox_size) FROM paths WHERE migrated < 1")
-        .unwrap()
+        .prepare(
+            "SELECT * FROM paths WHERE migrated = 1 AND deleted < 1 AND s3_hash IS NOT NULL
+                AND source_id != ?1 AND dropbox_hash != ''
+                AND (dropbox_hash, dropbox_size) =
+                    (SELECT dropbox_hash, dropbox_size FROM paths WHERE source_id = ?1)
+                AND source_id NOT IN (SELECT source_id FROM bundle_members)
+                AND source_id NOT IN (SELECT source_id FROM duplicates)
+                ORDER BY rowid LIMIT 1;",
+        )?
         .into_iter()
-        .map(|row| row.unwrap().try_read::<i64, _>(0))
+        .bind((1, source_id))?
         .next()
-        .unwrap()
     {
-        Ok(size) => size,
-        Err(_) => 0,
+        Some(row) => Ok(Some(row?)),
+        None => Ok(None),
     }
 }
 
-pub fn get_dropbox_size(connection: &DBConnection, dropbox_id: &str) -> i64 {
-    let query = format!("SELECT dropbox_size FROM paths WHERE dropbox_id = '{dropbox_id}';");
-    connection
-        .prepare(&query)
-        .unwrap()
+/// Marks `source_id` migrated as a reference to `s3_key`, the object of `canonical_id`,
+/// with that object's checksum as its `s3_hash`.
+pub fn set_duplicate(
+    connection: &DBConnection,
+    source_id: &str,
+    (canonical_id, s3_key): (&str, &str),
+    s3_hash: &str,
+) -> Result<()> {
+    transaction(connection, || {
+        execute(
+            connection,
+            "INSERT OR REPLACE INTO duplicates (source_id, canonical_id, s3_key) VALUES (?, ?, ?);",
+            &[source_id.into(), canonical_id.into(), s3_key.into()],
+        )?;
+        execute(
+            connection,
+            "UPDATE paths SET s3_hash = ?, compression = NULL, compressed_size = NULL WHERE source_id = ?;",
+            &[s3_hash.into(), source_id.into()],
+        )
+    })?;
+    set_migrated(connection, source_id)
+}
+
+/// The key of the object a deduplicated file refers to.
+pub fn get_duplicate(connection: &DBConnection, source_id: &str) -> Result<Option<String>> {
+    match connection
+        .prepare("SELECT s3_key FROM duplicates WHERE source_id = ?;")?
         .into_iter()
-        .map(|row| row.unwrap())
-        .map(|row| row.read::<i64, _>(0))
+        .bind((1, source_id))?
         .next()
-        .unwrap()
+    {
+        Some(row) => Ok(Some(row?.try_read::<&str, _>(0)?.to_string())),
+        None => Ok(None),
+    }
+}
+
+pub fn delete_duplicate(connection: &DBConnection, source_id: &str) -> Result<()> {
+    execute(
+        connection,
+        "DELETE FROM duplicates WHERE source_id = ?;",
+        &[source_id.into()],
+    )
+}
+
+/// Sends the duplicates of `canonical_id` back to be migrated, once its object no longer
+/// holds their content.
+fn release_duplicates_of(connection: &DBConnection, canonical_id: &str) -> Result<()> {
+    execute(
+        connection,
+        "UPDATE paths SET migrated = 0 WHERE migrated = 1
+            AND source_id IN (SELECT source_id FROM duplicates WHERE canonical_id = ?1);",
+        &[canonical_id.into()],
+    )?;
+    execute(
+        connection,
+        "DELETE FROM duplicates WHERE canonical_id = ?;",
+        &[canonical_id.into()],
+    )
+}
+
+/// An archived file picked for `restore`, with what it takes to find its bytes in S3.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ArchivedFile {
+    pub source_id: String,
+    pub source_path: String,
+    pub source_kind: String,
+    pub dropbox_hash: String,
+    pub dropbox_size: i64,
+    /// The key recorded when the file moved under `DeletionPolicy::Keep`.
+    pub s3_key: Option<String>,
+    /// The key of the identical file's object, for a deduplicated file.
+    pub duplicate_key: Option<String>,
+    /// The `(bundle_key, offset, length)` of a bundled file.
+    pub bundle: Option<(String, i64, i64)>,
+}
+
+/// Archived files matching `pattern`, compared without regard to case: a file's path,
+/// a folder's path (for every file under it), or a glob (`*`, `?` and `[...]`, where `*`
+/// also matches `/`).
+pub fn find_archived(connection: &DBConnection, pattern: &str) -> Result<Vec<ArchivedFile>> {
+    let matches = match pattern.contains(['*', '?', '[']) {
+        true => "lower(source_path) GLOB lower(?1)",
+        false => {
+            "(lower(source_path) = lower(?1)
+            OR substr(lower(source_path), 1, length(?1) + 1) = lower(?1) || '/')"
+        }
+    };
+    connection
+        .prepare(format!(
+            "SELECT paths.source_id, source_path, dropbox_hash, dropbox_size, paths.s3_key,
+                duplicates.s3_key, bundle_key, offset, length, source_kind
+                FROM paths
+                LEFT JOIN duplicates ON duplicates.source_id = paths.source_id
+                LEFT JOIN bundle_members ON bundle_members.source_id = paths.source_id
+                WHERE migrated = 1 AND {matches}
+                ORDER BY source_path;"
+        ))?
+        .into_iter()
+        .bind((1, pattern.trim_end_matches('/')))?
+        .map(|row| {
+            let row = row?;
+            let bundle = match row.try_read::<Option<&str>, _>(6)? {
+                Some(bundle_key) => Some((
+                    bundle_key.to_string(),
+                    row.try_read::<i64, _>(7)?,
+                    row.try_read::<i64, _>(8)?,
+                )),
+                None => None,
+            };
+            Ok(ArchivedFile {
+                source_id: row.try_read::<&str, _>(0)?.to_string(),
+                source_path: row.try_read::<&str, _>(1)?.to_string(),
+                source_kind: row.try_read::<&str, _>(9)?.to_string(),
+                dropbox_hash: row.try_read::<&str, _>(2)?.to_string(),
+                dropbox_size: row.try_read::<i64, _>(3)?,
+                s3_key: row.try_read::<Option<&str>, _>(4)?.map(str::to_string),
+                duplicate_key: row.try_read::<Option<&str>, _>(5)?.map(str::to_string),
+                bundle,
+            })
+        })
+        .collect()
+}
+
+pub fn record_restore_request(
+    connection: &DBConnection,
+    s3_key: &str,
+    (tier, days): (&str, i32),
+) -> Result<()> {
+    execute(
+        connection,
+        "INSERT OR REPLACE INTO restores (s3_key, tier, days) VALUES (?, ?, ?);",
+        &[s3_key.into(), tier.into(), (days as i64).into()],
+    )
+}
+
+/// Notes that the restored copy of `s3_key` is readable, the first time it is seen.
+pub fn set_restored(connection: &DBConnection, s3_key: &str) -> Result<()> {
+    execute(
+        connection,
+        "UPDATE restores SET restored_at = CURRENT_TIMESTAMP
+            WHERE s3_key = ? AND restored_at IS NULL;",
+        &[s3_key.into()],
+    )
+}
+
+pub fn set_restore_downloaded(connection: &DBConnection, s3_key: &str) -> Result<()> {
+    execute(
+        connection,
+        "UPDATE restores SET downloaded_at = CURRENT_TIMESTAMP WHERE s3_key = ?;",
+        &[s3_key.into()],
+    )
+}
+
+/// The path and size of every file still to migrate, for `plan`.
+pub fn get_unmigrated_files(connection: &DBConnection) -> Result<Vec<(String, i64)>> {
+    connection
+        .prepare(
+            "SELECT source_path, dropbox_size FROM paths
+                WHERE migrated < 1 AND deleted < 1 ORDER BY source_path",
+        )?
+        .into_iter()
+        .map(|row| {
+            let row = row?;
+            Ok((
+                row.try_read::<&str, _>(0)?.to_string(),
+                row.try_read::<i64, _>(1)?,
+            ))
+        })
+        .collect()
+}
+
+pub fn get_dropbox_size(connection: &DBConnection, source_id: &str) -> Result<i64> {
+    read_i64(
+        connection,
+        "SELECT dropbox_size FROM paths WHERE source_id = ?;",
+        &[source_id.into()],
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    fn insert_test_paths(sqlite: &crate::db::DBConnection) {
+        sqlite
+            .execute(
+                "INSERT INTO paths (source_id, source_path, dropbox_size, dropbox_hash, migrated, skip) VALUES
+                    ('id:a', '/a.txt', 1, 'hash-a', -1, 0),
+                    ('id:b', '/b.txt', 1, 'hash-b', 0, 0),
+                    ('id:c', '/c.txt', 1, 'hash-c', 1, 0),
+                    ('id:d', '/d.txt', 1, 'hash-d', 0, 1);",
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn it_refuses_files_from_another_source() {
+        let sqlite = crate::db::connect(":memory:").unwrap();
+        crate::db::check_source_kind(&sqlite, "local").unwrap();
+        insert_test_paths(&sqlite);
+        crate::db::check_source_kind(&sqlite, "dropbox").unwrap();
+        assert!(crate::db::check_source_kind(&sqlite, "local").is_err());
+    }
+
+    #[test]
+    fn it_claims_each_row_once_per_pass() {
+        let sqlite = crate::db::connect(":memory:").unwrap();
+        insert_test_paths(&sqlite);
+        let mut claimed = Vec::new();
+        while let Some(row) = crate::db::claim_next_path(&sqlite).unwrap() {
+            claimed.push(row.read::<&str, _>("source_id").to_string());
+        }
+        assert_eq!(claimed, vec!["id:a", "id:b"]);
+        crate::db::release_claims(&sqlite).unwrap();
+        assert!(crate::db::claim_next_path(&sqlite).unwrap().is_some());
+    }
+
+    #[test]
+    fn it_migrates_databases_from_before_schema_version() {
+        let dbpath = std::env::temp_dir().join("deep-freeze-legacy.sqlite");
+        std::fs::remove_file(&dbpath).ok();
+        let legacy = sqlite::open(&dbpath).unwrap();
+        legacy.execute(crate::db::MIGRATIONS[0]).unwrap();
+        legacy
+            .execute(
+                "INSERT INTO paths (dropbox_id, dropbox_path, dropbox_size, dropbox_hash, migrated) VALUES
+                    ('id:a', '/a.txt', 1, 'hash-a', 1),
+                    ('id:b', '/b.txt', 1, 'hash-b', 0);",
+            )
+            .unwrap();
+        drop(legacy);
+
+        let dbpath = dbpath.to_str().unwrap();
+        let sqlite = crate::db::connect(dbpath).unwrap();
+        assert_eq!(
+            crate::db::schema_version(&sqlite).unwrap(),
+            crate::db::MIGRATIONS.len() as i64
+        );
+        assert_eq!(crate::db::count_migrated(&sqlite).unwrap(), 1);
+        crate::db::set_s3_hash(&sqlite, "id:a", "same").unwrap();
+        crate::db::set_s3_hash(&sqlite, "id:b", "same").unwrap();
+        sqlite
+            .execute("UPDATE schema_version SET version = 99;")
+            .unwrap();
+        drop(sqlite);
+
+        assert!(matches!(
+            crate::db::connect(dbpath),
+            Err(crate::error::DeepFreezeError::NewerSchema { found: 99, .. })
+        ));
+        std::fs::remove_file(dbpath).unwrap();
+    }
+
+    #[test]
+    fn it_stores_source_paths_exactly() {
+        let sqlite = crate::db::connect(":memory:").unwrap();
+        let path = r#"/It's a "quoted" folder/O'Brien; DROP TABLE paths;.txt"#;
+        let page = serde_json::json!({"entries": [
+            {".tag": "file", "id": "id:q", "path_display": path, "size": 3, "content_hash": "hash-q"},
+            {".tag": "folder", "id": "id:f", "path_display": "/folder"},
+        ]});
+        let entries = crate::json::get_source_entries(&page).unwrap();
+        crate::db::insert_source_entries(&sqlite, "dropbox", &entries).unwrap();
+        crate::db::insert_source_entries(&sqlite, "dropbox", &entries).unwrap();
+        assert_eq!(crate::db::count_rows(&sqlite).unwrap(), 1);
+        let row = crate::db::claim_next_path(&sqlite).unwrap().unwrap();
+        assert_eq!(row.read::<&str, _>("source_path"), path);
+        assert_eq!(row.read::<&str, _>("source_kind"), "dropbox");
+        assert_eq!(crate::db::get_dropbox_size(&sqlite, "id:q").unwrap(), 3);
+    }
+
+    #[test]
+    fn it_queues_files_changed_in_dropbox() {
+        let sqlite = crate::db::connect(":memory:").unwrap();
+        insert_test_paths(&sqlite);
+        crate::db::insert_multipart_upload(&sqlite, "id:c", "c.txt", "upload-c").unwrap();
+        let entries = [
+            serde_json::json!({".tag": "file", "id": "id:b", "path_display": "/b.txt", "size": 1, "content_hash": "hash-b"}),
+            serde_json::json!({".tag": "file", "id": "id:c", "path_display": "/c.txt", "size": 2, "content_hash": "hash-c2"}),
+            serde_json::json!({".tag": "file", "id": "id:e", "path_display": "/e.txt", "size": 1, "content_hash": "hash-e"}),
+        ];
+        let mut summary = crate::db::SyncSummary::default();
+        crate::db::sync_dropbox_paths(
+            &sqlite,
+            &entries,
+            (crate::db::DeletionPolicy::Keep, "/Archive"),
+            &mut summary,
+        )
+        .unwrap();
+        assert_eq!((summary.added, summary.changed), (1, 1));
+        assert_eq!(crate::db::get_dropbox_size(&sqlite, "id:c").unwrap(), 2);
+        assert_eq!(
+            crate::db::get_multipart_upload(&sqlite, "id:c").unwrap(),
+            None
+        );
+        crate::db::set_unmigrated(&sqlite, "id:c").unwrap();
+        let mut claimed = Vec::new();
+        while let Some(row) = crate::db::claim_next_path(&sqlite).unwrap() {
+            claimed.push((
+                row.read::<&str, _>("source_id").to_string(),
+                row.read::<i64, _>("migrated"),
+            ));
+        }
+        assert_eq!(
+            claimed,
+            vec![
+                ("id:a".to_string(), -1),
+                ("id:b".to_string(), 0),
+                ("id:c".to_string(), -3),
+                ("id:e".to_string(), -1)
+            ]
+        );
+
+        assert_eq!(crate::db::get_cursor(&sqlite).unwrap(), None);
+        crate::db::set_cursor(&sqlite, "cursor-1").unwrap();
+        crate::db::set_cursor(&sqlite, "cursor-2").unwrap();
+        assert_eq!(
+            crate::db::get_cursor(&sqlite).unwrap(),
+            Some("cursor-2".to_string())
+        );
+    }
+
+    #[test]
+    fn it_follows_moves_and_deletions() {
+        let sync = |entries: &[serde_json::Value], policy| {
+            let sqlite = crate::db::connect(":memory:").unwrap();
+            insert_test_paths(&sqlite);
+            sqlite
+                .execute("INSERT INTO paths (source_id, source_path, dropbox_size, dropbox_hash, migrated) VALUES ('id:e', '/Dir/e.txt', 1, 'hash-e', 1);")
+                .unwrap();
+            let mut summary = crate::db::SyncSummary::default();
+            crate::db::sync_dropbox_paths(&sqlite, entries, (policy, "/Archive"), &mut summary)
+                .unwrap();
+            (sqlite, summary)
+        };
+        let read = |sqlite: &crate::db::DBConnection, source_id: &str| {
+            let row = sqlite
+                .prepare("SELECT * FROM paths WHERE source_id = ?;")
+                .unwrap()
+                .into_iter()
+                .bind((1, source_id))
+                .unwrap()
+                .next()
+                .unwrap()
+                .unwrap();
+            (
+                row.read::<&str, _>("source_path").to_string(),
+                row.read::<i64, _>("migrated"),
+                row.read::<Option<&str>, _>("s3_key").map(str::to_string),
+                row.read::<i64, _>("deleted"),
+            )
+        };
+        let moves = [
+            serde_json::json!({".tag": "file", "id": "id:b", "path_display": "/b2.txt", "size": 1, "content_hash": "hash-b"}),
+            serde_json::json!({".tag": "file", "id": "id:c", "path_display": "/c2.txt", "size": 1, "content_hash": "hash-c"}),
+        ];
+
+        let (sqlite, summary) = sync(&moves, crate::db::DeletionPolicy::Keep);
+        assert_eq!(summary.moved, 2);
+        assert_eq!(read(&sqlite, "id:b"), ("/b2.txt".to_string(), 0, None, 0));
+        assert_eq!(
+            read(&sqlite, "id:c"),
+            ("/c2.txt".to_string(), 1, Some("/c.txt".to_string()), 0)
+        );
+
+        let (sqlite, summary) = sync(&moves, crate::db::DeletionPolicy::Tag);
+        assert_eq!(read(&sqlite, "id:c"), ("/c2.txt".to_string(), -3, None, 0));
+        assert_eq!(summary.removed_keys, vec!["/c.txt"]);
+
+        let deletions = [
+            serde_json::json!({".tag": "deleted", "path_lower": "/a.txt", "path_display": "/a.txt"}),
+            serde_json::json!({".tag": "deleted", "path_lower": "/dir", "path_display": "/Dir"}),
+        ];
+        let (sqlite, summary) = sync(&deletions, crate::db::DeletionPolicy::Tag);
+        assert_eq!(summary.deleted, 2);
+        assert_eq!(summary.removed_keys, vec!["/Dir/e.txt"]);
+        assert_eq!(read(&sqlite, "id:e").3, 1);
+        assert_eq!(crate::db::count_deleted(&sqlite).unwrap(), 2);
+        assert_eq!(crate::db::count_pending(&sqlite).unwrap(), 1);
+
+        let restore = [
+            serde_json::json!({".tag": "file", "id": "id:a", "path_display": "/a.txt", "size": 1, "content_hash": "hash-a"}),
+        ];
+        let mut summary = crate::db::SyncSummary::default();
+        crate::db::sync_dropbox_paths(
+            &sqlite,
+            &restore,
+            (crate::db::DeletionPolicy::Tag, "/Archive"),
+            &mut summary,
+        )
+        .unwrap();
+        assert_eq!(read(&sqlite, "id:a").3, 0);
+        assert_eq!(crate::db::count_pending(&sqlite).unwrap(), 2);
+    }
+
+    #[test]
+    fn it_records_failures_and_skips_the_row() {
+        let sqlite = crate::db::connect(":memory:").unwrap();
+        insert_test_paths(&sqlite);
+        assert_eq!(crate::db::count_pending(&sqlite).unwrap(), 2);
+        crate::db::record_failure(&sqlite, "id:a", "S3 error: it's down").unwrap();
+        assert_eq!(crate::db::count_failures(&sqlite).unwrap(), 1);
+        assert_eq!(crate::db::count_pending(&sqlite).unwrap(), 1);
+        assert_eq!(crate::db::retry_failures(&sqlite).unwrap(), 1);
+        assert_eq!(crate::db::count_pending(&sqlite).unwrap(), 2);
+        assert_eq!(crate::db::count_failures(&sqlite).unwrap(), 1);
+        crate::db::set_migrated(&sqlite, "id:a").unwrap();
+        assert_eq!(crate::db::count_failures(&sqlite).unwrap(), 0);
+    }
+
+    #[test]
+    fn it_records_the_hash_of_a_file_listed_without_one() {
+        let sqlite = crate::db::connect(":memory:").unwrap();
+        insert_test_paths(&sqlite);
+        sqlite
+            .execute("UPDATE paths SET dropbox_hash = '' WHERE source_id IN ('id:a', 'id:b');")
+            .unwrap();
+        assert!(crate::db::find_canonical(&sqlite, "id:a")
+            .unwrap()
+            .is_none());
+        crate::db::check_content_hash(&sqlite, "id:a", ("", "hash-a".to_string())).unwrap();
+        crate::db::check_content_hash(&sqlite, "id:a", ("hash-a", "hash-a".to_string())).unwrap();
+        assert!(
+            crate::db::check_content_hash(&sqlite, "id:a", ("hash-a", "other".to_string()))
+                .is_err()
+        );
+        let row = sqlite
+            .prepare("SELECT dropbox_hash FROM paths WHERE source_id = 'id:a';")
+            .unwrap()
+            .into_iter()
+            .next()
+            .unwrap()
+            .unwrap();
+        assert_eq!(row.read::<&str, _>("dropbox_hash"), "hash-a");
+    }
+
+    #[test]
+    fn it_records_bundled_files() {
+        let sqlite = crate::db::connect(":memory:").unwrap();
+        insert_test_paths(&sqlite);
+        let candidates = crate::db::get_bundle_candidates(&sqlite, 2).unwrap();
+        assert_eq!(candidates.len(), 2);
+        let members = [("id:a".to_string(), 512, 1), ("id:b".to_string(), 1536, 1)];
+        crate::db::insert_bundle_members(&sqlite, "bundle.tar", &members).unwrap();
+        assert!(crate::db::get_bundle_candidates(&sqlite, 2)
+            .unwrap()
+            .is_empty());
+        assert_eq!(crate::db::count_bundled(&sqlite).unwrap(), (0, 0));
+        crate::db::set_bundle_migrated(&sqlite, "bundle.tar", "sum").unwrap();
+        assert_eq!(crate::db::count_bundled(&sqlite).unwrap(), (2, 1));
+        assert_eq!(
+            crate::db::get_bundle_member(&sqlite, "id:b").unwrap(),
+            Some(("bundle.tar".to_string(), 1536, 1))
+        );
+
+        let deletion = [
+            serde_json::json!({".tag": "deleted", "path_lower": "/a.txt", "path_display": "/a.txt"}),
+        ];
+        let mut summary = crate::db::SyncSummary::default();
+        crate::db::sync_dropbox_paths(
+            &sqlite,
+            &deletion,
+            (crate::db::DeletionPolicy::Tag, "/Archive"),
+            &mut summary,
+        )
+        .unwrap();
+        assert_eq!(summary.deleted, 1);
+        assert!(summary.removed_keys.is_empty());
+    }
+
+    #[test]
+    fn it_references_duplicates_until_the_original_changes() {
+        let sqlite = crate::db::connect(":memory:").unwrap();
+        insert_test_paths(&sqlite);
+        sqlite
+            .execute(
+                "UPDATE paths SET dropbox_hash = 'same', dropbox_size = 10;
+                UPDATE paths SET s3_hash = 'sum-c' WHERE source_id = 'id:c';",
+            )
+            .unwrap();
+        let canonical = crate::db::find_canonical(&sqlite, "id:a").unwrap().unwrap();
+        assert_eq!(canonical.read::<&str, _>("source_id"), "id:c");
+        assert!(crate::db::find_canonical(&sqlite, "id:c")
+            .unwrap()
+            .is_none());
+
+        crate::db::set_duplicate(&sqlite, "id:a", ("id:c", "/c.txt"), "sum-c").unwrap();
+        assert_eq!(
+            crate::db::get_duplicate(&sqlite, "id:a").unwrap(),
+            Some("/c.txt".to_string())
+        );
+        assert_eq!(crate::db::count_deduplicated(&sqlite).unwrap(), (1, 10));
+        // A duplicate never stands in for another file.
+        let canonical = crate::db::find_canonical(&sqlite, "id:b").unwrap().unwrap();
+        assert_eq!(canonical.read::<&str, _>("source_id"), "id:c");
+
+        let change = [serde_json::json!({
+            ".tag": "file", "id": "id:c", "path_display": "/c.txt",
+            "content_hash": "new", "size": 11
+        })];
+        let mut summary = crate::db::SyncSummary::default();
+        crate::db::sync_dropbox_paths(
+            &sqlite,
+            &change,
+            (crate::db::DeletionPolicy::Tag, "/Archive"),
+            &mut summary,
+        )
+        .unwrap();
+        assert_eq!(crate::db::get_duplicate(&sqlite, "id:a").unwrap(), None);
+        assert_eq!(crate::db::count_deduplicated(&sqlite).unwrap(), (0, 0));
+        assert_eq!(crate::db::count_pending(&sqlite).unwrap(), 3);
+    }
+
+    #[test]
+    fn it_finds_archived_files_by_path_folder_or_glob() {
+        let sqlite = crate::db::connect(":memory:").unwrap();
+        sqlite
+            .execute(
+                "INSERT INTO paths (source_id, source_path, dropbox_size, dropbox_hash, migrated, skip) VALUES
+                    ('id:a', '/Projects/2019/a.txt', 1, 'hash-a', 1, 0),
+                    ('id:b', '/Projects/2019/Drafts/b.txt', 1, 'hash-b', 1, 0),
+                    ('id:c', '/Projects/2019-old/c.txt', 1, 'hash-c', 1, 0),
+                    ('id:d', '/Projects/2019/d.txt', 1, 'hash-d', 0, 0);",
+            )
+            .unwrap();
+        crate::db::insert_bundle_members(&sqlite, "bundle.tar", &[("id:b".to_string(), 512, 1)])
+            .unwrap();
+        let ids = |pattern: &str| {
+            crate::db::find_archived(&sqlite, pattern)
+                .unwrap()
+                .into_iter()
+                .map(|file| file.source_id)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(ids("/projects/2019/"), vec!["id:b", "id:a"]);
+        assert_eq!(ids("/Projects/2019/a.txt"), vec!["id:a"]);
+        assert_eq!(ids("/Projects/2019*/*.txt"), vec!["id:c", "id:b", "id:a"]);
+        let bundled = crate::db::find_archived(&sqlite, "/Projects/2019/Drafts/b.txt").unwrap();
+        assert_eq!(bundled[0].bundle, Some(("bundle.tar".to_string(), 512, 1)));
+    }
+
+    #[test]
+    fn it_records_compressed_sizes() {
+        let sqlite = crate::db::connect(":memory:").unwrap();
+        insert_test_paths(&sqlite);
+        sqlite
+            .execute("UPDATE paths SET dropbox_size = 100 WHERE source_id = 'id:a';")
+            .unwrap();
+        crate::db::set_compression(&sqlite, "id:a", Some(("zstd", 40))).unwrap();
+        assert_eq!(crate::db::get_archived_size(&sqlite, "id:a").unwrap(), 40);
+        assert_eq!(crate::db::count_compressed(&sqlite).unwrap(), (0, 0));
+        crate::db::set_migrated(&sqlite, "id:a").unwrap();
+        assert_eq!(crate::db::count_compressed(&sqlite).unwrap(), (1, 60));
+        crate::db::set_compression(&sqlite, "id:a", None).unwrap();
+        assert_eq!(crate::db::get_archived_size(&sqlite, "id:a").unwrap(), 100);
+    }
+
+    #[test]
+    fn it_records_encrypted_objects() {
+        let sqlite = crate::db::connect(":memory:").unwrap();
+        let object = crate::db::EncryptedObject {
+            s3_key: "a.txt".to_string(),
+            key_id: "key-file:0123456789abcdef".to_string(),
+            scheme: "aes-256-gcm-stream-64k".to_string(),
+            wrapped_key: "00ff".to_string(),
+            nonce_prefix: "01020304050607".to_string(),
+            plaintext_size: 3,
+        };
+        crate::db::insert_encrypted_object(&sqlite, &object).unwrap();
+        assert_eq!(
+            crate::db::get_encrypted_object(&sqlite, "a.txt").unwrap(),
+            Some(object)
+        );
+        assert_eq!(crate::crypto::stored_size(&sqlite, "a.txt", 3).unwrap(), 19);
+        crate::db::delete_encrypted_object(&sqlite, "a.txt").unwrap();
+        assert_eq!(crate::crypto::stored_size(&sqlite, "a.txt", 3).unwrap(), 3);
+    }
+
+    #[test]
+    fn it_records_interruptions_without_skipping_the_row() {
+        let sqlite = crate::db::connect(":memory:").unwrap();
+        insert_test_paths(&sqlite);
+        crate::db::record_interruption(&sqlite, "id:a", "2 of 5 parts uploaded").unwrap();
+        assert_eq!(crate::db::count_interruptions(&sqlite).unwrap(), 1);
+        assert_eq!(crate::db::count_pending(&sqlite).unwrap(), 2);
+        crate::db::set_migrated(&sqlite, "id:a").unwrap();
+        assert_eq!(crate::db::count_interruptions(&sqlite).unwrap(), 0);
+    }
+
+    #[test]
+    fn it_records_multipart_progress() {
+        let sqlite = crate::db::connect(":memory:").unwrap();
+        crate::db::insert_multipart_upload(&sqlite, "id:a", "a.txt", "upload-1").unwrap();
+        crate::db::insert_multipart_part(&sqlite, "id:a", 2, "\"etag-2\"", "sum-2").unwrap();
+        crate::db::insert_multipart_part(&sqlite, "id:a", 1, "\"etag-1\"", "sum-1").unwrap();
+        assert_eq!(
+            crate::db::get_multipart_upload(&sqlite, "id:a").unwrap(),
+            Some(("a.txt".to_string(), "upload-1".to_string()))
+        );
+        let parts = crate::db::get_multipart_parts(&sqlite, "id:a").unwrap();
+        assert_eq!(
+            parts.iter().map(|part| part.0).collect::<Vec<_>>(),
+            vec![1, 2]
+        );
+        crate::db::insert_multipart_upload(&sqlite, "id:a", "a.txt", "upload-2").unwrap();
+        assert!(crate::db::get_multipart_parts(&sqlite, "id:a")
+            .unwrap()
+            .is_empty());
+        crate::db::delete_multipart_upload(&sqlite, "id:a").unwrap();
+        assert_eq!(
+            crate::db::get_multipart_upload(&sqlite, "id:a").unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn it_knows_the_keys_it_archives_under() {
+        let sqlite = crate::db::connect(":memory:").unwrap();
+        sqlite
+            .execute(
+                "INSERT INTO paths (source_id, source_path, dropbox_size, dropbox_hash, s3_key) VALUES
+                    ('id:a', '/Old/a.txt', 1, 'hash-a', 'Old/a.txt');
+                INSERT INTO bundle_members (source_id, bundle_key, offset, length) VALUES
+                    ('id:a', 'Old/deep-freeze-bundle-1.tar', 512, 1);",
+            )
+            .unwrap();
+        crate::db::insert_multipart_upload(&sqlite, "id:a", "Old/a.txt.partial", "upload").unwrap();
+        let keys = crate::db::get_object_keys(&sqlite).unwrap();
+        assert!(keys.contains("Old/a.txt"));
+        assert!(keys.contains("Old/deep-freeze-bundle-1.tar"));
+        assert!(keys.contains("Old/a.txt.partial"));
+        assert!(!keys.contains("backups/other-tool.bin"));
+    }
+
+    #[test]
+    fn it_only_resumes_uploads_for_rows_still_to_migrate() {
+        let sqlite = crate::db::connect(":memory:").unwrap();
+        insert_test_paths(&sqlite);
+        for (source_id, upload_id) in [("id:a", "a"), ("id:c", "c"), ("id:d", "d")] {
+            crate::db::insert_multipart_upload(&sqlite, source_id, source_id, upload_id).unwrap();
+        }
+        assert!(crate::db::is_resumable_upload(&sqlite, "a").unwrap());
+        assert!(!crate::db::is_resumable_upload(&sqlite, "c").unwrap());
+        assert!(!crate::db::is_resumable_upload(&sqlite, "d").unwrap());
+        assert!(!crate::db::is_resumable_upload(&sqlite, "unknown").unwrap());
+        crate::db::insert_multipart_upload(&sqlite, "id:b", "id:b", "b").unwrap();
+        crate::db::record_failure(&sqlite, "id:b", "S3 error").unwrap();
+        assert!(crate::db::is_resumable_upload(&sqlite, "b").unwrap());
+        crate::db::forget_multipart_upload(&sqlite, "a").unwrap();
+        assert!(!crate::db::is_resumable_upload(&sqlite, "a").unwrap());
+    }
 }
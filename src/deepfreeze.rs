@@ -1,35 +1,153 @@
 use crate::auth;
 use crate::aws;
+use crate::bundle;
+use crate::checksum::{self, DropboxContentHasher};
+use crate::compress;
+use crate::crypto;
 use crate::db::{self, DBConnection, DBRow};
-use crate::dropbox;
+use crate::dropbox::{self, DropboxSource};
+use crate::error::{DeepFreezeError, Result};
 use crate::localfs;
 use crate::progress;
+use crate::shutdown::Shutdown;
 use crate::util;
-use aws_sdk_s3::{Client as AWSClient, Error as AWSError};
-use indicatif::HumanDuration;
-use std::time::Instant;
+use aws_sdk_s3::{
+    operation::get_object_attributes::GetObjectAttributesOutput, Client as AWSClient,
+    Error as AWSError,
+};
+use deep_freeze::source::Source;
+use futures::future::Either;
+use futures::stream::{self, StreamExt, TryStreamExt};
+use indicatif::{HumanBytes, HumanDuration};
+use std::{cell::RefCell, cmp::min, io::SeekFrom, time::Instant};
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::Mutex;
 use util::getenv;
 
-#[async_recursion::async_recursion(?Send)]
-pub async fn perform_migration(
-    http: reqwest::Client,
-    sqlite: sqlite::ConnectionWithFullMutex,
-    aws: AWSClient,
-) {
-    let started = Instant::now();
-    print!("\n🧊  Performing migration...\n\n\n");
-    let m = progress::new_multi_progress();
-    for row in sqlite
-        .prepare("SELECT * FROM paths WHERE migrated < 1 AND skip < 1 ORDER BY dropbox_path ASC")
-        .unwrap()
-        .into_iter()
-        .map(|row| row.unwrap())
-    {
-        let dropbox_id = row
-            .try_read::<&str, &str>("dropbox_id")
-            .unwrap()
-            .to_string();
-        let filter = |&i| i == dropbox_id;
+/// Lists `source` into the catalog a page at a time, saving the cursor of the last page
+/// for `sync`. Each page is saved as it comes, so a listing that was interrupted is
+/// started again from the first page, keeping the files already catalogued. A catalog
+/// whose listing ran to the end is left for `sync` to update, and one that holds files
+/// from another source is refused.
+pub async fn get_paths<S: Source<Error = DeepFreezeError>>(
+    source: &S,
+    sqlite: &DBConnection,
+) -> Result<()> {
+    db::check_source_kind(sqlite, source.kind())?;
+    println!("🗄️   Getting file list...");
+    if !db::is_listed(sqlite, source.kind())? {
+        match db::count_rows(sqlite)? {
+            0 => println!("🗄️  File list empty"),
+            _ => println!("🗄️  File list incomplete, listing again from the start"),
+        }
+        println!("🗄️  Populating file list...");
+        let mut page = source.list(None).await?;
+        loop {
+            println!("🗄️  {} files found", page.entries.len());
+            db::insert_source_entries(sqlite, source.kind(), &page.entries)?;
+            if !page.has_more {
+                break;
+            }
+            println!("🗄️  Getting next page of results...");
+            page = source.list(page.cursor.as_deref()).await?;
+        }
+        if let Some(cursor) = page.cursor {
+            db::set_cursor(sqlite, &cursor)?;
+        }
+        db::set_listed(sqlite, source.kind())?;
+        println!();
+    }
+    db::report_status(sqlite)
+}
+
+/// Runs migration passes until every file is migrated or only failed rows are left.
+/// Files that failed in an earlier run are tried again first, once per run. Once
+/// `shutdown` is requested the workers stop claiming rows, so the pass ends once the
+/// files already in flight have finished or reached a checkpoint.
+pub async fn perform_migration<S: Source<Error = DeepFreezeError>>(
+    source: &S,
+    sqlite: &DBConnection,
+    aws: &AWSClient,
+    shutdown: &Shutdown,
+) -> Result<()> {
+    db::check_source_kind(sqlite, source.kind())?;
+    let retried = db::retry_failures(sqlite)?;
+    if retried > 0 {
+        println!("🔁  Trying {retried} files that failed before again");
+    }
+    loop {
+        let started = Instant::now();
+        let jobs = get_jobs();
+        print!("\n🧊  Performing migration with {jobs} worker(s)...\n\n\n");
+        let m = progress::new_multi_progress();
+        let token_lock = Mutex::new(());
+        db::release_claims(sqlite)?;
+        if getenv("BUNDLE").unwrap_or_default() == "true"
+            && getenv("CHECK_ONLY").unwrap_or_default() != "true"
+        {
+            bundle::migrate_bundles(source, aws, sqlite, &m, shutdown).await?;
+        }
+        stream::iter(0..jobs)
+            .map(Ok)
+            .try_for_each_concurrent(jobs, |_| {
+                migration_worker(source, aws, sqlite, &m, &token_lock, shutdown)
+            })
+            .await?;
+        db::release_claims(sqlite)?;
+        db::report_status(sqlite)?;
+
+        println!("✨ Done in {}", HumanDuration(started.elapsed()));
+        if getenv("CHECK_ONLY").unwrap_or_default() == "true" || shutdown.requested() {
+            return Ok(());
+        }
+        // Rows that failed in this run are skipped, so another pass only picks up rows
+        // that are still pending.
+        match (db::count_pending(sqlite)?, db::count_unmigrated(sqlite)?) {
+            (_, 0) => {
+                println!("✅  All files migrated");
+                return Ok(());
+            }
+            (0, _) => {
+                println!("🚨  Some files not migrated");
+                return Ok(());
+            }
+            _ => continue,
+        }
+    }
+}
+
+fn get_jobs() -> usize {
+    getenv("JOBS")
+        .unwrap_or_default()
+        .parse::<usize>()
+        .unwrap_or(1)
+        .max(1)
+}
+
+/// Claims unmigrated rows one at a time until none are left for this pass. Every
+/// worker shares the same `MultiProgress`, and `token_lock` keeps concurrent workers
+/// from preparing the source (refreshing the Dropbox token and rewriting the env file)
+/// at the same time.
+///
+/// A file that fails is recorded with `db::record_failure` and the worker moves on; only
+/// errors that would fail every file (database, auth) end the pass. A file a shutdown
+/// stops is recorded with `db::record_interruption` instead, and stays pending: after the
+/// first signal the transfer stops at its next checkpoint, after the second it is dropped
+/// where it stands.
+async fn migration_worker<S: Source<Error = DeepFreezeError>>(
+    source: &S,
+    aws: &AWSClient,
+    sqlite: &DBConnection,
+    m: &crate::progress::MultiProgress,
+    token_lock: &Mutex<()>,
+    shutdown: &Shutdown,
+) -> Result<()> {
+    while !shutdown.requested() {
+        let Some(row) = db::claim_next_path(sqlite)? else {
+            break;
+        };
+        let source_id = row.try_read::<&str, &str>("source_id")?.to_string();
+        let filter = |&i| i == source_id;
         if getenv("SKIP")
             .unwrap_or("".to_string())
             .split(',')
@@ -37,147 +155,592 @@ pub async fn perform_migration(
             .iter()
             .any(filter)
         {
-            println!("✅ Skipping {dropbox_id}\n\n");
+            println!("✅ Skipping {source_id}\n\n");
             continue;
-        } else {
-            if getenv("CHECK_ONLY").unwrap_or_default() != "true" {
-                auth::refresh_token(&http).await;
-            }
-            println!("📂  Migrating {dropbox_id}");
-            migrate_file_to_s3(row, &http, &aws, &sqlite, &m).await;
         }
-    }
-    db::report_status(&sqlite);
-
-    println!("✨ Done in {}", HumanDuration(started.elapsed()));
-    if getenv("CHECK_ONLY").unwrap() == "true" {
-        println!("✅  Exiting");
-        std::process::exit(0);
-    }
-    match db::count_unmigrated(&sqlite) {
-        0 => {
-            println!("✅  All files migrated");
-            ::std::process::exit(0)
+        if getenv("CHECK_ONLY").unwrap_or_default() != "true" {
+            let _guard = token_lock.lock().await;
+            source.prepare().await?;
         }
-        _ => {
-            println!("🚨  Some files not migrated");
-            perform_migration(http, sqlite, aws).await;
+        println!("📂  Migrating {source_id}");
+        let migrated = tokio::select! {
+            migrated = migrate_file_to_s3(row, source, aws, sqlite, m, shutdown) => migrated,
+            _ = shutdown.aborted() => {
+                Err(DeepFreezeError::Interrupted("aborted mid-transfer".to_string()))
+            }
+        };
+        match migrated {
+            Ok(()) => (),
+            Err(DeepFreezeError::Interrupted(reason)) => {
+                db::record_interruption(sqlite, &source_id, &reason)?
+            }
+            Err(err) => db::record_failure(sqlite, &source_id, &err.to_string())?,
         }
     }
+    Ok(())
 }
 
-async fn migrate_file_to_s3(
+async fn migrate_file_to_s3<S: Source<Error = DeepFreezeError>>(
     row: sqlite::Row,
-    http: &reqwest::Client,
+    source: &S,
     aws: &AWSClient,
     sqlite: &sqlite::ConnectionWithFullMutex,
     m: &crate::progress::MultiProgress,
-) {
-    let dropbox_id = row
-        .try_read::<&str, &str>("dropbox_id")
-        .unwrap()
-        .to_string();
-
-    match check_migration_status(&aws, &sqlite, &row).await {
-        -1..=0 => match getenv("CHECK_ONLY").unwrap().as_str() {
-            "true" => {
+    shutdown: &Shutdown,
+) -> Result<()> {
+    let source_id = row.try_read::<&str, &str>("source_id")?.to_string();
+
+    match check_migration_status(aws, sqlite, &row).await? {
+        -1..=0 => {
+            if getenv("CHECK_ONLY").unwrap_or_default() == "true" {
                 print!("\n\n");
-                return ();
+                return Ok(());
             }
-            _ => (),
-        },
-        1 => return (),
-        err => {
-            dbg!(err);
-            println!("❌  Unknown migration status {err}");
-            db::set_skip(&sqlite, &dropbox_id);
+        }
+        1 => return Ok(()),
+        status => {
+            return Err(DeepFreezeError::Integrity(format!(
+                "Unknown migration status {status}"
+            )))
         }
     };
 
-    let dropbox_path = row
-        .try_read::<&str, &str>("dropbox_path")
-        .unwrap()
-        .to_string();
-    let key = util::standardize_path(&dropbox_path);
-    let bucket = getenv("AWS_S3_BUCKET").unwrap();
+    if getenv("DEDUP").unwrap_or_default() == "true" {
+        if let Some(canonical) = db::find_canonical(sqlite, &source_id)? {
+            if deduplicate(aws, sqlite, &source_id, &canonical).await? {
+                return Ok(());
+            }
+        }
+    }
 
-    let local_path = format!("./temp/{key}");
+    let source_path = row.try_read::<&str, &str>("source_path")?.to_string();
+    let key = s3_key(&row)?;
+    let bucket = getenv("AWS_S3_BUCKET")?;
 
-    dropbox::download_from_dropbox(&http, &dropbox_id, &dropbox_path, &local_path, &m).await;
+    let dropbox_hash = row.try_read::<&str, &str>("dropbox_hash")?.to_string();
+    let dropbox_size = row.try_read::<i64, &str>("dropbox_size")? as u64;
 
-    // TODO verify checksum from DB
+    let local_path = localfs::temp_path(&key);
 
-    match aws::upload_to_s3(&aws, &key, &local_path, &bucket, &m).await {
-        Ok(_) => (),
+    let s3_hash = match transfer_file(
+        source,
+        aws,
+        sqlite,
+        (&source_id, &source_path, &dropbox_hash, dropbox_size),
+        (&key, &bucket),
+        &local_path,
+        (m, shutdown),
+    )
+    .await
+    {
+        Ok(s3_hash) => s3_hash,
+        Err(DeepFreezeError::HashMismatch(err)) => {
+            println!("🧨  {err}");
+            db::set_hash_mismatch(sqlite, &source_id)?;
+            localfs::delete_local_file(&local_path).await?;
+            return Ok(());
+        }
         Err(err) => {
             println!("🚫  {err}");
-            db::set_unmigrated(&sqlite, &dropbox_id);
-            db::set_skip(&sqlite, &dropbox_id);
+            db::set_unmigrated(sqlite, &source_id)?;
+            return Err(err);
         }
-    }
-
-    // TODO create checksum from file for AWS
+    };
 
-    match aws::confirm_upload_size(&sqlite, &aws, &bucket, &dropbox_id, &key).await {
+    let expected_size =
+        crypto::stored_size(sqlite, &key, db::get_archived_size(sqlite, &source_id)?)?;
+    match aws::confirm_upload_size(aws, &bucket, &key, expected_size, Some(&s3_hash)).await {
         Ok(_) => {
-            // // TODO verify checksum from S3
-            db::set_migrated(&sqlite, &dropbox_id);
-            localfs::delete_local_file(&local_path).await;
+            db::set_s3_hash(sqlite, &source_id, &s3_hash)?;
+            db::set_migrated(sqlite, &source_id)?;
+            db::delete_bundle_member(sqlite, &source_id)?;
+            localfs::delete_local_file(&local_path).await?;
+            Ok(())
         }
         Err(err) => {
             println!("🚫  {err}");
-            db::set_unmigrated(&sqlite, &dropbox_id);
-            match aws::delete_from_s3(&aws, &bucket, &key).await {
+            db::set_unmigrated(sqlite, &source_id)?;
+            match aws::delete_from_s3(aws, &bucket, &key).await {
                 Ok(_) => println!("🗑️  Deleted s3://{bucket}/{key}"),
                 Err(err) => println!("🚫  {err}"),
             };
-            db::set_skip(&sqlite, &dropbox_id);
-        }
-    }
-}
-
-async fn check_migration_status(aws: &AWSClient, sqlite: &DBConnection, row: &DBRow) -> i64 {
-    let dropbox_path = row
-        .try_read::<&str, &str>("dropbox_path")
-        .unwrap()
-        .to_string();
-    let bucket = getenv("AWS_S3_BUCKET").unwrap();
-    let key = util::standardize_path(&dropbox_path);
-    let dropbox_size = row.try_read::<i64, &str>("dropbox_size").unwrap();
-    let dropbox_id = row
-        .try_read::<&str, &str>("dropbox_id")
-        .unwrap()
-        .to_string();
-    let local_path = format!("./temp/{key}");
-    println!("🔍  Checking migration status for {}", dropbox_path);
-    match aws::get_s3_attrs(&aws, &bucket, &key).await {
+            Err(err)
+        }
+    }
+}
+
+/// Archives `source_id` as a reference to the object of `canonical`, an archived file
+/// with the same `content_hash`, instead of uploading it again. Only done once that object
+/// is confirmed to be in S3 with `canonical`'s checksum; returns whether it was.
+async fn deduplicate(
+    aws: &AWSClient,
+    sqlite: &DBConnection,
+    source_id: &str,
+    canonical: &DBRow,
+) -> Result<bool> {
+    let bucket = getenv("AWS_S3_BUCKET")?;
+    let key = s3_key(canonical)?;
+    match aws::get_s3_attrs(aws, &bucket, &key).await {
+        Ok(s3_attrs) if same_s3_checksum(canonical, &s3_attrs) => (),
+        Ok(_) | Err(AWSError::NoSuchKey(_)) => return Ok(false),
+        Err(err) => return Err(err.into()),
+    }
+    println!("🪞  Same content as s3://{bucket}/{key}");
+    db::set_duplicate(
+        sqlite,
+        source_id,
+        (canonical.try_read::<&str, &str>("source_id")?, &key),
+        canonical.try_read::<&str, &str>("s3_hash")?,
+    )?;
+    Ok(true)
+}
+
+/// Moves one file from its source to S3, either straight from the download stream
+/// (`DIRECT`), from where it is on a local disk, or through a local temp copy. Either
+/// way the bytes are hashed and checked against `dropbox_hash` (or the hash recorded,
+/// for a file listed without one) before S3 gets a finished object. After that the temp
+/// copy, or the file in place, is compressed when `compress::should_compress` picks it,
+/// and encrypted when `ENCRYPTION` is set. Returns the SHA-256 checksum S3 should now
+/// report for the object.
+async fn transfer_file<S: Source<Error = DeepFreezeError>>(
+    source: &S,
+    aws: &AWSClient,
+    sqlite: &DBConnection,
+    (source_id, source_path, dropbox_hash, dropbox_size): (&str, &str, &str, u64),
+    (key, bucket): (&str, &str),
+    local_path: &str,
+    (m, shutdown): (&crate::progress::MultiProgress, &Shutdown),
+) -> Result<String> {
+    match getenv("DIRECT").unwrap_or_default().as_str() {
+        "true" => {
+            let hasher = RefCell::new(DropboxContentHasher::new());
+            let stream = source.fetch(source_id, 0).await?.inspect(|chunk| {
+                if let Ok(bytes) = chunk {
+                    hasher.borrow_mut().update(bytes);
+                }
+            });
+            let verify = || {
+                let actual = hasher.take().finalize();
+                db::check_content_hash(sqlite, source_id, (dropbox_hash, actual))
+            };
+            // Compression needs the compressed size up front, so streamed files go as they are.
+            db::set_compression(sqlite, source_id, None)?;
+            let (size, metadata, stream) =
+                match crypto::envelope_for(sqlite, (source_id, key), dropbox_size)? {
+                    Some(envelope) => (
+                        envelope.ciphertext_size(),
+                        envelope.metadata(),
+                        Either::Left(crypto::encrypt_stream(envelope, stream)),
+                    ),
+                    None => (dropbox_size, aws::Metadata::new(), Either::Right(stream)),
+                };
+            aws::stream_upload(
+                aws,
+                (sqlite, source_id),
+                (key, bucket),
+                (size, stream),
+                verify,
+                &metadata,
+                (m, shutdown),
+            )
+            .await
+        }
+        _ => {
+            // A file on a local disk is read where it is; only what is made from it
+            // (compressed or encrypted copies) goes in the temp folder.
+            let source_file = match source.local_path(source_id) {
+                Some(path) => {
+                    localfs::create_download_folder(local_path).await?;
+                    let actual = checksum::dropbox_content_hash_of_file(&path).await?;
+                    db::check_content_hash(sqlite, source_id, (dropbox_hash, actual))?;
+                    path
+                }
+                None => {
+                    let actual = download_from_source(
+                        source,
+                        (source_id, dropbox_size),
+                        local_path,
+                        (m, shutdown),
+                    )
+                    .await?;
+                    db::check_content_hash(sqlite, source_id, (dropbox_hash, actual))?;
+                    local_path.to_string()
+                }
+            };
+            let size = localfs::get_local_size(&source_file).await? as u64;
+            let mut upload_path = source_file.clone();
+            let mut metadata = aws::Metadata::new();
+            let compressed_path = format!("{local_path}.zst");
+            let compressed = match compress::should_compress(source_path) {
+                true => compress::compress_file(&source_file, &compressed_path).await?,
+                false => None,
+            };
+            db::set_compression(
+                sqlite,
+                source_id,
+                compressed.map(|compressed| (compress::COMPRESSION, compressed as i64)),
+            )?;
+            if compressed.is_some() {
+                upload_path = compressed_path;
+                metadata.extend(compress::metadata(size));
+            }
+            let archived_size = compressed.unwrap_or(size);
+            if let Some(envelope) = crypto::envelope_for(sqlite, (source_id, key), archived_size)? {
+                let encrypted_path = format!("{local_path}.enc");
+                crypto::encrypt_file(&envelope, &upload_path, &encrypted_path).await?;
+                if upload_path != source_file {
+                    localfs::delete_local_file(&upload_path).await?;
+                }
+                upload_path = encrypted_path;
+                metadata.extend(envelope.metadata());
+            }
+            let s3_hash = aws::upload_to_s3(
+                aws,
+                (sqlite, source_id),
+                (key, bucket),
+                &upload_path,
+                &metadata,
+                (m, shutdown),
+            )
+            .await?;
+            if upload_path != source_file {
+                localfs::delete_local_file(&upload_path).await?;
+            }
+            Ok(s3_hash)
+        }
+    }
+}
+
+/// Downloads `source_id` to `local_path`, returning its Dropbox content hash. A temp
+/// copy left short by an earlier run is continued from where it stopped rather than
+/// fetched again. Once `shutdown` is requested the download stops after the chunk in
+/// hand, keeping what has been written for the next run.
+pub async fn download_from_source<S: Source<Error = DeepFreezeError>>(
+    source: &S,
+    (source_id, size): (&str, u64),
+    local_path: &str,
+    (m, shutdown): (&crate::progress::MultiProgress, &Shutdown),
+) -> Result<String> {
+    let mut downloaded = localfs::get_local_size(local_path).await? as u64;
+    if downloaded > size {
+        localfs::delete_local_file(local_path).await?;
+        downloaded = 0;
+    }
+    let pb = m.add(progress::new(size, "file_transfer"));
+    pb.set_prefix("⬇️   Download  ");
+    let content_hash = if downloaded != size {
+        let mut hasher = match downloaded {
+            0 => DropboxContentHasher::new(),
+            _ => {
+                println!("⏯️  Resuming download at {}", HumanBytes(downloaded));
+                checksum::dropbox_content_hasher_of_file(local_path).await?
+            }
+        };
+        let mut stream = source.fetch(source_id, downloaded).await?;
+        let mut file = localfs::get_local_file(local_path).await?;
+        file.seek(SeekFrom::End(0)).await?;
+        pb.set_position(downloaded);
+        while let Some(item) = stream.next().await {
+            let chunk = item?;
+            downloaded = min(downloaded + (chunk.len() as u64), size);
+            pb.set_position(downloaded);
+            hasher.update(&chunk);
+            file.write_all(&chunk).await?;
+            if let Err(err) = shutdown.checkpoint(|| {
+                format!(
+                    "{} of {} downloaded",
+                    HumanBytes(downloaded),
+                    HumanBytes(size)
+                )
+            }) {
+                file.flush().await?;
+                return Err(err);
+            }
+        }
+        file.flush().await?;
+        if downloaded != size {
+            return Err(DeepFreezeError::Integrity(format!(
+                "downloaded {downloaded} bytes of {size}"
+            )));
+        }
+        hasher.finalize()
+    } else {
+        checksum::dropbox_content_hash_of_file(local_path).await?
+    };
+    pb.finish();
+    pb.set_prefix("✅  Download ");
+    Ok(content_hash)
+}
+
+async fn check_migration_status(
+    aws: &AWSClient,
+    sqlite: &DBConnection,
+    row: &DBRow,
+) -> Result<i64> {
+    let source_path = row.try_read::<&str, &str>("source_path")?.to_string();
+    let bucket = getenv("AWS_S3_BUCKET")?;
+    let key = s3_key(row)?;
+    let dropbox_size = row.try_read::<i64, &str>("dropbox_size")?;
+    let archived_size = row
+        .try_read::<Option<i64>, &str>("compressed_size")?
+        .unwrap_or(dropbox_size);
+    let source_id = row.try_read::<&str, &str>("source_id")?.to_string();
+    let local_path = localfs::temp_path(&key);
+    if row.try_read::<i64, &str>("migrated")? == -3 {
+        println!("🔄  Changed in Dropbox since it was archived: {source_path}");
+        return Ok(0);
+    }
+    if let Some(member) = db::get_bundle_member(sqlite, &source_id)? {
+        return check_bundle_member(aws, sqlite, row, member).await;
+    }
+    if let Some(canonical_key) = db::get_duplicate(sqlite, &source_id)? {
+        return check_duplicate(aws, sqlite, row, canonical_key).await;
+    }
+    println!("🔍  Checking migration status for {}", source_path);
+    match aws::get_s3_attrs(aws, &bucket, &key).await {
         Err(err) => match err {
             AWSError::NoSuchKey(_) => {
                 println!("❌  Not found: s3:://{}/{}", bucket, key);
-                db::set_unmigrated(&sqlite, &dropbox_id);
-                0
-            }
-            err => {
-                println!("❌  {}", err);
-                db::set_skip(&sqlite, &dropbox_id);
-                0
+                db::set_unmigrated(sqlite, &source_id)?;
+                Ok(0)
             }
+            err => Err(err.into()),
         },
-        Ok(s3_attrs) => match s3_attrs.object_size().unwrap_or_default() == dropbox_size {
+        Ok(s3_attrs) => match s3_attrs.object_size().unwrap_or_default()
+            == crypto::stored_size(sqlite, &key, archived_size)?
+            && same_s3_checksum(row, &s3_attrs)
+        {
             true => {
                 println!("✅  Files the same size on DB & S3");
-                db::set_migrated(&sqlite, &dropbox_id);
-                localfs::delete_local_file(&local_path).await;
-                1
+                db::set_migrated(sqlite, &source_id)?;
+                localfs::delete_local_file(&local_path).await?;
+                Ok(1)
             }
             false => {
-                println!("❌  File exists on S3, but is not the correct size");
+                println!("❌  File exists on S3, but is not the correct size or checksum");
                 println!("🗳️  DB size: {dropbox_size}");
-                println!("🗂️  S3 size: {}", s3_attrs.object_size().unwrap_or_default());
-                aws::delete_from_s3(&aws, &bucket, &key).await.unwrap();
-                db::set_unmigrated(&sqlite, &dropbox_id);
-                0
+                println!(
+                    "🗂️  S3 size: {}",
+                    s3_attrs.object_size().unwrap_or_default()
+                );
+                aws::delete_from_s3(aws, &bucket, &key).await?;
+                db::set_unmigrated(sqlite, &source_id)?;
+                Ok(0)
             }
         },
     }
 }
+
+/// Checks a file packed into a tar bundle: its bundle must be in S3 with the checksum
+/// recorded for it, and big enough to hold the file at its offset. A bundle that isn't
+/// is left alone, since it may still hold other files; the file is archived again.
+async fn check_bundle_member(
+    aws: &AWSClient,
+    sqlite: &DBConnection,
+    row: &DBRow,
+    (bundle_key, offset, length): (String, i64, i64),
+) -> Result<i64> {
+    let source_path = row.try_read::<&str, &str>("source_path")?.to_string();
+    let source_id = row.try_read::<&str, &str>("source_id")?.to_string();
+    let bucket = getenv("AWS_S3_BUCKET")?;
+    println!("🔍  Checking {source_path} in bundle s3://{bucket}/{bundle_key}");
+    let found = match aws::get_s3_attrs(aws, &bucket, &bundle_key).await {
+        Ok(s3_attrs) => {
+            s3_attrs.object_size().unwrap_or_default() >= offset + length
+                && same_s3_checksum(row, &s3_attrs)
+        }
+        Err(AWSError::NoSuchKey(_)) => false,
+        Err(err) => return Err(err.into()),
+    };
+    match found {
+        true => {
+            println!("✅  Found in bundle");
+            db::set_migrated(sqlite, &source_id)?;
+            Ok(1)
+        }
+        false => {
+            println!("❌  Not in bundle: s3://{bucket}/{bundle_key}");
+            db::delete_bundle_member(sqlite, &source_id)?;
+            db::set_unmigrated(sqlite, &source_id)?;
+            Ok(0)
+        }
+    }
+}
+
+/// Checks a deduplicated file: the object it refers to must be in S3 with the checksum
+/// recorded for the file. If it isn't, the reference is dropped and the file archived
+/// again.
+async fn check_duplicate(
+    aws: &AWSClient,
+    sqlite: &DBConnection,
+    row: &DBRow,
+    canonical_key: String,
+) -> Result<i64> {
+    let source_path = row.try_read::<&str, &str>("source_path")?.to_string();
+    let source_id = row.try_read::<&str, &str>("source_id")?.to_string();
+    let bucket = getenv("AWS_S3_BUCKET")?;
+    println!("🔍  Checking {source_path} as a duplicate of s3://{bucket}/{canonical_key}");
+    let found = match aws::get_s3_attrs(aws, &bucket, &canonical_key).await {
+        Ok(s3_attrs) => same_s3_checksum(row, &s3_attrs),
+        Err(AWSError::NoSuchKey(_)) => false,
+        Err(err) => return Err(err.into()),
+    };
+    match found {
+        true => {
+            println!("✅  Found as a duplicate");
+            db::set_migrated(sqlite, &source_id)?;
+            Ok(1)
+        }
+        false => {
+            println!("❌  Duplicate gone: s3://{bucket}/{canonical_key}");
+            db::delete_duplicate(sqlite, &source_id)?;
+            db::set_unmigrated(sqlite, &source_id)?;
+            Ok(0)
+        }
+    }
+}
+
+/// The key of a row's object: the one recorded when the file moved under
+/// `DeletionPolicy::Keep`, or else the key its Dropbox path maps to.
+fn s3_key(row: &DBRow) -> Result<String> {
+    match row.try_read::<Option<&str>, &str>("s3_key")? {
+        Some(s3_key) => Ok(s3_key.to_string()),
+        None => util::standardize_path(
+            row.try_read::<&str, &str>("source_kind")?,
+            row.try_read::<&str, &str>("source_path")?,
+        ),
+    }
+}
+
+/// Brings the catalog up to date with Dropbox, then applies `DELETION_POLICY` to the
+/// objects of files that were deleted or moved.
+pub async fn sync(http: &reqwest::Client, aws: &AWSClient, sqlite: &DBConnection) -> Result<()> {
+    let summary = dropbox::sync(http, sqlite).await?;
+    let bucket = getenv("AWS_S3_BUCKET")?;
+    for key in &summary.removed_keys {
+        if let Err(err) = aws::tag_deleted_in_source(aws, &bucket, key).await {
+            println!("🚫  Could not tag s3://{bucket}/{key}: {err}");
+        }
+    }
+    Ok(())
+}
+
+/// Archives new and changed files as they appear in Dropbox until SIGTERM or Ctrl-C:
+/// syncs the catalog, migrates whatever is pending, then longpolls the saved cursor
+/// until Dropbox reports more changes. A signal during a pass lets the files in flight
+/// finish; one while waiting stops straight away.
+pub async fn watch(
+    http: &reqwest::Client,
+    aws: &AWSClient,
+    sqlite: &DBConnection,
+    shutdown: &mut Shutdown,
+) -> Result<()> {
+    println!("👀  Watching Dropbox for changes (stop with Ctrl-C or SIGTERM)");
+    'watch: while !shutdown.requested() {
+        auth::refresh_token(http).await?;
+        sync(http, aws, sqlite).await?;
+        perform_migration(&DropboxSource::new(http), sqlite, aws, shutdown).await?;
+        let cursor = db::get_cursor(sqlite)?
+            .ok_or_else(|| DeepFreezeError::Dropbox("sync saved no cursor".to_string()))?;
+        println!("👀  Waiting for changes in Dropbox...");
+        loop {
+            let poll = tokio::select! {
+                poll = dropbox::longpoll(http, &cursor) => poll?,
+                _ = shutdown.wait() => break 'watch,
+            };
+            if let Some(backoff) = poll.backoff {
+                tokio::select! {
+                    _ = tokio::time::sleep(backoff) => {}
+                    _ = shutdown.wait() => break 'watch,
+                }
+            }
+            if poll.changes {
+                break;
+            }
+        }
+    }
+    println!("🛑  Stopped watching");
+    Ok(())
+}
+
+/// Compares the checksum S3 reports with the one recorded at upload time. Rows uploaded
+/// before checksums were recorded, or objects without one, fall back to the size check.
+fn same_s3_checksum(row: &DBRow, s3_attrs: &GetObjectAttributesOutput) -> bool {
+    let s3_hash = row
+        .try_read::<Option<&str>, &str>("s3_hash")
+        .unwrap_or_default();
+    let s3_checksum = s3_attrs
+        .checksum()
+        .and_then(|checksum| checksum.checksum_sha256());
+    match (s3_hash, s3_checksum) {
+        (Some(s3_hash), Some(s3_checksum)) => checksum::s3_checksums_match(s3_checksum, s3_hash),
+        _ => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::{DeepFreezeError, Result};
+    use deep_freeze::source::{Source, SourceEntry, SourcePage, SourceStream};
+
+    /// A listing of one file per page, which fails at page `fail_at`.
+    struct PagedSource {
+        pages: usize,
+        fail_at: Option<usize>,
+    }
+
+    impl Source for PagedSource {
+        type Error = DeepFreezeError;
+
+        fn kind(&self) -> &str {
+            "local"
+        }
+
+        async fn list(&self, cursor: Option<&str>) -> Result<SourcePage> {
+            let page: usize = cursor.map_or(0, |cursor| cursor.parse().unwrap());
+            if self.fail_at == Some(page) {
+                return Err(DeepFreezeError::Config("listing interrupted".to_string()));
+            }
+            let has_more = page + 1 < self.pages;
+            Ok(SourcePage {
+                entries: vec![SourceEntry {
+                    id: format!("/{page}.txt"),
+                    path: format!("/{page}.txt"),
+                    size: 1,
+                    hash: String::new(),
+                    modified: None,
+                }],
+                cursor: has_more.then(|| (page + 1).to_string()),
+                has_more,
+            })
+        }
+
+        async fn fetch(&self, _id: &str, _offset: u64) -> Result<SourceStream<DeepFreezeError>> {
+            Err(DeepFreezeError::Config("not fetched".to_string()))
+        }
+    }
+
+    #[tokio::test]
+    async fn it_lists_again_after_an_interrupted_listing() {
+        let sqlite = crate::db::connect(":memory:").unwrap();
+        let interrupted = PagedSource {
+            pages: 3,
+            fail_at: Some(1),
+        };
+        assert!(crate::deepfreeze::get_paths(&interrupted, &sqlite)
+            .await
+            .is_err());
+        assert_eq!(crate::db::count_rows(&sqlite).unwrap(), 1);
+        assert!(!crate::db::is_listed(&sqlite, "local").unwrap());
+
+        let source = PagedSource {
+            pages: 3,
+            fail_at: None,
+        };
+        crate::deepfreeze::get_paths(&source, &sqlite)
+            .await
+            .unwrap();
+        assert_eq!(crate::db::count_rows(&sqlite).unwrap(), 3);
+        assert!(crate::db::is_listed(&sqlite, "local").unwrap());
+    }
+}
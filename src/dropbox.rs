@@ -1,206 +1,345 @@
-use futures_util::StreamExt;
-use std::cmp::min;
-use tokio::io::AsyncWriteExt;
+use deep_freeze::source::{Source, SourcePage, SourceStream};
+use futures_util::{Stream, TryStreamExt};
+use hyper::body::Bytes;
+use reqwest::{header::RANGE, StatusCode};
+use std::io::SeekFrom;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
 
+use crate::auth;
 use crate::db::{self, DBConnection};
+use crate::error::{DeepFreezeError, Result};
 use crate::http::{self, HTTPClient, HeaderMap};
 use crate::json::{self, JSON};
 use crate::localfs;
 use crate::progress;
+use crate::retry;
 use crate::util::{getenv, setenv};
 
-pub async fn add_files_to_list(
-    json: &JSON,
-    connection: &DBConnection,
-) -> Result<(), Box<dyn std::error::Error>> {
-    assert_eq!(
-        json.get("error"),
-        None,
-        "🛑 DropBox returned an error {json}"
-    );
-    let count: usize = json::count_files(&json);
-    println!("🗄️  {count} files found");
-    if count > 0 {
-        db::insert_dropbox_paths(&connection, json::get_entries(&json));
-    }
-    Ok(())
-}
+/// How long one longpoll request waits for changes, in seconds (Dropbox allows 30-480).
+const LONGPOLL_TIMEOUT: u64 = 480;
+/// How much of a file each upload session request carries. Dropbox takes up to 150 MiB
+/// per request and prefers multiples of 4 MiB.
+const UPLOAD_CHUNK_SIZE: u64 = 16 * 1024 * 1024;
 
-pub async fn get_team_members_list(http: &HTTPClient) -> String {
+pub async fn get_team_members_list(http: &HTTPClient) -> Result<String> {
     let mut headers = HeaderMap::new();
-    headers = http::dropbox_authorization_header(&mut headers);
-    headers = http::dropbox_content_type_json_header(&mut headers);
-    let body = format!("{{\"limit\": 1000}}");
-    http.post("https://api.dropboxapi.com/2/team/members/list_v2")
-        .headers(headers)
-        .body(body)
-        .send()
-        .await
-        .unwrap()
-        .text()
-        .await
-        .unwrap()
+    headers = http::dropbox_authorization_header(&mut headers)?;
+    headers = http::dropbox_content_type_json_header(&mut headers)?;
+    let body = "{\"limit\": 1000}".to_string();
+    retry::send_text("team/members/list_v2", || {
+        http.post("https://api.dropboxapi.com/2/team/members/list_v2")
+            .headers(headers.clone())
+            .body(body.clone())
+    })
+    .await
+    .map_err(DeepFreezeError::from)
 }
 
-async fn list_folder(http: &HTTPClient, recursive: bool) -> String {
+async fn list_folder(http: &HTTPClient, recursive: bool) -> Result<String> {
     // let base_folder = env::var("BASE_FOLDER").unwrap();
     // "{{\"path\": \"{}\", \"recursive\": true,  \"limit\": 2000, \"include_non_downloadable_files\": false}}",
     let mut headers = HeaderMap::new();
-    headers = http::dropbox_authorization_header(&mut headers);
-    headers = http::dropbox_content_type_json_header(&mut headers);
-    headers = http::dropbox_select_user_header(&mut headers);
-    headers = http::dropbox_api_path_root_header(&mut headers);
+    headers = http::dropbox_authorization_header(&mut headers)?;
+    headers = http::dropbox_content_type_json_header(&mut headers)?;
+    headers = http::dropbox_select_user_header(&mut headers)?;
+    headers = http::dropbox_api_path_root_header(&mut headers)?;
     let body = format!(
         "{{\"path\": \"{}\", \"recursive\": {},  \"limit\": 2000, \"include_non_downloadable_files\": false}}",
         getenv("DROPBOX_BASE_FOLDER").unwrap_or("".to_string()), recursive
     );
-    http.post("https://api.dropboxapi.com/2/files/list_folder")
-        .headers(headers)
-        .body(body)
-        .send()
-        .await
-        .unwrap()
-        .text()
-        .await
-        .unwrap()
+    retry::send_text("files/list_folder", || {
+        http.post("https://api.dropboxapi.com/2/files/list_folder")
+            .headers(headers.clone())
+            .body(body.clone())
+    })
+    .await
+    .map_err(DeepFreezeError::from)
 }
 
-async fn list_folder_continue(http: &HTTPClient, cursor: &String) -> String {
+async fn list_folder_continue(http: &HTTPClient, cursor: &str) -> Result<String> {
     let mut headers = HeaderMap::new();
-    headers = http::dropbox_authorization_header(&mut headers);
-    headers = http::dropbox_select_user_header(&mut headers);
-    headers = http::dropbox_content_type_json_header(&mut headers);
-    headers = http::dropbox_api_path_root_header(&mut headers);
-    let body = format!("{{\"cursor\": {cursor}}}");
-    http.post("https://api.dropboxapi.com/2/files/list_folder/continue")
-        .headers(headers)
-        .body(body)
-        .send()
-        .await
-        .unwrap()
-        .text()
-        .await
-        .unwrap()
+    headers = http::dropbox_authorization_header(&mut headers)?;
+    headers = http::dropbox_select_user_header(&mut headers)?;
+    headers = http::dropbox_content_type_json_header(&mut headers)?;
+    headers = http::dropbox_api_path_root_header(&mut headers)?;
+    let body = serde_json::json!({ "cursor": cursor }).to_string();
+    retry::send_text("files/list_folder/continue", || {
+        http.post("https://api.dropboxapi.com/2/files/list_folder/continue")
+            .headers(headers.clone())
+            .body(body.clone())
+    })
+    .await
+    .map_err(DeepFreezeError::from)
 }
 
-pub async fn choose_folder(http: &HTTPClient, sqlite: &DBConnection) {
+pub async fn choose_folder(http: &HTTPClient, sqlite: &DBConnection) -> Result<()> {
     let recursive = false;
-    let res = list_folder(&http, recursive).await;
-    let json: JSON = json::from_res(&res);
-    let folders = json.get("entries").unwrap().as_array().unwrap();
-    let options: Vec<String> = folders
-        .into_iter()
-        .map(|folder| {
-            let path = folder.get("path_display").unwrap().as_str().unwrap();
-            path.to_string()
-        })
-        .collect();
-    match inquire::Select::new("🗄️  Choose a folder to scan", options)
+    let res = list_folder(http, recursive).await?;
+    let json: JSON = json::from_res(&res)?;
+    let options: Vec<String> = json::get_entries(&json)?
+        .iter()
+        .map(|folder| json::get_str(folder, "path_display").map(str::to_string))
+        .collect::<Result<_>>()?;
+    let choice = inquire::Select::new("🗄️  Choose a folder to scan", options)
         .with_page_size(20)
-        .prompt()
-    {
-        Ok(choice) => {
-            println!("🗄️  You chose {choice}");
-            setenv("DROPBOX_BASE_FOLDER", choice).await;
-            db::insert_config(&sqlite);
+        .prompt()?;
+    println!("🗄️  You chose {choice}");
+    setenv("DROPBOX_BASE_FOLDER", choice).await?;
+    db::insert_config(sqlite)
+}
+
+/// Brings the catalog up to date with Dropbox (see `db::sync_dropbox_paths`): new files
+/// are added, changed files are queued to be archived again, and moves and deletions are
+/// recorded. Continues from the cursor the last listing saved, or lists the whole folder
+/// again if there is none (or Dropbox has expired it), saving the new cursor after every
+/// page.
+pub async fn sync(http: &HTTPClient, sqlite: &DBConnection) -> Result<db::SyncSummary> {
+    println!("🔄  Syncing file list with Dropbox...");
+    let mut json = match db::get_cursor(sqlite)? {
+        Some(cursor) => match json::from_res(&list_folder_continue(http, &cursor).await?) {
+            Err(DeepFreezeError::Dropbox(summary)) if summary.starts_with("reset") => {
+                println!("🗄️  Dropbox reset the cursor, listing everything again");
+                json::from_res(&list_folder(http, true).await?)?
+            }
+            json => json?,
+        },
+        None => json::from_res(&list_folder(http, true).await?)?,
+    };
+    let policy = getenv("DELETION_POLICY")
+        .unwrap_or_default()
+        .parse::<db::DeletionPolicy>()?;
+    let base_folder = getenv("DROPBOX_BASE_FOLDER")?;
+    let mut summary = db::SyncSummary::default();
+    loop {
+        db::sync_dropbox_paths(
+            sqlite,
+            json::get_entries(&json)?,
+            (policy, &base_folder),
+            &mut summary,
+        )?;
+        let cursor = json::get_cursor(&json)?;
+        db::set_cursor(sqlite, &cursor)?;
+        if !json::get_has_more(&json)? {
+            break;
         }
-        Err(err) => panic!("❌  Error choosing folder {err}"),
+        json = json::from_res(&list_folder_continue(http, &cursor).await?)?;
     }
+    println!(
+        "🆕  {} new, 🔄  {} changed, 🚚  {} moved, 🪦  {} deleted\n",
+        summary.added, summary.changed, summary.moved, summary.deleted
+    );
+    db::report_status(sqlite)?;
+    Ok(summary)
 }
 
-pub async fn get_paths(http: &HTTPClient, sqlite: &DBConnection) {
-    print!("🗄️   Getting file list...\n");
-    let count = db::count_rows(&sqlite);
-    if count == 0 {
-        println!("🗄️  File list empty");
-        println!("🗄️  Populating file list...");
-        let recursive = true;
-        let mut res = list_folder(&http, recursive).await;
-        let mut json: JSON = json::from_res(&res);
-        add_files_to_list(&json, &sqlite).await.unwrap();
-
-        let mut has_more = json::get_has_more(&json);
-        let mut cursor: String;
-        while has_more == true {
-            println!("🗄️  has_more is {}", has_more);
-            cursor = json::get_cursor(&json);
-            println!("🗄️  Getting next page of results...");
-            res = list_folder_continue(&http, &cursor).await;
-            json = json::from_res(&res);
-            println!("🗄️  Adding results to database...");
-            add_files_to_list(&json, &sqlite).await.unwrap();
-            has_more = json::get_has_more(&json);
+/// Waits up to `LONGPOLL_TIMEOUT` seconds for anything under `cursor` to change. The
+/// notify endpoint takes no auth header: the cursor identifies the folder. A cursor
+/// Dropbox has reset counts as a change, so the next `sync` lists everything again.
+pub async fn longpoll(http: &HTTPClient, cursor: &str) -> Result<json::LongPoll> {
+    let mut headers = HeaderMap::new();
+    headers = http::dropbox_content_type_json_header(&mut headers)?;
+    let body = serde_json::json!({ "cursor": cursor, "timeout": LONGPOLL_TIMEOUT }).to_string();
+    let res = retry::send_text("files/list_folder/longpoll", || {
+        http.post("https://notify.dropboxapi.com/2/files/list_folder/longpoll")
+            .headers(headers.clone())
+            .body(body.clone())
+    })
+    .await?;
+    match json::from_res(&res) {
+        Err(DeepFreezeError::Dropbox(summary)) if summary.starts_with("reset") => {
+            Ok(json::LongPoll {
+                changes: true,
+                backoff: None,
+            })
         }
-        print!("\n");
+        json => json::get_longpoll(&json?),
     }
-    db::report_status(&sqlite).try_into().unwrap()
 }
 
-pub async fn get_file_metadata(http: &HTTPClient, dropbox_path: &str) -> String {
+pub async fn _get_file_metadata(http: &HTTPClient, dropbox_path: &str) -> Result<String> {
     let mut headers = HeaderMap::new();
-    headers = http::dropbox_authorization_header(&mut headers);
-    headers = http::dropbox_select_user_header(&mut headers);
-    headers = http::dropbox_content_type_json_header(&mut headers);
+    headers = http::dropbox_authorization_header(&mut headers)?;
+    headers = http::dropbox_select_user_header(&mut headers)?;
+    headers = http::dropbox_content_type_json_header(&mut headers)?;
     let body = format!("{{\"path\": \"{dropbox_path}\"}}");
-    http.post("https://api.dropboxapi.com/2/files/get_metadata")
-        .headers(headers)
-        .body(body)
-        .send()
-        .await
-        .unwrap()
-        .text()
-        .await
-        .unwrap()
+    retry::send_text("files/get_metadata", || {
+        http.post("https://api.dropboxapi.com/2/files/get_metadata")
+            .headers(headers.clone())
+            .body(body.clone())
+    })
+    .await
+    .map_err(DeepFreezeError::from)
+}
+
+/// The files under `DROPBOX_BASE_FOLDER`, as the team member chosen at login.
+pub struct DropboxSource {
+    http: HTTPClient,
 }
 
-pub async fn get_dropbox_size(http: &HTTPClient, dropbox_path: &str) -> i64 {
-    let res = get_file_metadata(&http, dropbox_path).await;
-    let json = json::from_res(&res);
-    json::get_size(&json)
+impl DropboxSource {
+    pub fn new(http: &HTTPClient) -> DropboxSource {
+        DropboxSource { http: http.clone() }
+    }
 }
 
-pub async fn download_from_dropbox(
-    http: &reqwest::Client,
+impl Source for DropboxSource {
+    type Error = DeepFreezeError;
+
+    fn kind(&self) -> &str {
+        "dropbox"
+    }
+
+    /// Pages of a recursive `list_folder`. The last page's cursor is where `sync` picks
+    /// up changes from.
+    async fn list(&self, cursor: Option<&str>) -> Result<SourcePage> {
+        let res = match cursor {
+            Some(cursor) => list_folder_continue(&self.http, cursor).await?,
+            None => list_folder(&self.http, true).await?,
+        };
+        let json = json::from_res(&res)?;
+        Ok(SourcePage {
+            entries: json::get_source_entries(&json)?,
+            cursor: Some(json::get_cursor(&json)?),
+            has_more: json::get_has_more(&json)?,
+        })
+    }
+
+    async fn fetch(&self, id: &str, offset: u64) -> Result<SourceStream<DeepFreezeError>> {
+        let stream = download_stream(&self.http, id, offset).await?;
+        Ok(Box::pin(stream.map_err(DeepFreezeError::from)))
+    }
+
+    /// Access tokens only last a few hours, so each file starts with a fresh one.
+    async fn prepare(&self) -> Result<()> {
+        auth::refresh_token(&self.http).await?;
+        Ok(())
+    }
+}
+
+/// Opens a download of `dropbox_id` from byte `offset` on and returns the response body
+/// as a byte stream, without touching the local filesystem.
+pub async fn download_stream(
+    http: &HTTPClient,
     dropbox_id: &str,
-    _dropbox_path: &str,
-    local_path: &str,
-    m: &&crate::progress::MultiProgress,
-) {
-    let dropbox_size = get_dropbox_size(http, dropbox_id).await;
+    offset: u64,
+) -> Result<impl Stream<Item = reqwest::Result<Bytes>>> {
     let mut headers = HeaderMap::new();
-    headers = http::dropbox_authorization_header(&mut headers);
-    headers = http::dropbox_select_user_header(&mut headers);
+    headers = http::dropbox_authorization_header(&mut headers)?;
+    headers = http::dropbox_select_user_header(&mut headers)?;
     headers.insert(
         "Dropbox-API-Arg",
-        format!("{{\"path\":\"{dropbox_id}\"}}").parse().unwrap(),
+        format!("{{\"path\":\"{dropbox_id}\"}}").parse()?,
     );
-    let res = http
-        .post("https://content.dropboxapi.com/2/files/download")
-        .headers(headers)
-        .send()
-        .await
-        .unwrap();
-    let mut stream = res.bytes_stream();
-    let mut file: tokio::fs::File;
-    let mut downloaded: u64 = 0;
-    let pb = m.add(progress::new(dropbox_size as u64, "file_transfer"));
-    pb.set_prefix("⬇️   Download  ");
-    if localfs::get_local_size(&local_path).await != dropbox_size {
-        localfs::delete_local_file(local_path).await;
-        file = localfs::get_local_file(&local_path).await;
-        while let Some(item) = stream.next().await {
-            let chunk = item
-                .or(Err(format!("❌  Error while downloading file")))
-                .unwrap();
-            let new = min(downloaded + (chunk.len() as u64), dropbox_size as u64);
-            downloaded = new;
-            pb.set_position(downloaded);
-            file.write(&chunk).await.unwrap();
+    if offset > 0 {
+        headers.insert(RANGE, format!("bytes={offset}-").parse()?);
+    }
+    let res = retry::send("files/download", || {
+        http.post("https://content.dropboxapi.com/2/files/download")
+            .headers(headers.clone())
+    })
+    .await?;
+    if offset > 0 && res.status() == StatusCode::OK {
+        return Err(DeepFreezeError::Dropbox(format!(
+            "asked for {dropbox_id} from byte {offset} but got the whole file"
+        )));
+    }
+    match res.status().is_success() {
+        true => Ok(res.bytes_stream()),
+        false => Err(DeepFreezeError::Dropbox(res.text().await?)),
+    }
+}
+
+/// Sends one `files/upload_session/{endpoint}` request, refreshing the access token once
+/// if it has expired (a restore can wait many hours for S3 before it uploads).
+async fn upload_session(
+    http: &HTTPClient,
+    endpoint: &str,
+    arg: &JSON,
+    chunk: Bytes,
+) -> Result<JSON> {
+    let send = || async {
+        let mut headers = HeaderMap::new();
+        headers = http::dropbox_authorization_header(&mut headers)?;
+        headers = http::dropbox_select_user_header(&mut headers)?;
+        headers = http::dropbox_api_path_root_header(&mut headers)?;
+        headers = http::dropbox_content_type_octet_stream_header(&mut headers)?;
+        headers = http::dropbox_api_arg_header(&mut headers, arg)?;
+        let res = retry::send_text(&format!("files/upload_session/{endpoint}"), || {
+            http.post(format!(
+                "https://content.dropboxapi.com/2/files/upload_session/{endpoint}"
+            ))
+            .headers(headers.clone())
+            .body(chunk.clone())
+        })
+        .await?;
+        json::from_res(&res)
+    };
+    match send().await {
+        Err(DeepFreezeError::Dropbox(summary)) if summary.starts_with("expired_access_token") => {
+            auth::refresh_token(http).await?;
+            send().await
         }
-        assert_eq!(downloaded, dropbox_size as u64);
+        json => json,
+    }
+}
+
+/// Uploads `length` bytes of `local_path` from `offset` on to `dropbox_path`, as the team
+/// member deep-freeze runs as, in an upload session, and returns the `content_hash`
+/// Dropbox computed for it. A different file already at `dropbox_path` is a conflict;
+/// the same file is left as it is, so a restore can be run again.
+pub async fn upload_to_dropbox(
+    http: &HTTPClient,
+    (local_path, offset, length): (&str, u64, u64),
+    dropbox_path: &str,
+    m: &crate::progress::MultiProgress,
+) -> Result<String> {
+    let mut file = localfs::get_local_file(local_path).await?;
+    file.seek(SeekFrom::Start(offset)).await?;
+    let mut file = file.take(length);
+    let pb = m.add(progress::new(length, "file_transfer"));
+    pb.set_prefix("⬆️   Upload    ");
+    let mut session_id: Option<String> = None;
+    let mut uploaded: u64 = 0;
+    loop {
+        let mut chunk = Vec::new();
+        (&mut file)
+            .take(UPLOAD_CHUNK_SIZE)
+            .read_to_end(&mut chunk)
+            .await?;
+        let size = chunk.len() as u64;
+        let Some(id) = &session_id else {
+            let json = upload_session(http, "start", &serde_json::json!({}), chunk.into()).await?;
+            session_id = Some(json::get_str(&json, "session_id")?.to_string());
+            uploaded += size;
+            pb.set_position(uploaded);
+            continue;
+        };
+        let cursor = serde_json::json!({ "session_id": id, "offset": uploaded });
+        if uploaded + size >= length {
+            let commit = serde_json::json!({
+                "cursor": cursor,
+                "commit": { "path": dropbox_path, "mode": "add", "autorename": false, "mute": true },
+            });
+            let json = upload_session(http, "finish", &commit, chunk.into()).await?;
+            pb.finish();
+            pb.set_prefix("✅  Upload    ");
+            return Ok(json::get_str(&json, "content_hash")?.to_string());
+        }
+        if size == 0 {
+            return Err(DeepFreezeError::Integrity(format!(
+                "{local_path} ended after {uploaded} of {length} bytes"
+            )));
+        }
+        upload_session(
+            http,
+            "append_v2",
+            &serde_json::json!({ "cursor": cursor }),
+            chunk.into(),
+        )
+        .await?;
+        uploaded += size;
+        pb.set_position(uploaded);
     }
-    pb.finish();
-    pb.set_prefix("✅  Download ");
 }
 
 #[cfg(test)]
@@ -209,13 +348,15 @@ mod tests {
     async fn it_gets_file_metadata_from_dropbox() {
         dotenv::dotenv().ok();
         ::std::env::set_var("SILENT", "true");
-        let http = crate::http::new_client();
+        let http = crate::http::new_client().unwrap();
         let dropbox_path = "/deep-freeze-test/test-dropbox-download.txt";
-        let res = crate::dropbox::get_file_metadata(&http, dropbox_path).await;
-        let json = crate::json::from_res(&res);
-        let size = crate::json::get_size(&json);
+        let res = crate::dropbox::_get_file_metadata(&http, dropbox_path)
+            .await
+            .unwrap();
+        let json = crate::json::from_res(&res).unwrap();
+        let size = crate::json::get_size(&json).unwrap();
         assert_eq!(size, 22);
-        let id = crate::json::_get_id(&json);
+        let id = crate::json::_get_id(&json).unwrap();
         assert_eq!(id, "id:FVFwt7Ga8wEAAAAAACwqDA")
     }
 
@@ -225,26 +366,29 @@ mod tests {
         ::std::env::set_var("SILENT", "true");
         let base_folder: &str = "/deep-freeze-test";
         let file_name: &str = "test-dropbox-download.txt";
-        let http = crate::http::new_client();
+        let http = crate::http::new_client().unwrap();
         let dropbox_path = format!("{base_folder}/{}", &file_name);
-        let local_path: &str = &format!("test/{}", file_name.to_string());
-        if crate::localfs::local_file_exists(&local_path).await {
-            crate::localfs::delete_local_file(&local_path).await;
-        }
-        let res = crate::dropbox::get_file_metadata(&http, &dropbox_path).await;
-        let json = crate::json::from_res(&res);
-        let dropbox_size = crate::json::get_size(&json);
-        let dropbox_id = crate::json::_get_id(&json);
-        crate::dropbox::download_from_dropbox(
-            &http,
-            &dropbox_id,
-            &dropbox_path,
-            &local_path,
-            &&crate::progress::new_multi_progress(),
+        let local_path: &str = &format!("test/{}", file_name);
+        crate::localfs::delete_local_file(local_path).await.unwrap();
+        let res = crate::dropbox::_get_file_metadata(&http, &dropbox_path)
+            .await
+            .unwrap();
+        let json = crate::json::from_res(&res).unwrap();
+        let dropbox_size = crate::json::get_size(&json).unwrap();
+        let dropbox_id = crate::json::_get_id(&json).unwrap();
+        crate::deepfreeze::download_from_source(
+            &crate::dropbox::DropboxSource::new(&http),
+            (&dropbox_id, dropbox_size as u64),
+            local_path,
+            (
+                &crate::progress::new_multi_progress(),
+                &crate::shutdown::Shutdown::default(),
+            ),
         )
-        .await;
-        let local_size = crate::localfs::get_local_size(&local_path).await;
+        .await
+        .unwrap();
+        let local_size = crate::localfs::get_local_size(local_path).await.unwrap();
         assert_eq!(local_size, dropbox_size);
-        crate::localfs::delete_local_file(&local_path).await;
+        crate::localfs::delete_local_file(local_path).await.unwrap();
     }
 }
@@ -0,0 +1,119 @@
+use crate::checksum::HashMismatch;
+
+use aws_sdk_s3::error::{DisplayErrorContext, SdkError};
+use std::fmt::Debug;
+
+pub type Result<T, E = DeepFreezeError> = std::result::Result<T, E>;
+
+/// Everything that can go wrong while migrating. Errors that concern a single file are
+/// recorded against its row so the rest of the migration can carry on.
+#[derive(Debug, thiserror::Error)]
+pub enum DeepFreezeError {
+    /// Dropbox answered, but with an API error (its `error_summary`).
+    #[error("Dropbox API error: {0}")]
+    Dropbox(String),
+    /// The request to Dropbox (or another HTTP endpoint) never got a usable answer.
+    #[error("HTTP error: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("Unexpected response: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("S3 error: {0}")]
+    S3(String),
+    #[error("SQLite error: {0}")]
+    Sqlite(#[from] sqlite::Error),
+    /// The database was written by a newer deep-freeze with migrations this one doesn't know.
+    #[error("Database schema version {found} is newer than this build supports ({supported}), upgrade deep-freeze")]
+    NewerSchema { found: i64, supported: i64 },
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Auth error: {0}")]
+    Auth(String),
+    #[error("Missing configuration: {0}")]
+    Config(String),
+    #[error(transparent)]
+    HashMismatch(#[from] HashMismatch),
+    /// The object in S3 doesn't match what was uploaded (size or checksum).
+    #[error("Integrity error: {0}")]
+    Integrity(String),
+    /// Shutdown was requested mid-transfer; says how far the file got.
+    #[error("Interrupted: {0}")]
+    Interrupted(String),
+    /// Client-side encryption failed, or an object can't be opened with the key at hand.
+    #[error("Encryption error: {0}")]
+    Encryption(String),
+    /// The SSH connection, or the SFTP server at the other end of it, failed.
+    #[error("SFTP error: {0}")]
+    Sftp(String),
+    #[error("Background task failed: {0}")]
+    Task(#[from] tokio::task::JoinError),
+}
+
+impl<E, R> From<SdkError<E, R>> for DeepFreezeError
+where
+    E: std::error::Error + 'static,
+    R: Debug,
+{
+    fn from(err: SdkError<E, R>) -> Self {
+        DeepFreezeError::S3(DisplayErrorContext(&err).to_string())
+    }
+}
+
+impl From<aws_sdk_s3::Error> for DeepFreezeError {
+    fn from(err: aws_sdk_s3::Error) -> Self {
+        DeepFreezeError::S3(DisplayErrorContext(&err).to_string())
+    }
+}
+
+impl From<aws_sdk_s3::error::BuildError> for DeepFreezeError {
+    fn from(err: aws_sdk_s3::error::BuildError) -> Self {
+        DeepFreezeError::S3(err.to_string())
+    }
+}
+
+impl From<reqwest::header::InvalidHeaderValue> for DeepFreezeError {
+    fn from(err: reqwest::header::InvalidHeaderValue) -> Self {
+        DeepFreezeError::Config(err.to_string())
+    }
+}
+
+impl From<aes_gcm::Error> for DeepFreezeError {
+    fn from(err: aes_gcm::Error) -> Self {
+        DeepFreezeError::Encryption(err.to_string())
+    }
+}
+
+impl From<aes_gcm::aes::cipher::InvalidLength> for DeepFreezeError {
+    fn from(err: aes_gcm::aes::cipher::InvalidLength) -> Self {
+        DeepFreezeError::Encryption(err.to_string())
+    }
+}
+
+impl From<argon2::Error> for DeepFreezeError {
+    fn from(err: argon2::Error) -> Self {
+        DeepFreezeError::Encryption(err.to_string())
+    }
+}
+
+impl From<hex::FromHexError> for DeepFreezeError {
+    fn from(err: hex::FromHexError) -> Self {
+        DeepFreezeError::Encryption(err.to_string())
+    }
+}
+
+impl From<openssh::Error> for DeepFreezeError {
+    fn from(err: openssh::Error) -> Self {
+        DeepFreezeError::Sftp(err.to_string())
+    }
+}
+
+impl From<openssh_sftp_client::Error> for DeepFreezeError {
+    fn from(err: openssh_sftp_client::Error) -> Self {
+        DeepFreezeError::Sftp(err.to_string())
+    }
+}
+
+impl From<inquire::InquireError> for DeepFreezeError {
+    fn from(err: inquire::InquireError) -> Self {
+        DeepFreezeError::Config(err.to_string())
+    }
+}
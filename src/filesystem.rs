@@ -0,0 +1,292 @@
+use crate::checksum;
+use crate::error::{DeepFreezeError, Result};
+use crate::util::getenv;
+use deep_freeze::source::{Source, SourceEntry, SourcePage, SourceStream};
+
+use futures::stream;
+use hyper::body::Bytes;
+use std::collections::HashSet;
+use std::io::SeekFrom;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+/// How many files are hashed and handed to the catalog at a time.
+const PAGE_SIZE: usize = 1000;
+/// How much of a file each item of a `fetch` stream carries.
+const READ_SIZE: usize = 1024 * 1024;
+
+/// What the walk does with a symbolic link.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum SymlinkPolicy {
+    /// Leave it out. Whatever it points to is archived only if it is under the root too.
+    #[default]
+    Skip,
+    /// Archive what it points to, file or folder, under the link's path. A folder that
+    /// has already been walked (a link loop, or a second link to it) is walked once.
+    Follow,
+}
+
+impl std::str::FromStr for SymlinkPolicy {
+    type Err = DeepFreezeError;
+
+    fn from_str(policy: &str) -> Result<Self> {
+        match policy {
+            "" | "skip" => Ok(SymlinkPolicy::Skip),
+            "follow" => Ok(SymlinkPolicy::Follow),
+            other => Err(DeepFreezeError::Config(format!(
+                "SYMLINKS must be skip or follow, not {other}"
+            ))),
+        }
+    }
+}
+
+/// What the walk does with a file that has more than one hard link.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum HardlinkPolicy {
+    /// Archive it once, under the first of its paths the walk comes to.
+    #[default]
+    Once,
+    /// Archive it under every one of its paths.
+    Each,
+}
+
+impl std::str::FromStr for HardlinkPolicy {
+    type Err = DeepFreezeError;
+
+    fn from_str(policy: &str) -> Result<Self> {
+        match policy {
+            "" | "once" => Ok(HardlinkPolicy::Once),
+            "each" => Ok(HardlinkPolicy::Each),
+            other => Err(DeepFreezeError::Config(format!(
+                "HARDLINKS must be once or each, not {other}"
+            ))),
+        }
+    }
+}
+
+/// Where a walk has got to between pages.
+#[derive(Default)]
+struct Walk {
+    folders: Vec<PathBuf>,
+    /// `(device, inode)` of every folder walked, so a link loop is walked once.
+    walked: HashSet<(u64, u64)>,
+    /// `(device, inode)` of every hard linked file listed, for `HardlinkPolicy::Once`.
+    linked: HashSet<(u64, u64)>,
+}
+
+/// A folder on a local disk or a mounted NAS share. A file is known by its absolute
+/// path, which is both its `source_id` and its `source_path`: nothing else about it
+/// survives a remount, so a file that moves is a new file. Sockets, FIFOs and devices
+/// are never archived.
+pub struct FilesystemSource {
+    root: PathBuf,
+    symlinks: SymlinkPolicy,
+    hardlinks: HardlinkPolicy,
+    walk: Mutex<Walk>,
+}
+
+impl FilesystemSource {
+    pub fn new(root: PathBuf, symlinks: SymlinkPolicy, hardlinks: HardlinkPolicy) -> Self {
+        FilesystemSource {
+            root,
+            symlinks,
+            hardlinks,
+            walk: Mutex::new(Walk::default()),
+        }
+    }
+
+    /// The source `LOCAL_ROOT`, `SYMLINKS` and `HARDLINKS` describe.
+    pub fn from_env() -> Result<Self> {
+        Ok(FilesystemSource::new(
+            PathBuf::from(getenv("LOCAL_ROOT")?),
+            getenv("SYMLINKS").unwrap_or_default().parse()?,
+            getenv("HARDLINKS").unwrap_or_default().parse()?,
+        ))
+    }
+
+    /// Looks at one child of a folder being walked: a folder still to walk is queued,
+    /// a file to archive is hashed and returned, and anything else is reported and left
+    /// out.
+    async fn visit(&self, walk: &mut Walk, path: PathBuf) -> Result<Option<SourceEntry>> {
+        let Some(name) = path.to_str().map(str::to_string) else {
+            println!("⏭️  Skipping {}, not a UTF-8 path", path.display());
+            return Ok(None);
+        };
+        let mut metadata = tokio::fs::symlink_metadata(&path).await?;
+        if metadata.file_type().is_symlink() {
+            if self.symlinks == SymlinkPolicy::Skip {
+                println!("🔗  Skipping symlink {name}");
+                return Ok(None);
+            }
+            metadata = match tokio::fs::metadata(&path).await {
+                Ok(metadata) => metadata,
+                Err(_) => {
+                    println!("🔗  Skipping broken symlink {name}");
+                    return Ok(None);
+                }
+            };
+        }
+        let inode = (metadata.dev(), metadata.ino());
+        if metadata.is_dir() {
+            match walk.walked.insert(inode) {
+                true => walk.folders.push(path),
+                false => println!("🔁  Skipping {name}, a folder already walked"),
+            }
+            return Ok(None);
+        }
+        if !metadata.is_file() {
+            println!("⏭️  Skipping {name}, not a regular file");
+            return Ok(None);
+        }
+        if self.hardlinks == HardlinkPolicy::Once
+            && metadata.nlink() > 1
+            && !walk.linked.insert(inode)
+        {
+            println!("🔗  Skipping {name}, a hard link to a file already listed");
+            return Ok(None);
+        }
+        Ok(Some(SourceEntry {
+            id: name.clone(),
+            hash: checksum::dropbox_content_hash_of_file(&name).await?,
+            path: name,
+            size: metadata.len(),
+            modified: Some(metadata.mtime()),
+        }))
+    }
+}
+
+impl Source for FilesystemSource {
+    type Error = DeepFreezeError;
+
+    fn kind(&self) -> &str {
+        "local"
+    }
+
+    /// Walks the tree under the root, folders in name order, hashing each file with
+    /// Dropbox's `content_hash` scheme so every source is verified the same way. The
+    /// cursor only counts the files listed so far; the walk itself is kept in memory, so
+    /// an interrupted listing starts again from the root.
+    async fn list(&self, cursor: Option<&str>) -> Result<SourcePage> {
+        let mut walk = std::mem::take(&mut *self.walk.lock().unwrap());
+        let mut listed: usize = cursor.and_then(|cursor| cursor.parse().ok()).unwrap_or(0);
+        if cursor.is_none() {
+            let metadata = tokio::fs::metadata(&self.root).await?;
+            walk = Walk::default();
+            walk.walked.insert((metadata.dev(), metadata.ino()));
+            walk.folders.push(self.root.clone());
+        }
+        let mut entries = Vec::new();
+        while entries.len() < PAGE_SIZE {
+            let Some(folder) = walk.folders.pop() else {
+                break;
+            };
+            let mut children = Vec::new();
+            let mut dir = tokio::fs::read_dir(&folder).await?;
+            while let Some(child) = dir.next_entry().await? {
+                children.push(child.path());
+            }
+            children.sort();
+            let folders = walk.folders.len();
+            for child in children {
+                entries.extend(self.visit(&mut walk, child).await?);
+            }
+            // Queued in name order, so reversed to come off the stack in name order.
+            walk.folders[folders..].reverse();
+        }
+        listed += entries.len();
+        let has_more = !walk.folders.is_empty();
+        *self.walk.lock().unwrap() = walk;
+        Ok(SourcePage {
+            entries,
+            cursor: has_more.then(|| listed.to_string()),
+            has_more,
+        })
+    }
+
+    async fn fetch(&self, id: &str, offset: u64) -> Result<SourceStream<DeepFreezeError>> {
+        let mut file = tokio::fs::File::open(id).await?;
+        file.seek(SeekFrom::Start(offset)).await?;
+        Ok(Box::pin(stream::try_unfold(file, |mut file| async move {
+            let mut buf = vec![0; READ_SIZE];
+            let read = file.read(&mut buf).await?;
+            buf.truncate(read);
+            Ok((read > 0).then(|| (Bytes::from(buf), file)))
+        })))
+    }
+
+    fn local_path(&self, id: &str) -> Option<String> {
+        Some(id.to_string())
+    }
+}
+
+/// The root a `--local-root` names, made absolute so every path under it is too.
+pub fn absolute_root(root: &str) -> Result<String> {
+    let root = Path::new(root).canonicalize().map_err(|err| {
+        DeepFreezeError::Config(format!("LOCAL_ROOT {root} can't be read: {err}"))
+    })?;
+    root.to_str().map(str::to_string).ok_or_else(|| {
+        DeepFreezeError::Config(format!("LOCAL_ROOT {} isn't UTF-8", root.display()))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::filesystem::{FilesystemSource, HardlinkPolicy, SymlinkPolicy};
+    use deep_freeze::source::Source;
+    use futures::TryStreamExt;
+    use std::path::Path;
+
+    async fn paths(source: FilesystemSource, root: &Path) -> Vec<String> {
+        let page = source.list(None).await.unwrap();
+        assert!(!page.has_more);
+        page.entries
+            .into_iter()
+            .map(|entry| {
+                entry
+                    .path
+                    .trim_start_matches(root.to_str().unwrap())
+                    .to_string()
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn it_walks_a_tree_by_policy() {
+        let root = std::env::temp_dir().join("deep-freeze-filesystem");
+        std::fs::remove_dir_all(&root).ok();
+        std::fs::create_dir_all(root.join("b/c")).unwrap();
+        std::fs::write(root.join("a.txt"), "hello world").unwrap();
+        std::fs::write(root.join("b/c/d.txt"), "d").unwrap();
+        std::fs::hard_link(root.join("a.txt"), root.join("b/hard.txt")).unwrap();
+        std::os::unix::fs::symlink(root.join("b"), root.join("b/c/loop")).unwrap();
+        std::os::unix::fs::symlink(root.join("a.txt"), root.join("soft.txt")).unwrap();
+        let _socket = std::os::unix::net::UnixListener::bind(root.join("socket")).unwrap();
+
+        let skip = FilesystemSource::new(root.clone(), SymlinkPolicy::Skip, HardlinkPolicy::Once);
+        assert_eq!(paths(skip, &root).await, vec!["/a.txt", "/b/c/d.txt"]);
+        let follow =
+            FilesystemSource::new(root.clone(), SymlinkPolicy::Follow, HardlinkPolicy::Each);
+        assert_eq!(
+            paths(follow, &root).await,
+            vec!["/a.txt", "/soft.txt", "/b/hard.txt", "/b/c/d.txt"]
+        );
+
+        let source = FilesystemSource::new(root.clone(), SymlinkPolicy::Skip, HardlinkPolicy::Once);
+        let entry = source.list(None).await.unwrap().entries.remove(0);
+        assert_eq!(entry.size, 11);
+        assert!(entry.modified.is_some());
+        let bytes: Vec<Vec<u8>> = source
+            .fetch(&entry.id, 6)
+            .await
+            .unwrap()
+            .map_ok(|bytes| bytes.to_vec())
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(bytes.concat(), b"world");
+        assert_eq!(source.local_path(&entry.id), Some(entry.path));
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,88 @@
+use crate::aws::{self, AWSClient};
+use crate::db::{self, DBConnection};
+use crate::error::Result;
+use crate::util::getenv;
+
+use indicatif::{HumanBytes, HumanDuration};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Finds multipart uploads in `AWS_S3_BUCKET` that no row in the database could resume,
+/// reports their age and size, and aborts them unless `dry_run` is set. Parts of an
+/// unfinished upload are billed until it is aborted, even though no object is visible.
+/// The bucket may be shared with other tools or other databases, so an upload to a key
+/// this database would never archive under is only reported, unless `all` is set.
+pub async fn collect_garbage(
+    aws: &AWSClient,
+    sqlite: &DBConnection,
+    (dry_run, all): (bool, bool),
+) -> Result<()> {
+    let bucket = getenv("AWS_S3_BUCKET")?;
+    println!("🧹  Looking for orphaned multipart uploads in s3://{bucket}");
+    let uploads = aws::list_multipart_uploads(aws, &bucket).await?;
+    let keys = db::get_object_keys(sqlite)?;
+    let mut orphaned: u64 = 0;
+    let mut orphaned_size: u64 = 0;
+    let mut unknown: u64 = 0;
+    for upload in uploads {
+        let key = upload.key().unwrap_or_default();
+        let upload_id = upload.upload_id().unwrap_or_default();
+        if db::is_resumable_upload(sqlite, upload_id)? {
+            println!("⏯️  Keeping resumable upload of {key}");
+            continue;
+        }
+        if !all && !keys.contains(key) {
+            println!("❔  Leaving the upload of {key} alone, not a key of this database");
+            unknown += 1;
+            continue;
+        }
+        let size: i64 = match aws::list_parts(aws, &bucket, key, upload_id).await {
+            Ok(parts) => parts
+                .iter()
+                .map(|part| part.size().unwrap_or_default())
+                .sum(),
+            Err(err) => {
+                println!("🚫  Could not list parts of {key}: {err}");
+                0
+            }
+        };
+        let age = upload
+            .initiated()
+            .map(|initiated| {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs() as i64;
+                Duration::from_secs((now - initiated.secs()).max(0) as u64)
+            })
+            .unwrap_or_default();
+        println!(
+            "🗑️  {key} ({}, started {} ago)",
+            HumanBytes(size as u64),
+            HumanDuration(age)
+        );
+        orphaned += 1;
+        orphaned_size += size as u64;
+        if dry_run {
+            continue;
+        }
+        match aws::abort_multipart_upload(aws, &bucket, key, upload_id).await {
+            Ok(_) => db::forget_multipart_upload(sqlite, upload_id)?,
+            Err(err) => println!("🚫  {err}"),
+        }
+    }
+    match (orphaned, dry_run) {
+        (0, _) => println!("✨ No orphaned multipart uploads"),
+        (_, true) => println!(
+            "🧹  Dry run: {orphaned} orphaned uploads ({}) left in place",
+            HumanBytes(orphaned_size)
+        ),
+        (_, false) => println!(
+            "🧹  Aborted {orphaned} orphaned uploads ({})",
+            HumanBytes(orphaned_size)
+        ),
+    }
+    if unknown > 0 {
+        println!("❔  {unknown} uploads to other keys left in place, pass --all to abort them too");
+    }
+    Ok(())
+}
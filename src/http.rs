@@ -1,4 +1,6 @@
 use crate::aws::get_app_secret;
+use crate::error::Result;
+use crate::json::JSON;
 use crate::util::getenv;
 
 pub type HTTPClient = reqwest::Client;
@@ -6,73 +8,88 @@ pub type HeaderMap = reqwest::header::HeaderMap;
 
 const APP_KEY: &str = "5mmsu1p6otobzgk";
 
-pub fn new_client() -> HTTPClient {
-    HTTPClient::builder().build().unwrap()
+pub fn new_client() -> Result<HTTPClient> {
+    Ok(HTTPClient::builder().build()?)
 }
 
-pub fn dropbox_authorization_header(headers: &mut HeaderMap) -> HeaderMap {
-    let access_token = getenv("DROPBOX_ACCESS_TOKEN").unwrap();
-    headers.insert(
-        "Authorization",
-        format!("Bearer {}", access_token).parse().unwrap(),
-    );
-    headers.to_owned()
+pub fn dropbox_authorization_header(headers: &mut HeaderMap) -> Result<HeaderMap> {
+    let access_token = getenv("DROPBOX_ACCESS_TOKEN")?;
+    headers.insert("Authorization", format!("Bearer {}", access_token).parse()?);
+    Ok(headers.to_owned())
 }
 
-pub fn dropbox_api_path_root_header(headers: &mut HeaderMap) -> HeaderMap {
+pub fn dropbox_api_path_root_header(headers: &mut HeaderMap) -> Result<HeaderMap> {
     // val: format!("{{\".tag\": \"root\"}}").parse().unwrap()
     // let root_namespace_id = getenv("DROPBOX_ROOT_NAMESPACE_ID");
-    let home_namespace_id = getenv("DROPBOX_HOME_NAMESPACE_ID").unwrap();
+    let home_namespace_id = getenv("DROPBOX_HOME_NAMESPACE_ID")?;
     headers.insert(
         "Dropbox-API-Path-Root",
         format!(
             "{{\".tag\": \"namespace_id\", \"namespace_id\": \"{}\"}}",
             home_namespace_id
         )
-        .parse()
-        .unwrap(),
+        .parse()?,
     );
-    headers.to_owned()
+    Ok(headers.to_owned())
 }
 
-pub fn dropbox_select_user_header(headers: &mut HeaderMap) -> HeaderMap {
-    let team_member_id = getenv("DROPBOX_TEAM_MEMBER_ID").unwrap();
-    headers.insert(
-        "Dropbox-API-Select-User",
-        format!("{}", team_member_id).parse().unwrap(),
-    );
-    headers.to_owned()
+pub fn dropbox_select_user_header(headers: &mut HeaderMap) -> Result<HeaderMap> {
+    let team_member_id = getenv("DROPBOX_TEAM_MEMBER_ID")?;
+    headers.insert("Dropbox-API-Select-User", team_member_id.parse()?);
+    Ok(headers.to_owned())
 }
 
-pub fn dropbox_content_type_json_header(headers: &mut HeaderMap) -> HeaderMap {
-    headers.insert("Content-Type", "application/json".parse().unwrap());
-    headers.to_owned()
+pub fn dropbox_content_type_json_header(headers: &mut HeaderMap) -> Result<HeaderMap> {
+    headers.insert("Content-Type", "application/json".parse()?);
+    Ok(headers.to_owned())
 }
 
-pub fn dropbox_content_type_x_www_form_urlencoded_header(headers: &mut HeaderMap) -> HeaderMap {
-    headers.insert(
-        "Content-Type",
-        "application/x-www-form-urlencoded".parse().unwrap(),
-    );
-    headers.to_owned()
+pub fn dropbox_content_type_octet_stream_header(headers: &mut HeaderMap) -> Result<HeaderMap> {
+    headers.insert("Content-Type", "application/octet-stream".parse()?);
+    Ok(headers.to_owned())
 }
 
-pub async fn dropbox_refresh_token_body() -> String {
-    let refresh_token = getenv("DROPBOX_REFRESH_TOKEN").unwrap();
-    let app_secret = crate::aws::get_app_secret().await;
-    format!(
+/// Content endpoints take their arguments as JSON in the `Dropbox-API-Arg` header, where
+/// anything outside ASCII (like most non-English file names) has to be escaped as `\uXXXX`.
+pub fn dropbox_api_arg_header(headers: &mut HeaderMap, arg: &JSON) -> Result<HeaderMap> {
+    let mut escaped = String::new();
+    for c in arg.to_string().chars() {
+        match c.is_ascii() {
+            true => escaped.push(c),
+            false => {
+                for unit in c.encode_utf16(&mut [0; 2]) {
+                    escaped.push_str(&format!("\\u{unit:04x}"));
+                }
+            }
+        }
+    }
+    headers.insert("Dropbox-API-Arg", escaped.parse()?);
+    Ok(headers.to_owned())
+}
+
+pub fn dropbox_content_type_x_www_form_urlencoded_header(
+    headers: &mut HeaderMap,
+) -> Result<HeaderMap> {
+    headers.insert("Content-Type", "application/x-www-form-urlencoded".parse()?);
+    Ok(headers.to_owned())
+}
+
+pub async fn dropbox_refresh_token_body() -> Result<String> {
+    let refresh_token = getenv("DROPBOX_REFRESH_TOKEN")?;
+    let app_secret = crate::aws::get_app_secret().await?;
+    Ok(format!(
         "refresh_token={}&grant_type=refresh_token&client_id={}&client_secret={}",
         refresh_token, APP_KEY, app_secret
-    )
+    ))
 }
 
-pub async fn dropbox_oauth2_token_body() -> String {
-    let authorization_code = getenv("DROPBOX_AUTHORIZATION_CODE").unwrap();
-    let app_secret = get_app_secret().await;
-    format!(
+pub async fn dropbox_oauth2_token_body() -> Result<String> {
+    let authorization_code = getenv("DROPBOX_AUTHORIZATION_CODE")?;
+    let app_secret = get_app_secret().await?;
+    Ok(format!(
         "code={}&grant_type=authorization_code&client_id={}&client_secret={}",
         authorization_code, APP_KEY, app_secret
-    )
+    ))
 }
 
 pub fn dropbox_authorization_code_url() -> String {
@@ -89,3 +106,17 @@ pub fn dropbox_authorization_code_url() -> String {
 //     );
 //     headers.to_owned()
 // }
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn it_escapes_non_ascii_api_args() {
+        let mut headers = crate::http::HeaderMap::new();
+        let arg = serde_json::json!({ "path": "/Fotos/Café 🧊.jpg" });
+        let headers = crate::http::dropbox_api_arg_header(&mut headers, &arg).unwrap();
+        assert_eq!(
+            headers["Dropbox-API-Arg"],
+            r#"{"path":"/Fotos/Caf\u00e9 \ud83e\uddca.jpg"}"#
+        );
+    }
+}
@@ -1,48 +1,127 @@
+use crate::error::{DeepFreezeError, Result};
+use deep_freeze::source::SourceEntry;
+use std::time::Duration;
+
+#[allow(clippy::upper_case_acronyms)]
 pub type JSON = serde_json::Value;
 
-pub fn from_res(res: &String) -> JSON {
-    match serde_json::from_str::<JSON>(res) {
-        Ok(json) => json,
-        Err(e) => {
-            dbg!(&e);
-            panic!("❌  Error: {e}")
-        }
+/// Parses a Dropbox response, turning an API error body into `DeepFreezeError::Dropbox`.
+pub fn from_res(res: &str) -> Result<JSON> {
+    let json = serde_json::from_str::<JSON>(res)?;
+    match json.get("error") {
+        Some(_) => Err(DeepFreezeError::Dropbox(error_summary(&json))),
+        None => Ok(json),
     }
 }
 
-pub fn get_entries(json: &JSON) -> &Vec<JSON> {
-    json.get("entries").unwrap().as_array().unwrap()
+/// The `error_summary` Dropbox sends with every API error, e.g. `expired_access_token/..`.
+pub fn error_summary(json: &JSON) -> String {
+    json.get("error_summary")
+        .and_then(|summary| summary.as_str())
+        .map(str::to_string)
+        .unwrap_or_else(|| json.to_string())
 }
 
-pub fn get_has_more(json: &JSON) -> bool {
-    json.get("has_more").unwrap().as_bool().unwrap()
+fn field<'a>(json: &'a JSON, key: &str) -> Result<&'a JSON> {
+    json.get(key)
+        .ok_or_else(|| DeepFreezeError::Dropbox(format!("response has no `{key}`: {json}")))
 }
 
-pub fn get_cursor(json: &JSON) -> String {
-    json.get("cursor").unwrap().to_string().to_owned()
+pub fn get_str<'a>(json: &'a JSON, key: &str) -> Result<&'a str> {
+    field(json, key)?
+        .as_str()
+        .ok_or_else(|| DeepFreezeError::Dropbox(format!("`{key}` is not a string: {json}")))
 }
 
-pub fn get_size(json: &JSON) -> i64 {
-    match json.get("size").unwrap().as_i64() {
-        Some(size) => size,
-        None => {
-            println!("Your access token is likely expired. Please run `deepfreeze` again, we'll get this handled automatically in a future release.");
-            // TODO call auth::refresh_token(), need way to get http client in this function without drilling it through all the way from main()
-            std::process::exit(1)
-        }
-    }
+pub fn get_entries(json: &JSON) -> Result<&Vec<JSON>> {
+    field(json, "entries")?
+        .as_array()
+        .ok_or_else(|| DeepFreezeError::Dropbox(format!("`entries` is not a list: {json}")))
 }
 
-pub fn count_files(json: &JSON) -> usize {
-    json.get("entries")
-        .unwrap()
-        .as_array()
-        .unwrap()
+/// The files of a `list_folder` page; folders are left out.
+pub fn get_source_entries(json: &JSON) -> Result<Vec<SourceEntry>> {
+    get_entries(json)?
         .iter()
-        .filter(|row| row.get(".tag").unwrap().as_str().unwrap() == "file")
-        .count()
+        .filter(|entry| entry.get(".tag").and_then(|tag| tag.as_str()) == Some("file"))
+        .map(|entry| {
+            Ok(SourceEntry {
+                id: get_str(entry, "id")?.to_string(),
+                path: get_str(entry, "path_display")?.to_string(),
+                size: get_size(entry)? as u64,
+                hash: get_str(entry, "content_hash")?.to_string(),
+                modified: None,
+            })
+        })
+        .collect()
+}
+
+pub fn get_has_more(json: &JSON) -> Result<bool> {
+    field(json, "has_more")?
+        .as_bool()
+        .ok_or_else(|| DeepFreezeError::Dropbox(format!("`has_more` is not a bool: {json}")))
+}
+
+pub fn get_cursor(json: &JSON) -> Result<String> {
+    Ok(get_str(json, "cursor")?.to_string())
+}
+
+/// What `files/list_folder/longpoll` reported: whether anything changed, and how long
+/// Dropbox wants us to wait before polling again.
+#[derive(Debug, PartialEq)]
+pub struct LongPoll {
+    pub changes: bool,
+    pub backoff: Option<Duration>,
 }
 
-pub fn _get_id(json: &JSON) -> String {
-    json.get("id").unwrap().as_str().unwrap().to_string()
+pub fn get_longpoll(json: &JSON) -> Result<LongPoll> {
+    let changes = field(json, "changes")?
+        .as_bool()
+        .ok_or_else(|| DeepFreezeError::Dropbox(format!("`changes` is not a bool: {json}")))?;
+    let backoff = json
+        .get("backoff")
+        .and_then(|backoff| backoff.as_u64())
+        .map(Duration::from_secs);
+    Ok(LongPoll { changes, backoff })
+}
+
+pub fn get_size(json: &JSON) -> Result<i64> {
+    field(json, "size")?
+        .as_i64()
+        .ok_or_else(|| DeepFreezeError::Dropbox(format!("`size` is not a number: {json}")))
+}
+
+pub fn _get_id(json: &JSON) -> Result<String> {
+    Ok(get_str(json, "id")?.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::DeepFreezeError;
+
+    #[test]
+    fn it_turns_dropbox_errors_into_results() {
+        let res = r#"{"error_summary": "expired_access_token/..", "error": {".tag": "expired_access_token"}}"#;
+        assert!(matches!(
+            crate::json::from_res(res),
+            Err(DeepFreezeError::Dropbox(summary)) if summary == "expired_access_token/.."
+        ));
+        let json = crate::json::from_res(r#"{"size": 22}"#).unwrap();
+        assert_eq!(crate::json::get_size(&json).unwrap(), 22);
+        assert!(crate::json::get_cursor(&json).is_err());
+    }
+
+    #[test]
+    fn it_reads_longpoll_backoff() {
+        let json = crate::json::from_res(r#"{"changes": false, "backoff": 60}"#).unwrap();
+        assert_eq!(
+            crate::json::get_longpoll(&json).unwrap(),
+            crate::json::LongPoll {
+                changes: false,
+                backoff: Some(std::time::Duration::from_secs(60)),
+            }
+        );
+        let json = crate::json::from_res(r#"{"changes": true}"#).unwrap();
+        assert!(crate::json::get_longpoll(&json).unwrap().changes);
+    }
 }
@@ -5,6 +5,8 @@ use hyper::body::Bytes;
 use std::{path::PathBuf, task::Poll};
 use tokio::{fs::File, io::AsyncReadExt};
 
+pub mod source;
+
 const DEFAULT_BUFFER_SIZE: usize = 2048;
 
 pub const MIN_CHUNK_SIZE: u64 = 5242880; // 5 MiB in bytes
@@ -100,7 +102,7 @@ impl<I: AsyncReadExt + Unpin + Send + Sync + 'static> TrackableBodyStream<I> {
                 dyn Stream<
                         Item = Result<
                             hyper::body::Bytes,
-                            Box<(dyn std::error::Error + Sync + std::marker::Send + 'static)>,
+                            Box<dyn std::error::Error + Sync + std::marker::Send + 'static>,
                         >,
                     > + Send,
             >));
@@ -129,12 +131,8 @@ impl<I: AsyncReadExt + Unpin> Stream for TrackableBodyStream<I> {
                     return Poll::Ready(None);
                 }
                 mut_self.cur_read += read_op as u64;
-                if mut_self.callback.is_some() {
-                    mut_self.callback.as_ref().unwrap()(
-                        mut_self.file_size,
-                        mut_self.cur_read,
-                        read_op as u64,
-                    );
+                if let Some(callback) = mut_self.callback.as_ref() {
+                    callback(mut_self.file_size, mut_self.cur_read, read_op as u64);
                 }
                 Poll::Ready(Some(Ok(Bytes::from(Vec::from(&buf[0..read_op])))))
             }
@@ -5,9 +5,9 @@ use tokio::fs::{self, File, OpenOptions};
 use tokio::io::{self, AsyncWriteExt};
 
 pub async fn update_env_file(key: &str, value: String) -> io::Result<()> {
-    let env_filename = getenv("ENV_FILE").unwrap();
+    let env_filename = getenv("ENV_FILE").unwrap_or(".env".to_string());
     let env_temp_filename = format!("{env_filename}.temp", env_filename = &env_filename);
-    let mut currentenv = match local_file_exists(&env_filename).await {
+    let mut currentenv = match local_file_exists(&env_filename).await? {
         true => fs::read_to_string(&env_filename).await?,
         false => "".to_string(),
     };
@@ -28,85 +28,91 @@ pub async fn update_env_file(key: &str, value: String) -> io::Result<()> {
             newenv = currentenv;
         }
     }
-    newenv.push_str("\n");
+    newenv.push('\n');
     let mut dst = File::create(&env_temp_filename).await?;
     dst.write_all(newenv.as_bytes()).await?;
 
     fs::rename(env_temp_filename, &env_filename).await?;
     dotenv::from_filename(env_filename).ok();
-    assert_eq!(getenv(key).unwrap(), value);
     Ok(())
 }
 
-pub async fn delete_local_file(local_path: &str) {
-    if local_file_exists(local_path).await {
-        fs::remove_file(&local_path).await.unwrap();
+pub async fn delete_local_file(local_path: &str) -> io::Result<()> {
+    if local_file_exists(local_path).await? {
+        fs::remove_file(&local_path).await?;
     }
+    Ok(())
+}
+
+pub async fn local_file_exists(local_path: &str) -> io::Result<bool> {
+    fs::try_exists(local_path).await
 }
 
-pub async fn local_file_exists(local_path: &str) -> bool {
-    fs::try_exists(local_path).await.unwrap()
+/// Where the temp copy of `name` goes, under `TEMP_DIR`.
+pub fn temp_path(name: &str) -> String {
+    format!(
+        "{}/{name}",
+        getenv("TEMP_DIR").unwrap_or("temp".to_string())
+    )
 }
 
-pub async fn reset() {
+pub async fn reset() -> io::Result<()> {
     let temp_path = getenv("TEMP_DIR").unwrap_or("temp".to_string());
-    delete_local_dir(temp_path.as_str()).await;
+    delete_local_dir(temp_path.as_str()).await?;
     let env_path = getenv("ENV_FILE").unwrap_or(".env".to_string());
-    delete_local_file(env_path.as_str()).await;
-    fs::create_dir_all(temp_path).await.unwrap();
+    delete_local_file(env_path.as_str()).await?;
+    fs::create_dir_all(temp_path).await
 }
 
-async fn delete_local_dir(local_path: &str) {
-    if local_file_exists(local_path).await {
-        fs::remove_dir_all(local_path).await.unwrap()
+async fn delete_local_dir(local_path: &str) -> io::Result<()> {
+    if local_file_exists(local_path).await? {
+        fs::remove_dir_all(local_path).await?;
     }
+    Ok(())
 }
 
-pub async fn get_local_file(local_path: &str) -> File {
-    if local_file_exists(local_path).await {
+pub async fn get_local_file(local_path: &str) -> io::Result<File> {
+    if local_file_exists(local_path).await? {
         OpenOptions::new()
             .read(true)
             .write(true)
             .open(local_path)
             .await
-            .unwrap()
     } else {
-        create_download_folder(local_path).await;
+        create_download_folder(local_path).await?;
         create_local_file(local_path).await
     }
 }
 
-pub async fn create_local_file(local_path: &str) -> File {
-    match File::create(&local_path).await {
-        Ok(file) => file,
-        Err(e) => panic!("❌  Failed to create file '{}': {}", local_path, e),
-    }
+pub async fn create_local_file(local_path: &str) -> io::Result<File> {
+    File::create(&local_path).await
 }
 
-pub async fn create_download_folder(local_path: &str) -> () {
-    let path = Path::new(&local_path);
-    let prefix = path.parent().unwrap();
-    fs::create_dir_all(prefix).await.unwrap();
+pub async fn create_download_folder(local_path: &str) -> io::Result<()> {
+    match Path::new(&local_path).parent() {
+        Some(prefix) => fs::create_dir_all(prefix).await,
+        None => Ok(()),
+    }
 }
 
-pub async fn get_local_size(local_path: &str) -> i64 {
-    let file_size = if local_file_exists(&local_path).await {
-        fs::metadata(local_path).await.unwrap().len()
+pub async fn get_local_size(local_path: &str) -> io::Result<i64> {
+    let file_size = if local_file_exists(local_path).await? {
+        fs::metadata(local_path).await?.len()
     } else {
         0
     };
-    file_size.try_into().unwrap()
+    Ok(file_size as i64)
 }
 
 pub async fn _create_test_file(key: &str, bytes: i64) -> File {
     use rand::{distributions::Alphanumeric, thread_rng, Rng};
-    let mut file = create_local_file(&key).await;
+    let mut file = create_local_file(key).await.unwrap();
     // let pb = crate::progress::new(bytes);
     // let msg = format!("Creating sample file.");
     // pb.set_message(msg);
     // pb.set_position(0);
-    while get_local_size(key).await < bytes {
-        let position = get_local_size(key).await;
+    while get_local_size(key).await.unwrap() < bytes {
+        let position = get_local_size(key).await.unwrap();
         let rand_string: String = thread_rng()
             .sample_iter(&Alphanumeric)
             .take((bytes - position - 1) as usize)
@@ -136,11 +142,11 @@ mod tests {
     async fn it_creates_a_local_1_kb_file() {
         let key = "test/1-kb-generated.txt";
         let bytes = 1024;
-        let file: tokio::fs::File;
-        if crate::localfs::local_file_exists(&key.to_string()).await {
-            crate::localfs::delete_local_file(&key).await;
+
+        if crate::localfs::local_file_exists(key).await.unwrap() {
+            crate::localfs::delete_local_file(key).await.unwrap();
         }
-        file = crate::localfs::_create_test_file(&key, bytes).await;
+        let file: tokio::fs::File = crate::localfs::_create_test_file(key, bytes).await;
         let file_size: i64 = file.metadata().await.unwrap().len() as i64;
         assert_eq!(file_size, bytes);
     }
@@ -149,11 +155,11 @@ mod tests {
     async fn it_creates_a_local_5_mb_file() {
         let key = "test/5-MiB-generated.txt";
         let bytes = 5 * 1024 * 1024;
-        let file: tokio::fs::File;
-        if crate::localfs::local_file_exists(&key.to_string()).await {
-            crate::localfs::delete_local_file(&key).await;
+
+        if crate::localfs::local_file_exists(key).await.unwrap() {
+            crate::localfs::delete_local_file(key).await.unwrap();
         }
-        file = crate::localfs::_create_test_file(&key, bytes).await;
+        let file: tokio::fs::File = crate::localfs::_create_test_file(key, bytes).await;
         let file_size = file.metadata().await.unwrap().len() as i64;
         assert_eq!(file_size, bytes);
     }
@@ -161,14 +167,16 @@ mod tests {
     #[tokio::test]
     #[ignore]
     async fn it_creates_five_1_mb_test_files() {
-        let bytes = 1 * 1024 * 1024;
+        let bytes = 1024 * 1024;
         for i in 1..6 {
-            let file: tokio::fs::File;
             let key = format!("test/1-MiB-generated-{i}.txt");
-            if crate::localfs::local_file_exists(&key.to_string()).await {
-                crate::localfs::delete_local_file(&key).await;
+            if crate::localfs::local_file_exists(&key.to_string())
+                .await
+                .unwrap()
+            {
+                crate::localfs::delete_local_file(&key).await.unwrap();
             }
-            file = crate::localfs::_create_test_file(&key, bytes).await;
+            let file: tokio::fs::File = crate::localfs::_create_test_file(&key, bytes).await;
             let file_size = file.metadata().await.unwrap().len() as i64;
             assert_eq!(file_size, bytes);
         }
@@ -1,25 +1,40 @@
 mod auth;
 mod aws;
+mod bundle;
+mod checksum;
+mod compress;
+mod crypto;
 mod db;
 mod deepfreeze;
 mod dropbox;
+mod error;
+mod filesystem;
+mod gc;
 mod http;
 mod json;
 mod localfs;
+mod plan;
 mod progress;
+mod restore;
+mod retry;
+mod sftp;
+mod shutdown;
 mod util;
 
 use aws::AWSClient;
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use db::DBConnection;
+use deep_freeze::source::Source;
+use error::{DeepFreezeError, Result};
 use http::HTTPClient;
 use std::process;
-use tokio::signal;
 use util::{getenv, setenv, setenv_for_e2e};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
     /// Dropbox access token
     #[arg(long, default_value = "")]
     access_token: String,
@@ -35,18 +50,68 @@ struct Args {
     /// AWS region
     #[arg(long, default_value = "")]
     aws_region: String,
+    /// Pack small files of the same folder into tar bundles before archiving them
+    #[arg(short, long, default_value = "false")]
+    bundle: bool,
+    /// Files under this many bytes are bundled (with --bundle)
+    #[arg(long, default_value_t = bundle::DEFAULT_BUNDLE_THRESHOLD)]
+    bundle_threshold: i64,
+    /// Start a new bundle once one reaches this many bytes (with --bundle)
+    #[arg(long, default_value_t = bundle::DEFAULT_BUNDLE_SIZE)]
+    bundle_size: u64,
     /// Check the migration status of files
     #[arg(short, long, default_value = "false")]
     check_only: bool,
+    /// Compress files of the --compress-types with zstd before archiving them
+    #[arg(long, default_value = "false")]
+    compress: bool,
+    /// Extensions, MIME types and MIME type families (e.g. "log,application/json,text/*")
+    /// to compress with --compress
+    #[arg(long, default_value = compress::DEFAULT_COMPRESS_TYPES)]
+    compress_types: String,
     /// Path to the sqlite database file
     #[arg(long, default_value = "db.sqlite")]
     dbfile: String,
+    /// Upload each distinct Dropbox content_hash once, archiving identical files as
+    /// references to its object
+    #[arg(long, default_value = "false")]
+    dedup: bool,
+    /// What `sync` does with the S3 object of a file deleted or moved in Dropbox: keep it,
+    /// copy (archive a moved file again under its new key), or tag it as deleted
+    #[arg(long, default_value = "keep", value_parser = ["keep", "copy", "tag"])]
+    deletion_policy: String,
+    /// Stream Dropbox downloads straight into S3 instead of through the temp directory
+    #[arg(short, long, default_value = "false")]
+    direct: bool,
     /// Path to the .env file
     #[arg(short = 'v', long, default_value = ".env")]
     env_file: String,
+    /// Encrypt files before uploading them, with a key file or a passphrase (read from
+    /// ENCRYPTION_PASSPHRASE, or asked for)
+    #[arg(long, default_value = "none", value_parser = ["none", "key-file", "passphrase"])]
+    encryption: String,
+    /// Path to the 32-byte key (raw or hex) for --encryption key-file
+    #[arg(long, default_value = "")]
+    encryption_key_file: String,
     /// Run the program end-to-end with test values
     #[arg(short, long, default_value = "false")]
     e2e: bool,
+    /// With --source local, archive a file with several hard links once (under the
+    /// first path walked) or under each of its paths
+    #[arg(long, default_value = "once", value_parser = ["once", "each"])]
+    hardlinks: String,
+    /// Number of files to migrate concurrently
+    #[arg(short, long, default_value = "1")]
+    jobs: usize,
+    /// Folder (a local disk or a mounted NAS share) to archive with --source local
+    #[arg(long, default_value = "")]
+    local_root: String,
+    /// How many times to try each Dropbox or S3 request before giving up
+    #[arg(long, default_value = "5")]
+    max_attempts: u32,
+    /// Number of multipart chunks to buffer and upload at once when streaming
+    #[arg(long, default_value = "4")]
+    parallel_parts: usize,
     /// Reset the database and temp files
     #[arg(short, long, default_value = "false")]
     reset: bool,
@@ -62,110 +127,333 @@ struct Args {
     /// Skip these paths (e.g. --skip "path1,path2")
     #[arg(long)]
     skip: Vec<String>,
+    /// Where files are migrated from: Dropbox, the --local-root folder, or the
+    /// --sftp-root folder on --sftp-host
+    #[arg(long, default_value = "dropbox", value_parser = ["dropbox", "local", "sftp"])]
+    source: String,
+    /// SSH destination to archive with --source sftp ([user@]host or ssh://[user@]host[:port])
+    #[arg(long, default_value = "")]
+    sftp_host: String,
+    /// Absolute path of the remote folder to archive with --source sftp
+    #[arg(long, default_value = "")]
+    sftp_root: String,
     /// Display the migration status of files, then exit
     #[arg(long, default_value = "false")]
     status_only: bool,
+    /// With --source local, leave symlinks out or archive what they point to
+    #[arg(long, default_value = "skip", value_parser = ["skip", "follow"])]
+    symlinks: String,
     /// Path to the temp directory
     #[arg(long, default_value = "temp")]
     temp_dir: String,
 }
 
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Catch up with files added, changed, moved or deleted in Dropbox since the last listing, then migrate
+    Sync,
+    /// Keep syncing and migrating as files change in Dropbox, until SIGTERM or Ctrl-C
+    Watch,
+    /// List the folder into the database and report what a migration would upload and cost, without transferring anything
+    Plan,
+    /// Abort multipart uploads this database started that none of its files can resume
+    Gc {
+        /// List orphaned uploads without aborting them
+        #[arg(long, default_value = "false")]
+        dry_run: bool,
+        /// Also abort uploads to keys this database knows nothing about, e.g. from other tools
+        #[arg(long, default_value = "false")]
+        all: bool,
+    },
+    /// Request archived files back from Deep Archive and download them once restored
+    Restore {
+        /// Dropbox paths, folders or globs (e.g. "/Photos/2019/*.jpg") of the files to restore
+        #[arg(required = true)]
+        paths: Vec<String>,
+        /// Restore tier: standard (within 12 hours) or bulk (within 48 hours, cheaper)
+        #[arg(long, default_value = "bulk", value_parser = ["standard", "bulk"])]
+        tier: String,
+        /// Days to keep the restored copies readable in S3
+        #[arg(long, default_value = "7")]
+        days: i32,
+        /// Folder to download the files into, keeping their Dropbox paths
+        #[arg(long, default_value = "restored")]
+        to: String,
+        /// Upload the files back into Dropbox, as the same team member, instead of downloading them
+        #[arg(long, default_value = "false")]
+        dropbox: bool,
+        /// With --dropbox, restore the files below this Dropbox folder instead of at their original paths
+        #[arg(long, default_value = "")]
+        dropbox_folder: String,
+    },
+}
+
 #[tokio::main]
 async fn main() {
     print!("\n🧊🧊🧊 Deep Freeze - Migrate Files to S3 Deep Archive 🧊🧊🧊\n\n");
-    let (database, http, aws) = init(Args::parse()).await;
+    if let Err(err) = run().await {
+        cleanup().await.ok();
+        eprintln!("🚨  Exiting with error {err}");
+        process::exit(1)
+    }
+}
 
-    auth::check_account(&http, &database).await;
-    dropbox::get_paths(&http, &database).await;
-    deepfreeze::perform_migration(http, database, aws).await;
+async fn run() -> Result<()> {
+    let mut args = Args::parse();
+    let command = args.command.take();
+    let (database, http, aws) = init(args).await?;
 
-    cleanup().await;
+    if let Some(Command::Gc { dry_run, all }) = command {
+        gc::collect_garbage(&aws, &database, (dry_run, all)).await?;
+        println!("✅  Exiting");
+        return Ok(());
+    }
+    if let Some(Command::Restore {
+        paths,
+        tier,
+        days,
+        to,
+        dropbox,
+        dropbox_folder,
+    }) = &command
+    {
+        let target = match dropbox {
+            true => {
+                auth::check_account(&http, &database).await?;
+                restore::Target::Dropbox(&http, dropbox_folder)
+            }
+            false => restore::Target::Local(to),
+        };
+        let mut shutdown = shutdown::Shutdown::listen();
+        let m = progress::new_multi_progress();
+        restore::restore(
+            &aws,
+            &database,
+            paths,
+            (tier, *days, target),
+            (&m, &mut shutdown),
+        )
+        .await?;
+        cleanup().await?;
+        println!("✅  Exiting");
+        return Ok(());
+    }
 
-    match signal::ctrl_c().await {
-        Ok(_) => {
-            cleanup().await;
-            println!("✅  Exiting");
-            process::exit(0)
+    let kind = getenv("SOURCE")?;
+    if kind != "dropbox" {
+        if let Some(Command::Sync | Command::Watch) = command {
+            return Err(DeepFreezeError::Config(format!(
+                "sync and watch follow changes in Dropbox; a {kind} folder is only listed once, so archiving files added or changed since needs a fresh --dbfile or --reset"
+            )));
         }
-        Err(err) => {
-            cleanup().await;
-            eprintln!("🚨  Exiting with error {}", err);
-            process::exit(1)
+    }
+    if kind == "local" {
+        let source = filesystem::FilesystemSource::from_env()?;
+        return migrate(&source, &command, &database, &aws).await;
+    }
+    if kind == "sftp" {
+        let source = sftp::SftpSource::from_env().await?;
+        return migrate(&source, &command, &database, &aws).await;
+    }
+
+    auth::check_account(&http, &database).await?;
+    let source = dropbox::DropboxSource::new(&http);
+    let mut shutdown = shutdown::Shutdown::listen();
+    match command {
+        Some(Command::Watch) => {
+            deepfreeze::watch(&http, &aws, &database, &mut shutdown).await?;
+        }
+        Some(Command::Sync) => {
+            deepfreeze::sync(&http, &aws, &database).await?;
+            deepfreeze::perform_migration(&source, &database, &aws, &shutdown).await?;
         }
+        _ => return migrate(&source, &command, &database, &aws).await,
     }
+
+    cleanup().await?;
+    println!("✅  Exiting");
+    Ok(())
 }
 
-async fn init(args: Args) -> (DBConnection, HTTPClient, AWSClient) {
-    setenv("ENV_FILE", args.env_file).await;
-    setenv("SILENT", args.silent.to_string()).await;
-    if getenv("SILENT").unwrap() == "true" {
+/// Lists `source` into the database, then reports the plan or migrates it.
+async fn migrate<S: Source<Error = DeepFreezeError>>(
+    source: &S,
+    command: &Option<Command>,
+    database: &DBConnection,
+    aws: &AWSClient,
+) -> Result<()> {
+    deepfreeze::get_paths(source, database).await?;
+    if let Some(Command::Plan) = command {
+        plan::report_plan(database)?;
+        println!("✅  Exiting");
+        return Ok(());
+    }
+    let shutdown = shutdown::Shutdown::listen();
+    deepfreeze::perform_migration(source, database, aws, &shutdown).await?;
+    cleanup().await?;
+    println!("✅  Exiting");
+    Ok(())
+}
+
+async fn init(args: Args) -> Result<(DBConnection, HTTPClient, AWSClient)> {
+    setenv("ENV_FILE", args.env_file).await?;
+    setenv("SILENT", args.silent.to_string()).await?;
+    if getenv("SILENT")? == "true" {
         println!("🔇 Running in silent mode...");
     }
-    setenv("CHECK_ONLY", args.check_only.to_string()).await;
-    setenv("STATUS_ONLY", args.status_only.to_string()).await;
-    setenv("RESET", args.reset.to_string()).await;
-    setenv("RESET_ONLY", args.reset_only.to_string()).await;
+    setenv("CHECK_ONLY", args.check_only.to_string()).await?;
+    setenv("STATUS_ONLY", args.status_only.to_string()).await?;
+    setenv("RESET", args.reset.to_string()).await?;
+    setenv("RESET_ONLY", args.reset_only.to_string()).await?;
     if args.e2e {
-        setenv_for_e2e().await;
+        setenv_for_e2e().await?;
     }
     if getenv("DBFILE").is_err() || args.dbfile != "db.sqlite" {
-        setenv("DBFILE", args.dbfile).await;
+        setenv("DBFILE", args.dbfile).await?;
+    }
+    if getenv("SOURCE").is_err() || args.source != "dropbox" {
+        setenv("SOURCE", args.source).await?;
+    }
+    if !args.local_root.is_empty() {
+        setenv("LOCAL_ROOT", filesystem::absolute_root(&args.local_root)?).await?;
+    }
+    if getenv("SYMLINKS").is_err() || args.symlinks != "skip" {
+        setenv("SYMLINKS", args.symlinks).await?;
+    }
+    if getenv("HARDLINKS").is_err() || args.hardlinks != "once" {
+        setenv("HARDLINKS", args.hardlinks).await?;
+    }
+    if !args.sftp_host.is_empty() {
+        setenv("SFTP_HOST", args.sftp_host).await?;
+    }
+    if !args.sftp_root.is_empty() {
+        setenv("SFTP_ROOT", args.sftp_root).await?;
+    }
+    match getenv("SOURCE")?.as_str() {
+        "local" => println!("💽 Archiving the local folder {}", getenv("LOCAL_ROOT")?),
+        "sftp" => println!(
+            "🔐 Archiving {} on {} over SFTP",
+            getenv("SFTP_ROOT")?,
+            getenv("SFTP_HOST")?
+        ),
+        _ => {}
+    }
+    if getenv("DELETION_POLICY").is_err() || args.deletion_policy != "keep" {
+        setenv("DELETION_POLICY", args.deletion_policy).await?;
+    }
+    if getenv("DEDUP").is_err() || args.dedup {
+        setenv("DEDUP", args.dedup.to_string()).await?;
+    }
+    if getenv("DEDUP")? == "true" {
+        println!("🪞 Archiving identical files once");
+    }
+    if getenv("JOBS").is_err() || args.jobs != 1 {
+        setenv("JOBS", args.jobs.to_string()).await?;
+    }
+    if getenv("BUNDLE").is_err() || args.bundle {
+        setenv("BUNDLE", args.bundle.to_string()).await?;
     }
-    setenv("TEMP_DIR", args.temp_dir).await;
-    if getenv("TEMP_DIR").unwrap() != "temp" {
-        println!("📁 Using temp directory: {}", getenv("TEMP_DIR").unwrap());
+    if getenv("BUNDLE")? == "true" {
+        println!("📦 Bundling small files into tar archives");
     }
-    if getenv("RESET").unwrap() == "true" || getenv("RESET_ONLY").unwrap() == "true" {
-        reset().await;
-        if getenv("RESET_ONLY").unwrap() == "true" {
+    if getenv("BUNDLE_THRESHOLD").is_err()
+        || args.bundle_threshold != bundle::DEFAULT_BUNDLE_THRESHOLD
+    {
+        setenv("BUNDLE_THRESHOLD", args.bundle_threshold.to_string()).await?;
+    }
+    if getenv("BUNDLE_SIZE").is_err() || args.bundle_size != bundle::DEFAULT_BUNDLE_SIZE {
+        setenv("BUNDLE_SIZE", args.bundle_size.to_string()).await?;
+    }
+    if getenv("COMPRESS").is_err() || args.compress {
+        setenv("COMPRESS", args.compress.to_string()).await?;
+    }
+    if getenv("COMPRESS")? == "true" {
+        println!("🗜️ Compressing files with zstd");
+    }
+    if getenv("COMPRESS_TYPES").is_err() || args.compress_types != compress::DEFAULT_COMPRESS_TYPES
+    {
+        setenv("COMPRESS_TYPES", args.compress_types).await?;
+    }
+    if getenv("DIRECT").is_err() || args.direct {
+        setenv("DIRECT", args.direct.to_string()).await?;
+    }
+    if getenv("DIRECT")? == "true" {
+        let source = match getenv("SOURCE")?.as_str() {
+            "local" => "the local folder",
+            "sftp" => "the SFTP server",
+            _ => "Dropbox",
+        };
+        println!("🌊 Streaming directly from {source} to S3");
+    }
+    if getenv("ENCRYPTION").is_err() || args.encryption != "none" {
+        setenv("ENCRYPTION", args.encryption).await?;
+    }
+    if !args.encryption_key_file.is_empty() {
+        setenv("ENCRYPTION_KEY_FILE", args.encryption_key_file).await?;
+    }
+    if getenv("MAX_ATTEMPTS").is_err() || args.max_attempts != retry::DEFAULT_MAX_ATTEMPTS {
+        setenv("MAX_ATTEMPTS", args.max_attempts.to_string()).await?;
+    }
+    if getenv("PARALLEL_PARTS").is_err() || args.parallel_parts != 4 {
+        setenv("PARALLEL_PARTS", args.parallel_parts.to_string()).await?;
+    }
+    setenv("TEMP_DIR", args.temp_dir).await?;
+    if getenv("TEMP_DIR")? != "temp" {
+        println!("📁 Using temp directory: {}", getenv("TEMP_DIR")?);
+    }
+    if getenv("RESET")? == "true" || getenv("RESET_ONLY")? == "true" {
+        reset().await?;
+        if getenv("RESET_ONLY")? == "true" {
             println!("👌  Reset only");
             println!("✅  Exiting");
             process::exit(0)
         }
     }
     if getenv("SKIP").is_err() {
-        setenv("SKIP", args.skip.join(",")).await;
+        setenv("SKIP", args.skip.join(",")).await?;
     }
-    if getenv("SKIP").unwrap() == "" {
+    if getenv("SKIP")?.is_empty() {
         println!("⏭️   Skipping no paths\n");
     } else {
-        print!("⏭️   Skipping paths: {}\n", getenv("SKIP").unwrap());
+        println!("⏭️   Skipping paths: {}", getenv("SKIP")?);
     }
-    println!("🗄️  Using database file: {}\n", getenv("DBFILE").unwrap());
+    println!("🗄️  Using database file: {}\n", getenv("DBFILE")?);
 
     if !args.access_token.is_empty() {
-        setenv("DROPBOX_ACCESS_TOKEN", args.access_token).await;
+        setenv("DROPBOX_ACCESS_TOKEN", args.access_token).await?;
     }
-    if args.aws_access_key_id != "" {
-        setenv("AWS_ACCESS_KEY_ID", args.aws_access_key_id).await;
+    if !args.aws_access_key_id.is_empty() {
+        setenv("AWS_ACCESS_KEY_ID", args.aws_access_key_id).await?;
     }
     if getenv("AWS_ACCESS_KEY_ID").is_err() {
-        let aws_access_key_id = util::prompt("📦  AWS access key ID").await;
-        setenv("AWS_ACCESS_KEY_ID", aws_access_key_id).await;
+        let aws_access_key_id = util::prompt("📦  AWS access key ID").await?;
+        setenv("AWS_ACCESS_KEY_ID", aws_access_key_id).await?;
     }
-    if args.aws_secret_access_key != "" {
-        setenv("AWS_SECRET_ACCESS_KEY", args.aws_secret_access_key).await;
+    if !args.aws_secret_access_key.is_empty() {
+        setenv("AWS_SECRET_ACCESS_KEY", args.aws_secret_access_key).await?;
     }
     if getenv("AWS_SECRET_ACCESS_KEY").is_err() {
-        let aws_secret_access_key = util::prompt("📦  AWS secret access key").await;
-        setenv("AWS_SECRET_ACCESS_KEY", aws_secret_access_key).await;
+        let aws_secret_access_key = util::prompt("📦  AWS secret access key").await?;
+        setenv("AWS_SECRET_ACCESS_KEY", aws_secret_access_key).await?;
     }
-    if args.s3_bucket != "" {
-        setenv("AWS_S3_BUCKET", args.s3_bucket).await;
+    if !args.s3_bucket.is_empty() {
+        setenv("AWS_S3_BUCKET", args.s3_bucket).await?;
     }
 
-    let database: DBConnection = db::connect(getenv("DBFILE").unwrap().as_str());
+    crypto::init().await?;
+
+    let database: DBConnection = db::connect(getenv("DBFILE")?.as_str())?;
 
-    if getenv("STATUS_ONLY").unwrap() == "true" {
-        db::report_status(&database);
+    if getenv("STATUS_ONLY")? == "true" {
+        db::report_status(&database)?;
         println!("✅  Exiting");
         process::exit(0)
     }
 
-    let http: HTTPClient = http::new_client();
+    let http: HTTPClient = http::new_client()?;
 
     if args.auth_only {
-        auth::refresh_token(&http).await;
+        auth::refresh_token(&http).await?;
         println!("✅  Exiting");
         process::exit(0)
     }
@@ -173,36 +461,38 @@ async fn init(args: Args) -> (DBConnection, HTTPClient, AWSClient) {
     let aws: AWSClient = aws::new_client().await;
 
     if getenv("AWS_S3_BUCKET").is_err() {
-        aws::choose_bucket(&aws, &database).await;
+        aws::choose_bucket(&aws, &database).await?;
     }
-    if args.aws_region != "" {
-        setenv("AWS_REGION", args.aws_region).await;
+    if !args.aws_region.is_empty() {
+        setenv("AWS_REGION", args.aws_region).await?;
     }
     if getenv("AWS_REGION").is_err() {
         // let aws_region = util::prompt("📦  AWS region");
-        setenv("AWS_REGION", "us-east-1".to_string()).await;
+        setenv("AWS_REGION", "us-east-1".to_string()).await?;
     }
-    (database, http, aws)
+    Ok((database, http, aws))
 }
 
-async fn reset() {
+async fn reset() -> Result<()> {
     println!("🗑️  Resetting database and temp files");
-    db::reset(getenv("DBFILE").unwrap().as_str()).await;
+    db::reset(getenv("DBFILE")?.as_str()).await?;
     println!("🚮  Database reset");
-    if dotenv::var("E2E").is_ok() && getenv("E2E").unwrap() == "true" {
+    if dotenv::var("E2E").is_ok() && getenv("E2E")? == "true" {
         println!("🗑️  Resetting test bucket");
         crate::aws::_empty_test_bucket().await;
         println!("🚮  Test bucket reset");
     }
-    localfs::reset().await;
+    localfs::reset().await?;
     println!("🚮  Temp & env files deleted");
     print!("🎉 Reset complete\n\n");
+    Ok(())
 }
 
-async fn cleanup() {
-    if getenv("E2E").unwrap() == "true" {
-        localfs::delete_local_file(getenv("DBFILE").unwrap().as_str()).await;
-        localfs::delete_local_file(getenv("ENV_FILE").unwrap().as_str()).await;
+async fn cleanup() -> Result<()> {
+    if getenv("E2E").unwrap_or_default() == "true" {
+        localfs::delete_local_file(getenv("DBFILE")?.as_str()).await?;
+        localfs::delete_local_file(getenv("ENV_FILE")?.as_str()).await?;
         println!("🚮  Test database and env file deleted");
     }
+    Ok(())
 }
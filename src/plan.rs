@@ -0,0 +1,202 @@
+use crate::aws;
+use crate::db::{self, DBConnection};
+use crate::error::Result;
+use crate::util::{self, getenv};
+use deep_freeze::{MAX_CHUNK_SIZE, MAX_UPLOAD_SIZE, MIN_CHUNK_SIZE};
+
+use indicatif::HumanBytes;
+use std::{cmp::Reverse, collections::BTreeMap};
+
+/// S3 list prices in us-east-1, in USD; other regions charge a little more.
+const DEEP_ARCHIVE_GB_MONTH: f64 = 0.00099;
+const STANDARD_GB_MONTH: f64 = 0.023;
+const DEEP_ARCHIVE_PER_1000_REQUESTS: f64 = 0.05;
+/// Every Deep Archive object is also billed for 32 KiB of index at the Deep Archive rate
+/// and 8 KiB of metadata at the S3 Standard rate.
+const DEEP_ARCHIVE_OVERHEAD: u64 = 32 * 1024;
+const STANDARD_OVERHEAD: u64 = 8 * 1024;
+/// Objects deleted earlier are still billed for the rest of this many days.
+const MINIMUM_DAYS: f64 = 180.0;
+const GB: f64 = (1024 * 1024 * 1024) as f64;
+/// Label for files that sit directly in the base folder.
+const BASE_FOLDER: &str = "(base folder)";
+
+#[derive(Debug, Default, PartialEq)]
+pub struct FolderPlan {
+    /// As first seen; Dropbox doesn't always spell a folder the same way in every path.
+    pub name: String,
+    pub files: u64,
+    pub bytes: u64,
+}
+
+/// What migrating the files still in the catalog would take.
+#[derive(Debug, Default)]
+pub struct Plan {
+    /// Keyed by the lowercased top-level folder under the base folder.
+    pub folders: BTreeMap<String, FolderPlan>,
+    pub single_part: u64,
+    pub multipart: u64,
+    /// `UploadPart` requests across all the multipart uploads.
+    pub parts: u64,
+    /// Files over the S3 object size limit, which can't be uploaded at all.
+    pub too_big: u64,
+}
+
+impl Plan {
+    pub fn files(&self) -> u64 {
+        self.folders.values().map(|folder| folder.files).sum()
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.folders.values().map(|folder| folder.bytes).sum()
+    }
+
+    /// Monthly storage in USD, and the part of it that is per-object overhead.
+    pub fn monthly_cost(&self) -> (f64, f64) {
+        let objects = self.single_part + self.multipart;
+        let overhead = objects as f64
+            * (DEEP_ARCHIVE_OVERHEAD as f64 * DEEP_ARCHIVE_GB_MONTH
+                + STANDARD_OVERHEAD as f64 * STANDARD_GB_MONTH)
+            / GB;
+        let data = self.bytes() as f64 * DEEP_ARCHIVE_GB_MONTH / GB;
+        (data + overhead, overhead)
+    }
+
+    /// One-off request fees: a `PutObject` per single-part upload, and a create, a
+    /// complete and one request per part for each multipart upload.
+    pub fn request_cost(&self) -> f64 {
+        let requests = self.single_part + self.multipart * 2 + self.parts;
+        requests as f64 * DEEP_ARCHIVE_PER_1000_REQUESTS / 1000.0
+    }
+}
+
+/// Builds the plan for `files` (path and size), choosing single-part or multipart the
+/// way `aws::upload_to_s3` would, or `aws::stream_upload` when `direct` is set.
+pub fn plan(files: &[(String, i64)], base_folder: &str, direct: bool) -> Result<Plan> {
+    let multipart_from = match direct {
+        true => MIN_CHUNK_SIZE,
+        false => MAX_CHUNK_SIZE,
+    };
+    let mut plan = Plan::default();
+    for (path, size) in files {
+        let size = *size as u64;
+        let name = top_level_folder(path, base_folder);
+        let folder = plan
+            .folders
+            .entry(name.to_lowercase())
+            .or_insert_with(|| FolderPlan {
+                name,
+                ..Default::default()
+            });
+        folder.files += 1;
+        folder.bytes += size;
+        if size >= MAX_UPLOAD_SIZE {
+            plan.too_big += 1;
+        } else if size >= multipart_from {
+            let (_, chunk_count, _) = aws::chunk_math(size)?;
+            plan.multipart += 1;
+            plan.parts += chunk_count;
+        } else {
+            plan.single_part += 1;
+        }
+    }
+    Ok(plan)
+}
+
+/// The first folder of `path` below `base_folder`, compared without regard to case the
+/// way Dropbox compares paths.
+fn top_level_folder(path: &str, base_folder: &str) -> String {
+    let base_folder = base_folder.trim_end_matches('/');
+    let relative = match path.get(..base_folder.len()) {
+        Some(prefix) if prefix.eq_ignore_ascii_case(base_folder) => &path[base_folder.len()..],
+        _ => path,
+    };
+    match relative.trim_start_matches('/').split_once('/') {
+        Some((folder, _)) => folder.to_string(),
+        None => BASE_FOLDER.to_string(),
+    }
+}
+
+/// Reports what a migration of the catalog would upload and roughly what it would cost
+/// in Deep Archive, from the `paths` table alone: nothing is read from Dropbox or S3.
+pub fn report_plan(sqlite: &DBConnection) -> Result<()> {
+    let files = db::get_unmigrated_files(sqlite)?;
+    let base_folder = util::base_folder().unwrap_or_default();
+    let direct = getenv("DIRECT").unwrap_or_default() == "true";
+    let plan = plan(&files, &base_folder, direct)?;
+
+    println!(
+        "📋  Plan: {} files ({}) still to migrate",
+        plan.files(),
+        HumanBytes(plan.bytes())
+    );
+    let mut folders = plan.folders.values().collect::<Vec<_>>();
+    folders.sort_by_key(|folder| Reverse(folder.bytes));
+    for folder in folders {
+        println!(
+            "📁  {}: {} files ({})",
+            folder.name,
+            folder.files,
+            HumanBytes(folder.bytes)
+        );
+    }
+    println!("⬆️   Single-part uploads: {}", plan.single_part);
+    println!(
+        "🧩  Multipart uploads: {} ({} parts)",
+        plan.multipart, plan.parts
+    );
+    if plan.too_big > 0 {
+        println!("🚫  Over the S3 object size limit: {} files", plan.too_big);
+    }
+    let (monthly, overhead) = plan.monthly_cost();
+    println!("💰  Storage: ${monthly:.2} / month (${overhead:.2} of it per-object overhead)");
+    println!(
+        "📅  Minimum charge: ${:.2} (Deep Archive bills every object for at least {MINIMUM_DAYS} days)",
+        monthly * MINIMUM_DAYS / 30.0
+    );
+    println!("📨  Upload requests: ${:.2} one-off", plan.request_cost());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    const GIB: i64 = 1024 * 1024 * 1024;
+
+    #[test]
+    fn it_groups_files_by_top_level_folder() {
+        let files = [
+            ("/Archive/Photos/2020/a.jpg".to_string(), 3),
+            ("/archive/photos/b.jpg".to_string(), 4),
+            ("/Archive/Video/c.mov".to_string(), 6 * GIB),
+            ("/Archive/Video/d.mov".to_string(), 8 * 1024 * 1024),
+            ("/Archive/e.txt".to_string(), 1),
+        ];
+        let plan = crate::plan::plan(&files, "/Archive", false).unwrap();
+        assert_eq!(
+            plan.folders["photos"],
+            crate::plan::FolderPlan {
+                name: "Photos".to_string(),
+                files: 2,
+                bytes: 7
+            }
+        );
+        assert_eq!(plan.folders["(base folder)"].files, 1);
+        assert_eq!((plan.single_part, plan.multipart, plan.parts), (4, 1, 1229));
+        assert_eq!(plan.files(), 5);
+
+        let direct = crate::plan::plan(&files, "/Archive", true).unwrap();
+        assert_eq!(
+            (direct.single_part, direct.multipart, direct.parts),
+            (3, 2, 1231)
+        );
+    }
+
+    #[test]
+    fn it_charges_per_object_overhead() {
+        let files = [("/a.txt".to_string(), 0), ("/b.txt".to_string(), 0)];
+        let (monthly, overhead) = crate::plan::plan(&files, "", false).unwrap().monthly_cost();
+        assert_eq!(monthly, overhead);
+        let per_object = (32.0 * 0.00099 + 8.0 * 0.023) / 1024.0 / 1024.0;
+        assert!((overhead - 2.0 * per_object).abs() < 1e-12);
+    }
+}
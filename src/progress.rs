@@ -1,12 +1,11 @@
 use crate::util::getenv;
-use indicatif;
 
 pub type Progress = indicatif::ProgressBar;
 pub type MultiProgress = indicatif::MultiProgress;
 pub type ProgressStyle = indicatif::ProgressStyle;
 
 pub fn new_multi_progress() -> MultiProgress {
-    let silent: bool = getenv("SILENT").unwrap() == "true";
+    let silent: bool = getenv("SILENT").unwrap_or_default() == "true";
     let m = MultiProgress::new();
     if silent {
         m.set_draw_target(indicatif::ProgressDrawTarget::hidden());
@@ -17,7 +16,7 @@ pub fn new_multi_progress() -> MultiProgress {
 
 pub fn new(total: u64, style: &str) -> Progress {
     let mut pb = Progress::new(total);
-    let silent: bool = getenv("SILENT").unwrap() == "true";
+    let silent: bool = getenv("SILENT").unwrap_or_default() == "true";
     if silent {
         pb.set_draw_target(indicatif::ProgressDrawTarget::hidden());
         return pb;
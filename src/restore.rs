@@ -0,0 +1,324 @@
+use crate::aws::{self, AWSClient, Metadata};
+use crate::checksum;
+use crate::compress;
+use crate::crypto;
+use crate::db::{self, ArchivedFile, DBConnection};
+use crate::dropbox;
+use crate::error::{DeepFreezeError, Result};
+use crate::http::HTTPClient;
+use crate::localfs;
+use crate::progress::MultiProgress;
+use crate::shutdown::Shutdown;
+use crate::util::{self, getenv};
+
+use aws_sdk_s3::types::{StorageClass, Tier};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+/// Deep Archive restores take 12 hours (Standard) to 48 hours (Bulk), so there is no
+/// point asking S3 much more often than this.
+const POLL_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+/// Where restored files go.
+pub enum Target<'a> {
+    /// A local folder, with each file at its Dropbox path below it.
+    Local(&'a str),
+    /// Back into Dropbox, at each file's Dropbox path below this folder (`""` for the
+    /// original paths).
+    Dropbox(&'a HTTPClient, &'a str),
+}
+
+#[derive(Debug, PartialEq)]
+enum RestoreStatus {
+    NotRequested,
+    InProgress,
+    /// The object can be downloaded: restored, or never in Deep Archive at all.
+    Restored,
+}
+
+/// Reads `HeadObject`'s storage class and `x-amz-restore` header, which is
+/// `ongoing-request="true"` while the restore runs and `ongoing-request="false",
+/// expiry-date="..."` once the copy is readable.
+fn restore_status(storage_class: Option<&StorageClass>, restore: Option<&str>) -> RestoreStatus {
+    match storage_class {
+        Some(StorageClass::DeepArchive | StorageClass::Glacier) => match restore {
+            Some(restore) if restore.contains("ongoing-request=\"false\"") => {
+                RestoreStatus::Restored
+            }
+            Some(_) => RestoreStatus::InProgress,
+            None => RestoreStatus::NotRequested,
+        },
+        _ => RestoreStatus::Restored,
+    }
+}
+
+/// The object holding a file's contents: its bundle, the original it is a duplicate of,
+/// or its own object.
+fn object_key(file: &ArchivedFile) -> Result<String> {
+    match (&file.bundle, &file.duplicate_key, &file.s3_key) {
+        (Some((bundle_key, _, _)), _, _) => Ok(bundle_key.clone()),
+        (None, Some(duplicate_key), _) => Ok(duplicate_key.clone()),
+        (None, None, Some(s3_key)) => Ok(s3_key.clone()),
+        (None, None, None) => util::standardize_path(&file.source_kind, &file.source_path),
+    }
+}
+
+/// Where a file is restored to: its Dropbox path under `to`.
+fn destination(to: &str, file: &ArchivedFile) -> String {
+    Path::new(to)
+        .join(file.source_path.trim_start_matches('/'))
+        .to_string_lossy()
+        .to_string()
+}
+
+/// Where a file is restored to in Dropbox: its Dropbox path under `folder`.
+fn dropbox_destination(folder: &str, file: &ArchivedFile) -> String {
+    format!("{}{}", folder.trim_end_matches('/'), file.source_path)
+}
+
+/// Brings back the archived files matching `patterns` (paths, folders or globs, see
+/// `db::find_archived`) to `target`, keeping their Dropbox paths. Requests a restore of
+/// each object still in Deep Archive, then checks on them every `POLL_INTERVAL` and
+/// downloads each one as soon as it is readable, decrypting and decompressing it and
+/// checking every file against its Dropbox content hash. Files already in a local
+/// target are skipped, and Dropbox keeps a file it already has as it is, so an
+/// interrupted restore picks up where it stopped when run again.
+pub async fn restore(
+    aws: &AWSClient,
+    sqlite: &DBConnection,
+    patterns: &[String],
+    (tier, days, target): (&str, i32, Target<'_>),
+    (m, shutdown): (&MultiProgress, &mut Shutdown),
+) -> Result<()> {
+    let bucket = getenv("AWS_S3_BUCKET")?;
+    let mut files = BTreeMap::new();
+    for pattern in patterns {
+        let matches = db::find_archived(sqlite, pattern)?;
+        if matches.is_empty() {
+            println!("🤷  No archived files match {pattern}");
+        }
+        for file in matches {
+            files.insert(file.source_id.clone(), file);
+        }
+    }
+
+    let mut objects: BTreeMap<String, Vec<ArchivedFile>> = BTreeMap::new();
+    for file in files.into_values() {
+        if let Target::Local(to) = target {
+            let path = destination(to, &file);
+            if localfs::local_file_exists(&path).await?
+                && localfs::get_local_size(&path).await? == file.dropbox_size
+            {
+                println!("✅  Already restored {}", file.source_path);
+                continue;
+            }
+        }
+        objects.entry(object_key(&file)?).or_default().push(file);
+    }
+
+    let restore_tier = match tier {
+        "standard" => Tier::Standard,
+        _ => Tier::Bulk,
+    };
+    let mut failed = 0;
+    while !objects.is_empty() {
+        let mut restored = Vec::new();
+        for key in objects.keys() {
+            let head = aws::head_object(aws, &bucket, key).await?;
+            match restore_status(head.storage_class(), head.restore()) {
+                RestoreStatus::NotRequested => {
+                    aws::restore_object(aws, &bucket, key, (restore_tier.clone(), days)).await?;
+                    db::record_restore_request(sqlite, key, (tier, days))?;
+                    println!(
+                        "📨  Requested a {tier} restore of s3://{bucket}/{key} for {days} days"
+                    );
+                }
+                RestoreStatus::InProgress => println!("⏳  Still restoring s3://{bucket}/{key}"),
+                RestoreStatus::Restored => {
+                    db::set_restored(sqlite, key)?;
+                    restored.push((key.clone(), head.metadata().cloned().unwrap_or_default()));
+                }
+            }
+        }
+        for (key, metadata) in restored {
+            if shutdown.requested() {
+                break;
+            }
+            let local_path = fetch_object(aws, (&key, &bucket), &metadata, m).await?;
+            for file in objects.remove(&key).unwrap_or_default() {
+                let restored = match target {
+                    Target::Local(to) => extract_file(&local_path, &file, to).await,
+                    Target::Dropbox(http, folder) => {
+                        upload_file(http, &local_path, &file, (folder, m)).await
+                    }
+                };
+                match restored {
+                    Ok(true) => {}
+                    Ok(false) => failed += 1,
+                    Err(err) => {
+                        println!("🚫  Could not restore {}: {err}", file.source_path);
+                        failed += 1;
+                    }
+                }
+            }
+            localfs::delete_local_file(&local_path).await?;
+            db::set_restore_downloaded(sqlite, &key)?;
+        }
+        if objects.is_empty() {
+            break;
+        }
+        if !shutdown.requested() {
+            println!(
+                "💤  Waiting for {} objects, checking again in {} minutes",
+                objects.len(),
+                POLL_INTERVAL.as_secs() / 60
+            );
+            tokio::select! {
+                _ = tokio::time::sleep(POLL_INTERVAL) => continue,
+                _ = shutdown.wait() => {}
+            }
+        }
+        println!(
+            "🛑  Stopped with {} objects left to restore, run again to carry on",
+            objects.len()
+        );
+        return Ok(());
+    }
+    match failed {
+        0 => Ok(()),
+        _ => Err(DeepFreezeError::Integrity(format!(
+            "{failed} files could not be restored or don't match their Dropbox content hash"
+        ))),
+    }
+}
+
+/// Downloads a restored object and undoes the encryption and compression its metadata
+/// records, returning the path of the plain copy.
+async fn fetch_object(
+    aws: &AWSClient,
+    (key, bucket): (&str, &str),
+    metadata: &Metadata,
+    m: &MultiProgress,
+) -> Result<String> {
+    let mut local_path = localfs::temp_path(&format!("restore/{key}"));
+    aws::download_object(aws, (key, bucket), &local_path, m).await?;
+    if let Some(envelope) = crypto::envelope_from_metadata(key, metadata)? {
+        let decrypted = format!("{local_path}.plain");
+        crypto::decrypt_file(&envelope, &local_path, &decrypted).await?;
+        localfs::delete_local_file(&local_path).await?;
+        local_path = decrypted;
+    }
+    if compress::is_compressed(metadata) {
+        let decompressed = format!("{local_path}.raw");
+        compress::decompress_file(&local_path, &decompressed).await?;
+        localfs::delete_local_file(&local_path).await?;
+        local_path = decompressed;
+    }
+    Ok(local_path)
+}
+
+/// Copies one file out of a fetched object (all of it, or its range of a bundle) to its
+/// place under `to`. Returns whether it matches its Dropbox content hash.
+async fn extract_file(local_path: &str, file: &ArchivedFile, to: &str) -> Result<bool> {
+    let path = destination(to, file);
+    localfs::create_download_folder(&path).await?;
+    match file.bundle {
+        Some((_, offset, length)) => {
+            let mut source = localfs::get_local_file(local_path).await?;
+            source.seek(std::io::SeekFrom::Start(offset as u64)).await?;
+            let mut destination = localfs::create_local_file(&path).await?;
+            tokio::io::copy(&mut source.take(length as u64), &mut destination).await?;
+        }
+        None => {
+            tokio::fs::copy(local_path, &path).await?;
+        }
+    }
+    let hash = checksum::dropbox_content_hash_of_file(&path).await?;
+    match checksum::verify_dropbox_hash(&file.dropbox_hash, hash) {
+        Ok(()) => {
+            println!("📥  Restored {} to {path}", file.source_path);
+            Ok(true)
+        }
+        Err(err) => {
+            println!("🚫  {}: {err}", file.source_path);
+            Ok(false)
+        }
+    }
+}
+
+/// Uploads one file of a fetched object (all of it, or its range of a bundle) back into
+/// Dropbox below `folder`. Returns whether the `content_hash` Dropbox reports for it
+/// matches the one it had when it was archived.
+async fn upload_file(
+    http: &HTTPClient,
+    local_path: &str,
+    file: &ArchivedFile,
+    (folder, m): (&str, &MultiProgress),
+) -> Result<bool> {
+    let path = dropbox_destination(folder, file);
+    let range = match file.bundle {
+        Some((_, offset, length)) => (offset as u64, length as u64),
+        None => (0, localfs::get_local_size(local_path).await? as u64),
+    };
+    let hash = dropbox::upload_to_dropbox(http, (local_path, range.0, range.1), &path, m).await?;
+    match checksum::verify_dropbox_hash(&file.dropbox_hash, hash) {
+        Ok(()) => {
+            println!("📤  Restored {} to Dropbox at {path}", file.source_path);
+            Ok(true)
+        }
+        Err(err) => {
+            println!("🚫  {path}: {err}");
+            Ok(false)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::restore::{dropbox_destination, object_key, restore_status, RestoreStatus};
+    use aws_sdk_s3::types::StorageClass;
+
+    #[test]
+    fn it_reads_the_restore_status() {
+        let deep_archive = Some(&StorageClass::DeepArchive);
+        assert_eq!(
+            restore_status(deep_archive, None),
+            RestoreStatus::NotRequested
+        );
+        assert_eq!(
+            restore_status(deep_archive, Some("ongoing-request=\"true\"")),
+            RestoreStatus::InProgress
+        );
+        assert_eq!(
+            restore_status(
+                deep_archive,
+                Some("ongoing-request=\"false\", expiry-date=\"Fri, 23 Dec 2026 00:00:00 GMT\"")
+            ),
+            RestoreStatus::Restored
+        );
+        assert_eq!(restore_status(None, None), RestoreStatus::Restored);
+    }
+
+    #[test]
+    fn it_finds_the_object_holding_a_file() {
+        let mut file = crate::db::ArchivedFile {
+            source_id: "id:a".to_string(),
+            source_path: "/a.txt".to_string(),
+            source_kind: "dropbox".to_string(),
+            dropbox_hash: "hash".to_string(),
+            dropbox_size: 1,
+            s3_key: Some("moved/a.txt".to_string()),
+            duplicate_key: None,
+            bundle: None,
+        };
+        assert_eq!(object_key(&file).unwrap(), "moved/a.txt");
+        assert_eq!(dropbox_destination("", &file), "/a.txt");
+        assert_eq!(dropbox_destination("/Restored/", &file), "/Restored/a.txt");
+        file.duplicate_key = Some("original.txt".to_string());
+        assert_eq!(object_key(&file).unwrap(), "original.txt");
+        file.bundle = Some(("bundle.tar".to_string(), 512, 1));
+        assert_eq!(object_key(&file).unwrap(), "bundle.tar");
+    }
+}
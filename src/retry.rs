@@ -0,0 +1,283 @@
+use crate::util::getenv;
+
+use aws_sdk_s3::{config::http::HttpResponse, error::ProvideErrorMetadata, error::SdkError};
+use rand::Rng;
+use reqwest::{header::RETRY_AFTER, StatusCode};
+use std::{
+    fmt::Display,
+    future::Future,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+pub const DEFAULT_MAX_ATTEMPTS: u32 = 5;
+const BASE_DELAY: Duration = Duration::from_secs(1);
+const MAX_DELAY: Duration = Duration::from_secs(120);
+/// How long to pause when Dropbox rate-limits a request without saying for how long.
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(10);
+/// How many rate-limited responses in a row one attempt waits out before it fails.
+const MAX_RATE_LIMITS: u32 = 10;
+/// How long one attempt may spend paused for rate limits before it fails.
+const MAX_RATE_LIMIT_WAIT: Duration = Duration::from_secs(30 * 60);
+
+/// Until when no Dropbox request may go out. Every worker checks it before sending, so one
+/// rate-limited response pauses the whole pool instead of each worker hitting the limit.
+static DROPBOX_PAUSED_UNTIL: Mutex<Option<Instant>> = Mutex::new(None);
+
+/// S3 error codes that are worth another attempt even though they don't come back as a
+/// 5xx or 429.
+const RETRYABLE_CODES: [&str; 5] = [
+    "RequestTimeout",
+    "SlowDown",
+    "InternalError",
+    "Throttling",
+    "ThrottlingException",
+];
+
+/// Separates transient failures (dropped connections, timeouts, throttling, 5xx) from
+/// ones that will fail the same way however often they are retried.
+pub trait Retryable {
+    fn is_retryable(&self) -> bool;
+}
+
+impl Retryable for reqwest::Error {
+    fn is_retryable(&self) -> bool {
+        self.is_timeout()
+            || self.is_connect()
+            || self.is_request()
+            || self.is_body()
+            || self
+                .status()
+                .is_some_and(|status| retryable_status(status.as_u16()))
+    }
+}
+
+impl<E: ProvideErrorMetadata> Retryable for SdkError<E, HttpResponse> {
+    fn is_retryable(&self) -> bool {
+        match self {
+            SdkError::TimeoutError(_)
+            | SdkError::DispatchFailure(_)
+            | SdkError::ResponseError(_) => true,
+            SdkError::ServiceError(err) => {
+                retryable_status(err.raw().status().as_u16())
+                    || self
+                        .code()
+                        .is_some_and(|code| RETRYABLE_CODES.contains(&code))
+            }
+            _ => false,
+        }
+    }
+}
+
+fn retryable_status(status: u16) -> bool {
+    status == 429 || (500..600).contains(&status)
+}
+
+pub fn get_max_attempts() -> u32 {
+    getenv("MAX_ATTEMPTS")
+        .unwrap_or_default()
+        .parse::<u32>()
+        .unwrap_or(DEFAULT_MAX_ATTEMPTS)
+        .max(1)
+}
+
+/// Exponential backoff with jitter: the ceiling doubles with every attempt (up to
+/// `MAX_DELAY`), and the actual delay is picked at random from its upper half so
+/// concurrent workers that failed together don't all come back at the same moment.
+pub fn backoff(attempt: u32) -> Duration {
+    let ceiling = BASE_DELAY
+        .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+        .min(MAX_DELAY);
+    let half = ceiling / 2;
+    half + half.mul_f64(rand::thread_rng().gen::<f64>())
+}
+
+/// Runs `operation` until it succeeds, fails with an error that isn't retryable, or has
+/// been attempted `MAX_ATTEMPTS` times, sleeping for `backoff` between attempts.
+/// `operation` is called afresh for every attempt, so it must rebuild its request.
+pub async fn with_retry<T, E, F, Fut>(what: &str, mut operation: F) -> Result<T, E>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+    E: Retryable + Display,
+{
+    let max_attempts = get_max_attempts();
+    let mut attempt: u32 = 1;
+    loop {
+        match operation().await {
+            Ok(res) => return Ok(res),
+            Err(err) if attempt < max_attempts && err.is_retryable() => {
+                let delay = backoff(attempt);
+                println!(
+                    "🔁  {what} failed ({err}), retrying in {:.1}s ({attempt}/{max_attempts})",
+                    delay.as_secs_f64()
+                );
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Sends the request built by `request` and reads the whole body as text, retrying the
+/// pair together so a connection dropped mid-body is retried too. Rate-limited responses
+/// wait out Dropbox's `Retry-After` (see `send_checked`) and 5xx responses count as
+/// failures; any other response is returned for the caller to parse.
+pub async fn send_text<F>(what: &str, request: F) -> reqwest::Result<String>
+where
+    F: Fn() -> reqwest::RequestBuilder,
+{
+    with_retry(what, || async {
+        let res = send_checked(&request).await?;
+        res.text().await
+    })
+    .await
+}
+
+/// Like `send_text`, but hands back the response once its headers arrive, for bodies the
+/// caller wants to stream.
+pub async fn send<F>(what: &str, request: F) -> reqwest::Result<reqwest::Response>
+where
+    F: Fn() -> reqwest::RequestBuilder,
+{
+    with_retry(what, || send_checked(&request)).await
+}
+
+/// Sends one request, first waiting out any rate-limit pause. A 429 response (Dropbox's
+/// `too_many_requests` / `too_many_write_operations` rate limit errors) pauses every
+/// Dropbox request for the `Retry-After` duration and then sends again, without using up
+/// one of the `MAX_ATTEMPTS`. A limit that never lifts (`MAX_RATE_LIMITS` responses in a
+/// row, or `MAX_RATE_LIMIT_WAIT` spent paused) is returned as the 429 error instead, so
+/// it uses up an attempt and the request eventually fails rather than stalling every
+/// worker for good.
+async fn send_checked<F>(request: &F) -> reqwest::Result<reqwest::Response>
+where
+    F: Fn() -> reqwest::RequestBuilder,
+{
+    let mut rate_limits: u32 = 0;
+    let mut waited = Duration::ZERO;
+    loop {
+        wait_for_rate_limit().await;
+        let res = request().send().await?;
+        if res.status() == StatusCode::TOO_MANY_REQUESTS {
+            rate_limits += 1;
+            if rate_limit_exhausted(rate_limits, waited) {
+                println!("🐢  Dropbox rate limit hit {rate_limits} times in a row, giving up");
+                return Err(res.error_for_status().unwrap_err());
+            }
+            let header = res
+                .headers()
+                .get(RETRY_AFTER)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string);
+            let body = res.text().await.unwrap_or_default();
+            let delay = parse_retry_after(header.as_deref(), &body);
+            waited += delay;
+            println!(
+                "🐢  Dropbox rate limit hit, pausing requests for {}s",
+                delay.as_secs()
+            );
+            pause_dropbox(delay);
+            continue;
+        }
+        return match retryable_status(res.status().as_u16()) {
+            true => Err(res.error_for_status().unwrap_err()),
+            false => Ok(res),
+        };
+    }
+}
+
+/// Whether an attempt has been rate-limited `rate_limits` times in a row, after pausing
+/// for `waited` in all, too often or too long to wait out again.
+fn rate_limit_exhausted(rate_limits: u32, waited: Duration) -> bool {
+    rate_limits >= MAX_RATE_LIMITS || waited >= MAX_RATE_LIMIT_WAIT
+}
+
+async fn wait_for_rate_limit() {
+    let paused_until = *DROPBOX_PAUSED_UNTIL.lock().unwrap();
+    if let Some(paused_until) = paused_until {
+        tokio::time::sleep_until(paused_until.into()).await;
+    }
+}
+
+fn pause_dropbox(delay: Duration) {
+    let mut paused_until = DROPBOX_PAUSED_UNTIL.lock().unwrap();
+    let until = Instant::now() + delay;
+    if paused_until.is_none_or(|paused_until| paused_until < until) {
+        *paused_until = Some(until);
+    }
+}
+
+/// Reads how long Dropbox asked us to wait, from the `Retry-After` header or else the
+/// `retry_after` field of the rate limit error body.
+fn parse_retry_after(header: Option<&str>, body: &str) -> Duration {
+    header
+        .and_then(|seconds| seconds.trim().parse::<u64>().ok())
+        .or_else(|| {
+            serde_json::from_str::<serde_json::Value>(body)
+                .ok()?
+                .get("error")?
+                .get("retry_after")?
+                .as_u64()
+        })
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_RETRY_AFTER)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    #[test]
+    fn it_backs_off_exponentially_with_jitter() {
+        for attempt in 1..=4 {
+            let ceiling = Duration::from_secs(2u64.pow(attempt - 1));
+            let delay = crate::retry::backoff(attempt);
+            assert!(delay >= ceiling / 2 && delay <= ceiling, "{delay:?}");
+        }
+        assert!(crate::retry::backoff(30) <= Duration::from_secs(120));
+    }
+
+    #[test]
+    fn it_retries_throttling_and_server_errors_only() {
+        assert!(crate::retry::retryable_status(429));
+        assert!(crate::retry::retryable_status(503));
+        assert!(!crate::retry::retryable_status(404));
+        assert!(!crate::retry::retryable_status(409));
+    }
+
+    #[test]
+    fn it_gives_up_on_a_rate_limit_that_never_lifts() {
+        assert!(!crate::retry::rate_limit_exhausted(1, Duration::ZERO));
+        assert!(!crate::retry::rate_limit_exhausted(
+            crate::retry::MAX_RATE_LIMITS - 1,
+            Duration::from_secs(60)
+        ));
+        assert!(crate::retry::rate_limit_exhausted(
+            crate::retry::MAX_RATE_LIMITS,
+            Duration::from_secs(60)
+        ));
+        assert!(crate::retry::rate_limit_exhausted(
+            2,
+            crate::retry::MAX_RATE_LIMIT_WAIT
+        ));
+    }
+
+    #[test]
+    fn it_reads_dropbox_retry_after() {
+        let body = r#"{"error_summary": "too_many_requests/..", "error": {"reason": {".tag": "too_many_requests"}, "retry_after": 300}}"#;
+        assert_eq!(
+            crate::retry::parse_retry_after(Some("7"), body),
+            Duration::from_secs(7)
+        );
+        assert_eq!(
+            crate::retry::parse_retry_after(None, body),
+            Duration::from_secs(300)
+        );
+        assert_eq!(
+            crate::retry::parse_retry_after(None, "too_many_requests"),
+            crate::retry::DEFAULT_RETRY_AFTER
+        );
+    }
+}
@@ -0,0 +1,235 @@
+use crate::error::{DeepFreezeError, Result};
+use crate::util::getenv;
+use deep_freeze::source::{Source, SourceEntry, SourcePage, SourceStream};
+
+use futures::{stream, Stream, TryStreamExt};
+use hyper::body::Bytes;
+use openssh::{KnownHosts, Session};
+use openssh_sftp_client::{file::File, Sftp};
+use std::io::SeekFrom;
+use std::sync::Mutex;
+use tokio::io::AsyncSeekExt;
+
+/// How many files are handed to the catalog at a time.
+const PAGE_SIZE: usize = 1000;
+/// How much of a file each read asks the server for. Servers cap it lower (OpenSSH at
+/// 255 KiB), so a read can come back shorter.
+const READ_SIZE: u32 = 1024 * 1024;
+
+/// A folder on a server reached over SSH. The connection goes through the system `ssh`
+/// client, so keys, the agent, `~/.ssh/config` and `known_hosts` work as they do for
+/// `ssh` itself; a host not yet in `known_hosts` is added, and one whose key changed is
+/// refused. A file is known by its absolute remote path, its `source_id` and its
+/// `source_path`. Symlinks and special files are never archived.
+pub struct SftpSource {
+    sftp: Sftp,
+    /// `sftp:` and the host, so files from different servers are told apart.
+    kind: String,
+    root: String,
+    /// Folders still to walk, so a listing can go on where the last page stopped.
+    folders: Mutex<Vec<String>>,
+}
+
+impl SftpSource {
+    /// Connects to `destination` (`[user@]host` or `ssh://[user@]host[:port]`) and opens
+    /// its SFTP subsystem, to archive the absolute folder `root`.
+    pub async fn connect(destination: &str, root: &str) -> Result<Self> {
+        if !root.starts_with('/') {
+            return Err(DeepFreezeError::Config(format!(
+                "SFTP_ROOT must be an absolute path, not {root}"
+            )));
+        }
+        let session = Session::connect_mux(destination, KnownHosts::Add).await?;
+        let sftp = Sftp::from_session(session, Default::default()).await?;
+        Ok(SftpSource {
+            sftp,
+            kind: format!("sftp:{}", host(destination)),
+            root: root.trim_end_matches('/').to_string(),
+            folders: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// The source `SFTP_HOST` and `SFTP_ROOT` describe.
+    pub async fn from_env() -> Result<Self> {
+        SftpSource::connect(&getenv("SFTP_HOST")?, &getenv("SFTP_ROOT")?).await
+    }
+}
+
+/// The host of an SSH `destination`, with its port but without the scheme or user.
+fn host(destination: &str) -> &str {
+    let host = destination.trim_start_matches("ssh://");
+    host.rsplit_once('@').map_or(host, |(_, host)| host)
+}
+
+/// The bytes of an open remote file from `offset` on. The offset is moved on by what
+/// each read returned rather than what it asked for, since a server may send less.
+fn read_chunks(file: File, offset: u64) -> impl Stream<Item = Result<Bytes>> {
+    stream::try_unfold((file, offset), |(mut file, offset)| async move {
+        file.seek(SeekFrom::Start(offset)).await?;
+        let Some(chunk) = file.read(READ_SIZE, Default::default()).await? else {
+            return Ok(None);
+        };
+        let next = offset + chunk.len() as u64;
+        Ok(Some((chunk.freeze(), (file, next))))
+    })
+}
+
+impl Source for SftpSource {
+    type Error = DeepFreezeError;
+
+    fn kind(&self) -> &str {
+        &self.kind
+    }
+
+    /// Walks the tree under the root, folders in name order, recording each file's size and
+    /// modification time. A file whose size the server leaves out of the listing is looked
+    /// up on its own, and left out if that doesn't tell either. SFTP servers don't report a
+    /// hash and reading every file here would move the tree over the network twice, so
+    /// files are listed without one and hashed as they are transferred. SFTP can't tell
+    /// hard links apart, so a file with several is archived under each of its paths. As
+    /// with a local folder, an interrupted listing starts again from the root.
+    async fn list(&self, cursor: Option<&str>) -> Result<SourcePage> {
+        let mut folders = std::mem::take(&mut *self.folders.lock().unwrap());
+        let mut listed: usize = cursor.and_then(|cursor| cursor.parse().ok()).unwrap_or(0);
+        if cursor.is_none() {
+            folders = vec![self.root.clone()];
+        }
+        let mut entries = Vec::new();
+        while entries.len() < PAGE_SIZE {
+            let Some(folder) = folders.pop() else {
+                break;
+            };
+            let mut children: Vec<_> = self
+                .sftp
+                .fs()
+                .open_dir(&folder)
+                .await?
+                .read_dir()
+                .try_collect()
+                .await?;
+            children.sort_by(|a, b| a.filename().cmp(b.filename()));
+            let queued = folders.len();
+            for child in children {
+                let Some(name) = child.filename().to_str() else {
+                    println!(
+                        "⏭️  Skipping {}, not a UTF-8 path",
+                        child.filename().display()
+                    );
+                    continue;
+                };
+                if name == "." || name == ".." {
+                    continue;
+                }
+                let path = format!("{folder}/{name}");
+                match child.file_type() {
+                    Some(file_type) if file_type.is_dir() => folders.push(path),
+                    Some(file_type) if file_type.is_file() => {
+                        let metadata = child.metadata();
+                        let size = match metadata.len() {
+                            Some(size) => Some(size),
+                            None => self.sftp.fs().metadata(&path).await?.len(),
+                        };
+                        let Some(size) = size else {
+                            println!("⏭️  Skipping {path}, the server doesn't report its size");
+                            continue;
+                        };
+                        entries.push(SourceEntry {
+                            id: path.clone(),
+                            hash: String::new(),
+                            path,
+                            size,
+                            modified: metadata
+                                .modified()
+                                .map(|modified| i64::from(modified.into_raw())),
+                        });
+                    }
+                    Some(file_type) if file_type.is_symlink() => {
+                        println!("🔗  Skipping symlink {path}")
+                    }
+                    _ => println!("⏭️  Skipping {path}, not a regular file"),
+                }
+            }
+            // Queued in name order, so reversed to come off the stack in name order.
+            folders[queued..].reverse();
+        }
+        listed += entries.len();
+        let has_more = !folders.is_empty();
+        *self.folders.lock().unwrap() = folders;
+        Ok(SourcePage {
+            entries,
+            cursor: has_more.then(|| listed.to_string()),
+            has_more,
+        })
+    }
+
+    async fn fetch(&self, id: &str, offset: u64) -> Result<SourceStream<DeepFreezeError>> {
+        let file = self.sftp.open(id).await?;
+        Ok(Box::pin(read_chunks(file, offset)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sftp::{host, SftpSource};
+    use deep_freeze::source::Source;
+    use futures::TryStreamExt;
+
+    #[test]
+    fn it_finds_the_host_of_a_destination() {
+        assert_eq!(host("files.example.com"), "files.example.com");
+        assert_eq!(host("archive@files.example.com"), "files.example.com");
+        assert_eq!(host("ssh://archive@localhost:2222"), "localhost:2222");
+    }
+
+    /// Needs an SSH server with SFTP whose key is in the agent or `~/.ssh`, named by
+    /// `SFTP_TEST_HOST` (e.g. `ssh://deep-freeze@localhost:2222`); see the README for one
+    /// in a container.
+    #[tokio::test]
+    #[ignore]
+    async fn it_lists_and_fetches_over_sftp() {
+        let host = std::env::var("SFTP_TEST_HOST").unwrap();
+        let root = "/tmp/deep-freeze-sftp";
+        let source = SftpSource::connect(&host, root).await.unwrap();
+        let mut fs = source.sftp.fs();
+        fs.create_dir(root).await.ok();
+        fs.create_dir(format!("{root}/b")).await.ok();
+        let text = "hello world".repeat(100_000);
+        fs.write(format!("{root}/a.txt"), &text).await.unwrap();
+        fs.write(format!("{root}/b/c.txt"), "c").await.unwrap();
+        fs.symlink(format!("{root}/a.txt"), format!("{root}/soft.txt"))
+            .await
+            .ok();
+
+        let page = source.list(None).await.unwrap();
+        assert!(!page.has_more);
+        let paths: Vec<_> = page
+            .entries
+            .iter()
+            .map(|entry| entry.path.as_str())
+            .collect();
+        assert_eq!(
+            paths,
+            vec![format!("{root}/a.txt"), format!("{root}/b/c.txt")]
+        );
+        let entry = &page.entries[0];
+        assert_eq!(entry.size, text.len() as u64);
+        assert!(entry.modified.is_some());
+        assert!(entry.hash.is_empty());
+
+        let bytes: Vec<Vec<u8>> = source
+            .fetch(&entry.id, 6)
+            .await
+            .unwrap()
+            .map_ok(|bytes| bytes.to_vec())
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(bytes.concat(), text.as_bytes()[6..]);
+
+        fs.remove_file(format!("{root}/soft.txt")).await.ok();
+        fs.remove_file(format!("{root}/b/c.txt")).await.unwrap();
+        fs.remove_file(format!("{root}/a.txt")).await.unwrap();
+        fs.remove_dir(format!("{root}/b")).await.unwrap();
+        fs.remove_dir(root).await.unwrap();
+    }
+}
@@ -0,0 +1,118 @@
+use crate::error::{DeepFreezeError, Result};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+
+/// How far shutdown has got. The first SIGTERM or Ctrl-C moves to `Draining`, the
+/// second to `Aborting`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Stage {
+    Running,
+    /// Claim no more rows; transfers in flight stop at their next checkpoint.
+    Draining,
+    /// Drop whatever is in flight right away.
+    Aborting,
+}
+
+/// Follows the shutdown `Stage`. Clones share the same signals, so the workers, the
+/// transfers and the `watch` loop can all check it without owning the listener.
+#[derive(Clone)]
+pub struct Shutdown(watch::Receiver<Stage>);
+
+impl Shutdown {
+    /// Starts listening for SIGTERM and Ctrl-C in the background.
+    pub fn listen() -> Shutdown {
+        let (tx, rx) = watch::channel(Stage::Running);
+        tokio::spawn(async move {
+            wait_for_signal().await;
+            println!("\n🛑  Stopping: checkpointing the files in flight (signal again to abort)");
+            tx.send(Stage::Draining).ok();
+            wait_for_signal().await;
+            println!("\n🛑  Aborting the files in flight");
+            tx.send(Stage::Aborting).ok();
+        });
+        Shutdown(rx)
+    }
+
+    /// A `Shutdown` driven by the returned sender instead of signals.
+    pub fn manual() -> (watch::Sender<Stage>, Shutdown) {
+        let (tx, rx) = watch::channel(Stage::Running);
+        (tx, Shutdown(rx))
+    }
+
+    pub fn requested(&self) -> bool {
+        *self.0.borrow() >= Stage::Draining
+    }
+
+    /// Fails with `DeepFreezeError::Interrupted` (carrying how far the transfer got) once
+    /// shutdown is requested. Transfers call it wherever they can stop and resume later:
+    /// between chunks of a download and between the parts of a multipart upload.
+    pub fn checkpoint(&self, progress: impl FnOnce() -> String) -> Result<()> {
+        match self.requested() {
+            true => Err(DeepFreezeError::Interrupted(progress())),
+            false => Ok(()),
+        }
+    }
+
+    /// Resolves once shutdown is requested, for racing against long waits.
+    pub async fn wait(&mut self) {
+        self.wait_for(Stage::Draining).await
+    }
+
+    /// Resolves on the second signal, for racing against a transfer in flight.
+    pub async fn aborted(&self) {
+        self.clone().wait_for(Stage::Aborting).await
+    }
+
+    async fn wait_for(&mut self, stage: Stage) {
+        // Without a sender the stage can't change any more, so there is nothing to wait for.
+        if self.0.wait_for(|current| *current >= stage).await.is_err() {
+            std::future::pending::<()>().await
+        }
+    }
+}
+
+impl Default for Shutdown {
+    /// A `Shutdown` that is never requested.
+    fn default() -> Shutdown {
+        Shutdown::manual().1
+    }
+}
+
+async fn wait_for_signal() {
+    match signal(SignalKind::terminate()) {
+        Ok(mut sigterm) => {
+            tokio::select! {
+                _ = sigterm.recv() => {}
+                _ = tokio::signal::ctrl_c() => {}
+            }
+        }
+        Err(_) => {
+            tokio::signal::ctrl_c().await.ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::shutdown::{Shutdown, Stage};
+
+    #[tokio::test]
+    async fn it_drains_then_aborts() {
+        let (tx, shutdown) = Shutdown::manual();
+        assert!(!shutdown.requested());
+        assert!(shutdown
+            .checkpoint(|| "1 of 3 parts uploaded".to_string())
+            .is_ok());
+        tx.send(Stage::Draining).unwrap();
+        assert!(shutdown.requested());
+        assert!(shutdown
+            .checkpoint(|| "2 of 3 parts uploaded".to_string())
+            .is_err());
+        let aborted = tokio::spawn({
+            let shutdown = shutdown.clone();
+            async move { shutdown.aborted().await }
+        });
+        tx.send(Stage::Aborting).unwrap();
+        aborted.await.unwrap();
+    }
+}
@@ -0,0 +1,69 @@
+use futures::{Future, Stream};
+use hyper::body::Bytes;
+use std::pin::Pin;
+
+/// One file as a `Source` lists it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceEntry {
+    /// Stays the same while the file keeps its contents, across renames where the source
+    /// can tell (Dropbox's `id:` file ids), so the catalog can follow a file around.
+    pub id: String,
+    /// Where the file is in the source; archived under the key this maps to.
+    pub path: String,
+    pub size: u64,
+    /// The file's Dropbox-style `content_hash` (SHA-256 of the SHA-256 of each 4 MiB
+    /// block), which every transfer is checked against before S3 gets a finished object.
+    /// Empty when the source can't tell without reading the file; the first transfer
+    /// then records it.
+    pub hash: String,
+    /// When the file was last modified, in seconds since the Unix epoch, where the source
+    /// reports it.
+    pub modified: Option<i64>,
+}
+
+/// One page of a listing.
+#[derive(Debug, Default)]
+pub struct SourcePage {
+    pub entries: Vec<SourceEntry>,
+    /// Where the next page starts, and for sources that can follow changes, where to
+    /// look for them once the last page is in.
+    pub cursor: Option<String>,
+    pub has_more: bool,
+}
+
+/// The bytes of a file, as they are fetched.
+pub type SourceStream<E> = Pin<Box<dyn Stream<Item = Result<Bytes, E>> + Send>>;
+
+/// Somewhere files are migrated from. The migration pipeline only lists, fetches and
+/// verifies through this trait, so every source shares the resumable transfers, the
+/// integrity checks and the catalog.
+pub trait Source {
+    type Error;
+
+    /// What `paths.source_kind` records for the files of this source, e.g. `dropbox`.
+    fn kind(&self) -> &str;
+
+    /// Lists the files, a page at a time: `None` for the first page, then the `cursor`
+    /// of the page before while it `has_more`.
+    fn list(&self, cursor: Option<&str>) -> impl Future<Output = Result<SourcePage, Self::Error>>;
+
+    /// Opens the file with the stable `id` and streams its bytes from `offset` on, so
+    /// an interrupted download can carry on where it stopped.
+    fn fetch(
+        &self,
+        id: &str,
+        offset: u64,
+    ) -> impl Future<Output = Result<SourceStream<Self::Error>, Self::Error>>;
+
+    /// Where the file with `id` can be read in place, for sources on a local disk or
+    /// mount. The pipeline then uploads straight from it instead of through a temp copy.
+    fn local_path(&self, _id: &str) -> Option<String> {
+        None
+    }
+
+    /// Called before each file is transferred, for sources whose credentials expire
+    /// during a long migration. Does nothing by default.
+    fn prepare(&self) -> impl Future<Output = Result<(), Self::Error>> {
+        async { Ok(()) }
+    }
+}
@@ -2,49 +2,97 @@ use sedregex::find_and_replace;
 use std::{env, io::stdin};
 use tokio::io::{self, AsyncWriteExt};
 
+use crate::error::{DeepFreezeError, Result};
 use crate::localfs;
 
-pub async fn setenv(key: &str, value: String) {
+pub async fn setenv(key: &str, value: String) -> Result<()> {
     env::set_var(key, value.clone());
-    localfs::update_env_file(key, value).await.unwrap();
+    localfs::update_env_file(key, value).await?;
+    Ok(())
 }
 
-pub fn getenv(key: &str) -> Result<String, env::VarError> {
-    env::var(key)
+pub fn getenv(key: &str) -> Result<String> {
+    env::var(key).map_err(|_| DeepFreezeError::Config(key.to_string()))
 }
 
-pub async fn setenv_for_e2e() {
+pub async fn setenv_for_e2e() -> Result<()> {
     println!("🧪 Running end-to-end test");
-    setenv("ENV_FILE", ".env.test".to_string()).await;
-    setenv("E2E", "true".to_string()).await;
-    setenv("DBFILE", "test/db.sqlite".to_string()).await;
-    setenv("TEMP_DIR", "test".to_string()).await;
-    setenv("SILENT", "false".to_string()).await;
-    setenv("RESET", "true".to_string()).await;
-    setenv("DROPBOX_BASE_FOLDER", "/deep-freeze-test".to_string()).await;
-    setenv("AWS_S3_BUCKET", "deep-freeze-test".to_string()).await;
-    setenv("RUST_BACKTRACE", "1".to_string()).await;
-}
-
-pub async fn prompt(msg: &str) -> String {
-    io::stderr().flush().await.unwrap();
+    setenv("ENV_FILE", ".env.test".to_string()).await?;
+    setenv("E2E", "true".to_string()).await?;
+    setenv("DBFILE", "test/db.sqlite".to_string()).await?;
+    setenv("TEMP_DIR", "test".to_string()).await?;
+    setenv("SILENT", "false".to_string()).await?;
+    setenv("RESET", "true".to_string()).await?;
+    setenv("DROPBOX_BASE_FOLDER", "/deep-freeze-test".to_string()).await?;
+    setenv("AWS_S3_BUCKET", "deep-freeze-test".to_string()).await?;
+    setenv("RUST_BACKTRACE", "1".to_string()).await?;
+    Ok(())
+}
+
+pub async fn prompt(msg: &str) -> Result<String> {
+    io::stderr().flush().await?;
     eprint!("{}: ", msg);
     let mut input = String::new();
-    stdin().read_line(&mut input).unwrap();
+    stdin().read_line(&mut input)?;
     eprint!("\n\n");
-    input.trim().to_owned()
+    Ok(input.trim().to_owned())
+}
+
+/// The folder the active source is listed from, which `plan` groups files below.
+pub fn base_folder() -> Result<String> {
+    match getenv("SOURCE").unwrap_or_default().as_str() {
+        "local" => getenv("LOCAL_ROOT"),
+        "sftp" => getenv("SFTP_ROOT"),
+        _ => getenv("DROPBOX_BASE_FOLDER"),
+    }
 }
 
-pub fn standardize_path(old_path: &str) -> String {
-    let base_folder = getenv("DROPBOX_BASE_FOLDER").unwrap();
-    find_and_replace(
-        &old_path.to_owned(),
-        &[format!("s/\\{}\\///g", base_folder)],
-    )
-    .unwrap()
-    .to_string()
+/// The object key of a file from a source of `source_kind`. A Dropbox file keeps the
+/// key it has always had, its path below `DROPBOX_BASE_FOLDER`. Any other file is keyed
+/// by its whole path under a prefix naming its source, and for SFTP its host
+/// (`local/mnt/nas/a.jpg`, `sftp/files.example.com/srv/a.jpg`), so files from different
+/// sources sharing a bucket never share a key.
+pub fn standardize_path(source_kind: &str, old_path: &str) -> Result<String> {
+    match source_kind {
+        "dropbox" => dropbox_key(&getenv("DROPBOX_BASE_FOLDER")?, old_path),
+        _ => Ok(format!(
+            "{}/{}",
+            source_kind.replacen(':', "/", 1),
+            old_path.trim_start_matches('/')
+        )),
+    }
+}
+
+/// The object key of a Dropbox file: its path below `base_folder`.
+pub fn dropbox_key(base_folder: &str, path: &str) -> Result<String> {
+    find_and_replace(path, &[format!("s/\\{}\\///g", base_folder)])
+        .map(|path| path.to_string())
+        .map_err(|err| DeepFreezeError::Config(format!("DROPBOX_BASE_FOLDER: {err}")))
 }
 
 pub fn coerce_static_str(s: String) -> &'static str {
     Box::leak(s.into_boxed_str())
 }
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn it_keys_files_by_source() {
+        assert_eq!(
+            crate::util::standardize_path("local", "/mnt/nas/Photos/a.jpg").unwrap(),
+            "local/mnt/nas/Photos/a.jpg"
+        );
+        assert_ne!(
+            crate::util::standardize_path("sftp:a.example.com", "/srv/a.jpg").unwrap(),
+            crate::util::standardize_path("sftp:b.example.com", "/srv/a.jpg").unwrap()
+        );
+        assert_eq!(
+            crate::util::standardize_path("sftp:localhost:2222", "/srv/a.jpg").unwrap(),
+            "sftp/localhost:2222/srv/a.jpg"
+        );
+        assert_eq!(
+            crate::util::dropbox_key("/Archive", "/Archive/Photos/a.jpg").unwrap(),
+            "Photos/a.jpg"
+        );
+    }
+}